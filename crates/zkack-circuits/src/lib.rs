@@ -1,7 +1,10 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
 /// Generic trait for proof systems used by ZK-ACK
 pub trait ProofSystem {
+    /// Recorded on receipts and selected in verifier config
+    const ID: ProofSystemId;
     fn prove(addr_hash_b64: &str, msg_digest_b64: &str, nonce_b64: &str) -> Result<Vec<u8>>;
     fn verify(
         addr_hash_b64: &str,
@@ -14,6 +17,7 @@ pub trait ProofSystem {
 /// Mock proof system; replace with Halo2/Groth16
 pub struct MockProof;
 impl ProofSystem for MockProof {
+    const ID: ProofSystemId = ProofSystemId::Mock;
     fn prove(_a: &str, _m: &str, _n: &str) -> Result<Vec<u8>> {
         Ok(b"mock-proof-ok".to_vec())
     }
@@ -21,3 +25,59 @@ impl ProofSystem for MockProof {
         Ok(proof == b"mock-proof-ok")
     }
 }
+
+/// The available proof systems, for picking one at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProofSystemId {
+    Mock,
+}
+
+impl ProofSystemId {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProofSystemId::Mock => "mock",
+        }
+    }
+
+    pub fn prove(
+        self,
+        addr_hash_b64: &str,
+        msg_digest_b64: &str,
+        nonce_b64: &str,
+    ) -> Result<Vec<u8>> {
+        match self {
+            ProofSystemId::Mock => MockProof::prove(addr_hash_b64, msg_digest_b64, nonce_b64),
+        }
+    }
+
+    pub fn verify(
+        self,
+        addr_hash_b64: &str,
+        msg_digest_b64: &str,
+        nonce_b64: &str,
+        proof: &[u8],
+    ) -> Result<bool> {
+        match self {
+            ProofSystemId::Mock => {
+                MockProof::verify(addr_hash_b64, msg_digest_b64, nonce_b64, proof)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ProofSystemId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ProofSystemId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "mock" => Ok(ProofSystemId::Mock),
+            _ => Err(format!("unknown proof system {s:?} (have: mock)")),
+        }
+    }
+}
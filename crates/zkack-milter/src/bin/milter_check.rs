@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use mailparse::parse_headers;
+use std::fs;
+use tokio::net::TcpStream;
+
+use zkack_milter::*;
+use zkack_signer::{header_end, inject_headers};
+
+/// Local milter test client: plays one .eml through a milter the way an MTA
+/// would and prints the filter's verdict and header edits as JSON.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Milter address
+    #[arg(long, default_value = "127.0.0.1:8892")]
+    milter: String,
+    /// Envelope sender
+    #[arg(long)]
+    mail_from: String,
+    /// Envelope recipient (repeatable)
+    #[arg(long, required = true)]
+    rcpt: Vec<String>,
+    /// Write the message with the milter's header edits applied here
+    #[arg(long)]
+    out: Option<String>,
+    /// Input .eml file path
+    eml: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let eml = fs::read(&args.eml)?;
+    let (headers, body_at) = parse_headers(&eml)?;
+    let mut s = TcpStream::connect(&args.milter).await?;
+
+    let mut neg = Vec::new();
+    neg.extend_from_slice(&MILTER_VERSION.to_be_bytes());
+    neg.extend_from_slice(&0x1ffu32.to_be_bytes());
+    neg.extend_from_slice(&0u32.to_be_bytes());
+    write_packet(&mut s, SMFIC_OPTNEG, &neg).await?;
+    expect(&mut s, SMFIC_OPTNEG).await?;
+
+    let mut connect = b"localhost\0".to_vec();
+    connect.extend_from_slice(b"4\x00\x00127.0.0.1\0");
+    step(&mut s, SMFIC_CONNECT, &connect).await?;
+    step(&mut s, SMFIC_HELO, b"localhost\0").await?;
+    step(
+        &mut s,
+        SMFIC_MAIL,
+        format!("<{}>\0", args.mail_from).as_bytes(),
+    )
+    .await?;
+    for r in &args.rcpt {
+        step(&mut s, SMFIC_RCPT, format!("<{r}>\0").as_bytes()).await?;
+    }
+    step(&mut s, SMFIC_DATA, &[]).await?;
+    for h in &headers {
+        let value = String::from_utf8_lossy(h.get_value_raw());
+        step(
+            &mut s,
+            SMFIC_HEADER,
+            &header_payload(&h.get_key(), value.trim_start()),
+        )
+        .await?;
+    }
+    step(&mut s, SMFIC_EOH, &[]).await?;
+    let mut added: Vec<(Option<u32>, String, String)> = Vec::new();
+    // (occurrence index, name, new value; empty = delete)
+    let mut changed: Vec<(u32, String, String)> = Vec::new();
+    let mut reply = None;
+    // the milter may end the message early, e.g. when it is too large
+    let mut early = None;
+    for chunk in eml[body_at..].chunks(65535) {
+        write_packet(&mut s, SMFIC_BODY, chunk).await?;
+        match read_packet(&mut s).await? {
+            Some((SMFIR_CONTINUE, _)) => {}
+            Some((cmd, data)) => {
+                early = Some(cmd);
+                reply = Some(
+                    String::from_utf8_lossy(&data)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                );
+                break;
+            }
+            None => return Err(anyhow!("milter closed the connection")),
+        }
+    }
+    if early.is_none() {
+        write_packet(&mut s, SMFIC_BODYEOB, &[]).await?;
+    }
+    let verdict = loop {
+        if let Some(cmd) = early {
+            break cmd as char;
+        }
+        let (cmd, data) = read_packet(&mut s)
+            .await?
+            .ok_or_else(|| anyhow!("milter closed the connection"))?;
+        match cmd {
+            SMFIR_ADDHEADER => {
+                let f = nul_strings(&data);
+                added.push((None, f[0].clone(), f.get(1).cloned().unwrap_or_default()));
+            }
+            SMFIR_INSHEADER => {
+                let idx = u32::from_be_bytes(data[..4].try_into()?);
+                let f = nul_strings(&data[4..]);
+                added.push((
+                    Some(idx),
+                    f[0].clone(),
+                    f.get(1).cloned().unwrap_or_default(),
+                ));
+            }
+            SMFIR_CHGHEADER => {
+                let idx = u32::from_be_bytes(data[..4].try_into()?);
+                let mut f = data[4..].split(|&b| b == 0);
+                let name = String::from_utf8_lossy(f.next().unwrap_or_default()).into_owned();
+                let value = String::from_utf8_lossy(f.next().unwrap_or_default()).into_owned();
+                changed.push((idx, name, value));
+            }
+            SMFIR_REPLYCODE => {
+                reply = Some(
+                    String::from_utf8_lossy(&data)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                );
+                break SMFIR_REPLYCODE as char;
+            }
+            other => break other as char,
+        }
+    };
+    write_packet(&mut s, SMFIC_QUIT, &[]).await?;
+
+    if let Some(out) = &args.out {
+        let mut eml = eml.clone();
+        for (idx, name, value) in &changed {
+            eml = change_header(&eml, name, *idx, value);
+        }
+        let (_, eol) = header_end(&eml);
+        let eol = String::from_utf8_lossy(eol);
+        let mut top = Vec::new();
+        let mut bottom = Vec::new();
+        for (idx, name, value) in &added {
+            let value = value.replace('\n', &eol);
+            match idx {
+                Some(_) => top.extend_from_slice(format!("{name}: {value}{eol}").as_bytes()),
+                None => bottom.push((name.as_str(), value)),
+            }
+        }
+        top.extend_from_slice(&inject_headers(&eml, &bottom));
+        fs::write(out, top)?;
+    }
+
+    println!(
+        "{}",
+        serde_json::json!({
+            "verdict": verdict.to_string(),
+            "reply": reply,
+            "headers": added
+                .iter()
+                .map(|(i, n, v)| serde_json::json!({ "index": i, "name": n, "value": v }))
+                .collect::<Vec<_>>(),
+            "changed": changed
+                .iter()
+                .map(|(i, n, v)| serde_json::json!({ "index": i, "name": n, "value": v }))
+                .collect::<Vec<_>>(),
+        })
+    );
+    Ok(())
+}
+
+/// Apply SMFIR_CHGHEADER: replace the `idx`-th (1-based) `name` field, or delete it
+/// when `value` is empty.
+fn change_header(eml: &[u8], name: &str, idx: u32, value: &str) -> Vec<u8> {
+    let (hdr_end, eol) = header_end(eml);
+    let mut out = Vec::with_capacity(eml.len());
+    let mut seen = 0;
+    let mut skipping = false;
+    for line in eml[..hdr_end].split_inclusive(|&b| b == b'\n') {
+        let folded = line.first().is_some_and(|&b| b == b' ' || b == b'\t');
+        if !folded {
+            skipping = false;
+            let is_name = line.len() > name.len()
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+                && line[name.len()..]
+                    .iter()
+                    .find(|&&b| b != b' ' && b != b'\t')
+                    == Some(&b':');
+            if is_name {
+                seen += 1;
+                if seen == idx {
+                    skipping = true;
+                    if !value.is_empty() {
+                        let value = value.replace('\n', &String::from_utf8_lossy(eol));
+                        out.extend_from_slice(format!("{name}: {value}").as_bytes());
+                        out.extend_from_slice(eol);
+                    }
+                }
+            }
+        }
+        if !skipping {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(&eml[hdr_end..]);
+    out
+}
+
+async fn step(s: &mut TcpStream, cmd: u8, data: &[u8]) -> Result<()> {
+    write_packet(s, cmd, data).await?;
+    expect(s, SMFIR_CONTINUE).await
+}
+
+async fn expect(s: &mut TcpStream, want: u8) -> Result<()> {
+    match read_packet(s).await? {
+        Some((cmd, _)) if cmd == want => Ok(()),
+        Some((cmd, _)) => Err(anyhow!(
+            "milter answered '{}' (expected '{}')",
+            cmd as char,
+            want as char
+        )),
+        None => Err(anyhow!("milter closed the connection")),
+    }
+}
@@ -0,0 +1,127 @@
+//! Sendmail milter protocol (v6) pieces used by the zkack-milter server and its
+//! check client: packet framing, command/response codes and per-message state.
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MILTER_VERSION: u32 = 6;
+
+// MTA -> filter commands
+pub const SMFIC_ABORT: u8 = b'A';
+pub const SMFIC_BODY: u8 = b'B';
+pub const SMFIC_CONNECT: u8 = b'C';
+pub const SMFIC_MACRO: u8 = b'D';
+pub const SMFIC_BODYEOB: u8 = b'E';
+pub const SMFIC_HELO: u8 = b'H';
+pub const SMFIC_QUIT_NC: u8 = b'K';
+pub const SMFIC_HEADER: u8 = b'L';
+pub const SMFIC_MAIL: u8 = b'M';
+pub const SMFIC_EOH: u8 = b'N';
+pub const SMFIC_OPTNEG: u8 = b'O';
+pub const SMFIC_QUIT: u8 = b'Q';
+pub const SMFIC_RCPT: u8 = b'R';
+pub const SMFIC_DATA: u8 = b'T';
+pub const SMFIC_UNKNOWN: u8 = b'U';
+
+// filter -> MTA responses
+pub const SMFIR_ADDHEADER: u8 = b'h';
+pub const SMFIR_INSHEADER: u8 = b'i';
+pub const SMFIR_CHGHEADER: u8 = b'm';
+pub const SMFIR_ACCEPT: u8 = b'a';
+pub const SMFIR_CONTINUE: u8 = b'c';
+pub const SMFIR_REJECT: u8 = b'r';
+pub const SMFIR_TEMPFAIL: u8 = b't';
+pub const SMFIR_REPLYCODE: u8 = b'y';
+
+// actions we request at negotiation
+pub const SMFIF_ADDHDRS: u32 = 0x01;
+pub const SMFIF_CHGHDRS: u32 = 0x10;
+
+/// Upper bound on a single packet; MTAs send bodies in 64KiB chunks.
+const MAX_PACKET: usize = 1 << 20;
+
+/// Read one packet: 4-byte big-endian length (command byte included), command, data.
+pub async fn read_packet<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET {
+        return Err(anyhow!("milter: bad packet length {len}"));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    let cmd = buf.remove(0);
+    Ok(Some((cmd, buf)))
+}
+
+pub async fn write_packet<W: AsyncWrite + Unpin>(w: &mut W, cmd: u8, data: &[u8]) -> Result<()> {
+    let len = (data.len() + 1) as u32;
+    let mut buf = Vec::with_capacity(data.len() + 5);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.push(cmd);
+    buf.extend_from_slice(data);
+    w.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Split NUL-terminated strings (MAIL/RCPT args, header name/value).
+pub fn nul_strings(data: &[u8]) -> Vec<String> {
+    data.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// `name\0value\0`, as used by SMFIC_HEADER and SMFIR_ADDHEADER.
+pub fn header_payload(name: &str, value: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len() + value.len() + 2);
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(value.as_bytes());
+    out.push(0);
+    out
+}
+
+/// Strip `<...>` from an SMTP path argument.
+pub fn smtp_path(arg: &str) -> String {
+    arg.trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+/// State of the message currently passing through a milter connection.
+#[derive(Debug, Default)]
+pub struct MessageState {
+    pub mail_from: Option<String>,
+    pub rcpt_to: Vec<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Bytes of headers and body seen so far
+    pub size: usize,
+}
+
+impl MessageState {
+    pub fn push_header(&mut self, name: String, value: String) {
+        self.size += name.len() + value.len() + 4;
+        self.headers.push((name, value));
+    }
+
+    /// Rebuild the message as seen by the filter (CRLF line endings).
+    pub fn to_eml(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 4096);
+        for (name, value) in &self.headers {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.replace("\r\n", "\n").replace('\n', "\r\n").as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
@@ -0,0 +1,538 @@
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use regex::Regex;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tracing_subscriber::{fmt::Subscriber, EnvFilter};
+
+use zkack_milter::*;
+use zkack_signer::ledger::{IssuanceRecord, JsonlLedger};
+use zkack_signer::{
+    dkim, inject_headers, issue_dat, load_key, message_id, strip_dats, DigestMode, ResignMode,
+    SignOptions, SignerKey,
+};
+
+/// Milter server: Postfix/Sendmail call it during SMTP and it adds X-ZK-DAT
+/// (same issuance as zkack-signer) to messages matching the sender/header rules.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Listen address (Postfix: smtpd_milters = inet:127.0.0.1:8892)
+    #[arg(long, default_value = "127.0.0.1:8892")]
+    listen: String,
+    /// Path to private key JSON (kid, sk_b64, vk_b64)
+    #[arg(long)]
+    privkey: String,
+    /// Key id to use (must match priv key file)
+    #[arg(long)]
+    kid: Option<String>,
+    /// ACK deadline seconds (default 900s)
+    #[arg(long, default_value_t = 900)]
+    ack_by_secs: u64,
+    /// Message digest mode; manifest survives the header changes MTAs make in transit
+    #[arg(long, value_enum, default_value_t = DigestMode::Manifest)]
+    digest: DigestMode,
+    /// Envelope sender regex; a message is signed only if one matches (repeatable)
+    #[arg(long)]
+    match_sender: Vec<String>,
+    /// Header rule "Name:regex"; every rule must match some instance of Name (repeatable)
+    #[arg(long)]
+    match_header: Vec<String>,
+    /// When the message already has X-ZK-DAT: refuse (handled per --on-error), replace
+    /// it (asks the MTA for header-change rights), or countersign next to it
+    #[arg(long, value_enum, default_value_t = ResignMode::Refuse)]
+    resign: ResignMode,
+    /// What to tell the MTA when signing a matching message fails
+    #[arg(long, value_enum, default_value_t = OnError::Tempfail)]
+    on_error: OnError,
+    /// Matching message with more than one RCPT (a DAT binds one recipient): pass it
+    /// through unsigned with a warning, or reject it (5xx) so the sender splits it
+    #[arg(long, value_enum, default_value_t = MultiRcpt::Accept)]
+    multi_rcpt: MultiRcpt,
+    /// Largest matching message (headers and body, bytes) to sign; larger ones get 552
+    #[arg(long, default_value_t = 25 * 1024 * 1024)]
+    max_size: usize,
+    /// Issuance ledger (JSON lines, as zkack-signer writes); query with ledger_query
+    #[arg(long, default_value = "./data/issuance-ledger.jsonl")]
+    ledger: PathBuf,
+    /// Do not write the issuance ledger
+    #[arg(long)]
+    no_ledger: bool,
+    /// DKIM private key to sign after injection (see zkack-signer --dkim-key)
+    #[arg(long, requires_all = ["dkim_domain", "dkim_selector"])]
+    dkim_key: Option<String>,
+    /// DKIM signing domain (d=)
+    #[arg(long)]
+    dkim_domain: Option<String>,
+    /// DKIM selector (s=)
+    #[arg(long)]
+    dkim_selector: Option<String>,
+    /// DKIM algorithm
+    #[arg(long, value_enum, default_value_t = dkim::DkimAlg::RsaSha256)]
+    dkim_alg: dkim::DkimAlg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OnError {
+    /// 4xx: the MTA keeps the message and retries
+    Tempfail,
+    /// Let the message through unsigned
+    Accept,
+    /// 5xx: bounce
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum MultiRcpt {
+    /// Let the message through unsigned
+    Accept,
+    /// 550: the sender must send one message per recipient
+    Reject,
+}
+
+/// A header change requested from the MTA at end of message.
+enum Edit {
+    Add(&'static str, String),
+    /// Insert at this position (0 = top)
+    Insert(u32, &'static str, String),
+    /// Delete the n-th (1-based) occurrence of the field
+    Delete(u32, &'static str),
+}
+
+struct Rules {
+    senders: Vec<Regex>,
+    headers: Vec<(String, Regex)>,
+}
+
+impl Rules {
+    fn parse(senders: &[String], headers: &[String]) -> Result<Self> {
+        let senders = senders
+            .iter()
+            .map(|s| Regex::new(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = headers
+            .iter()
+            .map(|h| {
+                let (name, re) = h
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("--match-header wants Name:regex, got {h}"))?;
+                Ok((name.trim().to_string(), Regex::new(re.trim())?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Rules { senders, headers })
+    }
+
+    fn matches(&self, msg: &MessageState) -> bool {
+        let sender = msg.mail_from.as_deref().unwrap_or_default();
+        if !self.senders.is_empty() && !self.senders.iter().any(|re| re.is_match(sender)) {
+            return false;
+        }
+        self.headers.iter().all(|(name, re)| {
+            msg.headers
+                .iter()
+                .any(|(n, v)| n.eq_ignore_ascii_case(name) && re.is_match(v.trim()))
+        })
+    }
+}
+
+struct Milter {
+    key: SignerKey,
+    opts: SignOptions,
+    rules: Rules,
+    on_error: OnError,
+    multi_rcpt: MultiRcpt,
+    max_size: usize,
+    ledger: Option<JsonlLedger>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _ = Subscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+    let args = Args::parse();
+
+    let dkim = match &args.dkim_key {
+        Some(path) => Some(Arc::new(dkim::DkimSigner::load(
+            path,
+            args.dkim_alg,
+            args.dkim_domain.as_deref().unwrap_or_default(),
+            args.dkim_selector.as_deref().unwrap_or_default(),
+        )?)),
+        None => None,
+    };
+    let milter = Arc::new(Milter {
+        key: load_key(&args.privkey, args.kid.as_deref())?,
+        opts: SignOptions {
+            digest: args.digest,
+            ack_by_secs: args.ack_by_secs,
+            dkim,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: args.resign,
+        },
+        rules: Rules::parse(&args.match_sender, &args.match_header)?,
+        on_error: args.on_error,
+        multi_rcpt: args.multi_rcpt,
+        max_size: args.max_size,
+        ledger: match args.no_ledger {
+            true => None,
+            false => Some(JsonlLedger::open(&args.ledger)?),
+        },
+    });
+
+    let listener = TcpListener::bind(&args.listen).await?;
+    tracing::info!(kid=%milter.key.kid, "zkack-milter listening on {}", args.listen);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let milter = milter.clone();
+        tokio::spawn(async move {
+            if let Err(e) = milter.session(stream).await {
+                tracing::warn!(%peer, "milter session ended: {e:#}");
+            }
+        });
+    }
+}
+
+impl Milter {
+    async fn session(&self, mut stream: TcpStream) -> Result<()> {
+        let mut msg = MessageState::default();
+        // whether this message gets signed, decided at its first body chunk;
+        // the body of any other message is not kept
+        let mut signing = None;
+        while let Some((cmd, data)) = read_packet(&mut stream).await? {
+            match cmd {
+                SMFIC_OPTNEG => {
+                    let version = data.get(..4).map_or(MILTER_VERSION, |v| {
+                        u32::from_be_bytes(v.try_into().unwrap())
+                    });
+                    let mut reply = Vec::with_capacity(12);
+                    reply.extend_from_slice(&version.min(MILTER_VERSION).to_be_bytes());
+                    let actions = match self.opts.resign {
+                        ResignMode::Replace => SMFIF_ADDHDRS | SMFIF_CHGHDRS,
+                        _ => SMFIF_ADDHDRS,
+                    };
+                    reply.extend_from_slice(&actions.to_be_bytes());
+                    reply.extend_from_slice(&0u32.to_be_bytes()); // no steps skipped
+                    write_packet(&mut stream, SMFIC_OPTNEG, &reply).await?;
+                }
+                SMFIC_MACRO => {}
+                SMFIC_MAIL => {
+                    msg = MessageState {
+                        mail_from: nul_strings(&data).first().map(|a| smtp_path(a)),
+                        ..Default::default()
+                    };
+                    signing = None;
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?;
+                }
+                SMFIC_RCPT => {
+                    if let Some(a) = nul_strings(&data).first() {
+                        msg.rcpt_to.push(smtp_path(a));
+                    }
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?;
+                }
+                SMFIC_HEADER => {
+                    let mut fields = data.split(|&b| b == 0);
+                    let name = String::from_utf8_lossy(fields.next().unwrap_or_default());
+                    let value = String::from_utf8_lossy(fields.next().unwrap_or_default());
+                    msg.push_header(name.into_owned(), value.into_owned());
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?;
+                }
+                SMFIC_BODY | SMFIC_BODYEOB => {
+                    let sign = *signing.get_or_insert_with(|| self.rules.matches(&msg));
+                    msg.size += data.len();
+                    if sign && msg.size > self.max_size {
+                        tracing::warn!(from=?msg.mail_from, max_size=self.max_size, "message too large to sign");
+                        reply(&mut stream, "552", "5.3.4", "message too large to sign").await?;
+                        msg = MessageState::default();
+                        signing = Some(false);
+                        continue;
+                    }
+                    if sign {
+                        msg.body.extend_from_slice(&data);
+                    }
+                    if cmd == SMFIC_BODY {
+                        write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?;
+                    } else {
+                        if sign {
+                            self.end_of_message(&mut stream, &msg).await?;
+                        } else {
+                            tracing::debug!(from=?msg.mail_from, "no rule matched; passing through");
+                            write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?;
+                        }
+                        msg = MessageState::default();
+                        signing = None;
+                    }
+                }
+                SMFIC_ABORT | SMFIC_QUIT_NC => {
+                    msg = MessageState::default();
+                    signing = None;
+                }
+                SMFIC_QUIT => break,
+                // CONNECT, HELO, DATA, EOH, UNKNOWN
+                _ => write_packet(&mut stream, SMFIR_CONTINUE, &[]).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn end_of_message(&self, stream: &mut TcpStream, msg: &MessageState) -> Result<()> {
+        let to = match msg.rcpt_to.as_slice() {
+            [one] => one,
+            // addr_hash binds one recipient; a DAT over several would be wrong for all but one
+            rcpts => {
+                return match self.multi_rcpt {
+                    MultiRcpt::Accept => {
+                        tracing::warn!(from=?msg.mail_from, rcpts=rcpts.len(), "not signing: more than one recipient; passed through unsigned");
+                        write_packet(stream, SMFIR_ACCEPT, &[]).await
+                    }
+                    MultiRcpt::Reject => {
+                        tracing::warn!(from=?msg.mail_from, rcpts=rcpts.len(), "rejected: more than one recipient");
+                        reply(
+                            stream,
+                            "550",
+                            "5.5.3",
+                            "send one message per recipient for X-ZK-DAT signing",
+                        )
+                        .await
+                    }
+                };
+            }
+        };
+        let headers = match self.sign(msg, to) {
+            Ok(h) => h,
+            Err(e) => {
+                tracing::warn!(from=?msg.mail_from, "signing failed: {e:#}");
+                return self.fail(stream).await;
+            }
+        };
+        for edit in headers {
+            match edit {
+                Edit::Add(name, value) => {
+                    let value = value.replace("\r\n", "\n");
+                    write_packet(stream, SMFIR_ADDHEADER, &header_payload(name, &value)).await?
+                }
+                Edit::Insert(i, name, value) => {
+                    let mut data = i.to_be_bytes().to_vec();
+                    data.extend_from_slice(&header_payload(name, &value.replace("\r\n", "\n")));
+                    write_packet(stream, SMFIR_INSHEADER, &data).await?;
+                }
+                // an empty value deletes the index-th occurrence of the field
+                Edit::Delete(i, name) => {
+                    let mut data = i.to_be_bytes().to_vec();
+                    data.extend_from_slice(&header_payload(name, ""));
+                    write_packet(stream, SMFIR_CHGHEADER, &data).await?;
+                }
+            }
+        }
+        tracing::info!(from=?msg.mail_from, to=%to, "X-ZK-DAT added");
+        write_packet(stream, SMFIR_CONTINUE, &[]).await
+    }
+
+    /// Header edits for one message, in the order they are sent to the MTA.
+    fn sign(&self, msg: &MessageState, to: &str) -> Result<Vec<Edit>> {
+        let mut edits = Vec::new();
+        let mut eml = msg.to_eml();
+        if self.opts.resign == ResignMode::Replace {
+            for name in ["X-ZK-DAT", "X-ZK-DAT-SD"] {
+                let n = msg
+                    .headers
+                    .iter()
+                    .filter(|(h, _)| h.eq_ignore_ascii_case(name))
+                    .count() as u32;
+                // highest occurrence first so the remaining indexes stay put
+                edits.extend((1..=n).rev().map(|i| Edit::Delete(i, name)));
+            }
+            eml = strip_dats(&eml);
+        }
+        let issued = issue_dat(&self.key, &eml, to, &self.opts)?;
+        // recorded before the MTA gets the header; a failure here fails the message
+        if let Some(ledger) = &self.ledger {
+            let mut rec = IssuanceRecord::new(&self.key.kid, &issued.dat, "zkack-milter");
+            rec.message_id = message_id(&eml);
+            rec.output = Some("milter".into());
+            ledger.append(&rec)?;
+        }
+        edits.extend(issued.headers.iter().map(|(n, v)| Edit::Add(n, v.clone())));
+        if let Some(d) = &self.opts.dkim {
+            let signed = inject_headers(&eml, &issued.headers);
+            edits.push(Edit::Insert(
+                0,
+                "DKIM-Signature",
+                d.signature_value(&signed)?,
+            ));
+        }
+        Ok(edits)
+    }
+
+    async fn fail(&self, stream: &mut TcpStream) -> Result<()> {
+        let code = match self.on_error {
+            OnError::Tempfail => SMFIR_TEMPFAIL,
+            OnError::Accept => SMFIR_ACCEPT,
+            OnError::Reject => SMFIR_REJECT,
+        };
+        write_packet(stream, code, &[]).await
+    }
+}
+
+/// SMFIR_REPLYCODE: end the message with this SMTP reply.
+async fn reply(stream: &mut TcpStream, code: &str, status: &str, text: &str) -> Result<()> {
+    write_packet(
+        stream,
+        SMFIR_REPLYCODE,
+        format!("{code} {status} {text}\0").as_bytes(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use zkack_spec::{
+        generate_keypair, jws_verify, manifest_digest_b64, part_manifest, PrivKeyJson,
+    };
+
+    const BODY: &[u8] = b"Your notice is attached.\r\n";
+
+    /// A milter signing mail from example.gov, and the key's verifying half.
+    fn milter(multi_rcpt: MultiRcpt, max_size: usize) -> (Milter, ed25519_dalek::VerifyingKey) {
+        let (sk, vk) = generate_keypair();
+        let b64 = |b: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(b);
+        let path = std::env::temp_dir().join(format!(
+            "zkack-milter-{}-{}.json",
+            std::process::id(),
+            b64(&vk.to_bytes()[..6])
+        ));
+        let key = PrivKeyJson {
+            kid: "k1".into(),
+            sk_b64: b64(&sk.to_bytes()),
+            vk_b64: b64(&vk.to_bytes()),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        };
+        std::fs::write(&path, serde_json::to_vec(&key).unwrap()).unwrap();
+        let key = load_key(path.to_str().unwrap(), None).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let milter = Milter {
+            key,
+            opts: SignOptions {
+                digest: DigestMode::Manifest,
+                ack_by_secs: 900,
+                dkim: None,
+                from: None,
+                fallbacks: zkack_spec::default_fallbacks(),
+                footer_url: None,
+                resign: ResignMode::Refuse,
+            },
+            rules: Rules::parse(&[r"@example\.gov$".into()], &[]).unwrap(),
+            on_error: OnError::Tempfail,
+            multi_rcpt,
+            max_size,
+            ledger: None,
+        };
+        (milter, vk)
+    }
+
+    async fn send(s: &mut TcpStream, cmd: u8, data: Vec<u8>) -> (u8, Vec<u8>) {
+        write_packet(s, cmd, &data).await.unwrap();
+        read_packet(s).await.unwrap().unwrap()
+    }
+
+    /// Play the MTA for one message; returns the filter's replies from the end of
+    /// the headers on (the last one ends the message).
+    async fn exchange(milter: Milter, from: &str, rcpts: &[&str]) -> Vec<(u8, Vec<u8>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            milter.session(stream).await.unwrap();
+        });
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        let mut optneg = MILTER_VERSION.to_be_bytes().to_vec();
+        optneg.extend_from_slice(&[0, 0, 0, 0x3f, 0, 0, 0, 0]);
+        assert_eq!(send(&mut s, SMFIC_OPTNEG, optneg).await.0, SMFIC_OPTNEG);
+        let path = |a: &str| format!("<{a}>\0").into_bytes();
+        assert_eq!(send(&mut s, SMFIC_MAIL, path(from)).await.0, SMFIR_CONTINUE);
+        for r in rcpts {
+            assert_eq!(send(&mut s, SMFIC_RCPT, path(r)).await.0, SMFIR_CONTINUE);
+        }
+        for (name, value) in [
+            ("From", format!("Agency <{from}>")),
+            ("To", rcpts.join(", ")),
+            ("Subject", "Notice".to_string()),
+        ] {
+            let reply = send(&mut s, SMFIC_HEADER, header_payload(name, &value)).await;
+            assert_eq!(reply.0, SMFIR_CONTINUE);
+        }
+        assert_eq!(send(&mut s, SMFIC_EOH, Vec::new()).await.0, SMFIR_CONTINUE);
+        let reply = send(&mut s, SMFIC_BODY, BODY.to_vec()).await;
+        if reply.0 != SMFIR_CONTINUE {
+            return vec![reply];
+        }
+        let mut replies = vec![send(&mut s, SMFIC_BODYEOB, Vec::new()).await];
+        while matches!(
+            replies.last().unwrap().0,
+            SMFIR_ADDHEADER | SMFIR_INSHEADER | SMFIR_CHGHEADER
+        ) {
+            replies.push(read_packet(&mut s).await.unwrap().unwrap());
+        }
+        write_packet(&mut s, SMFIC_QUIT, &[]).await.unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn matching_message_gets_a_dat_over_its_parts() {
+        let (milter, vk) = milter(MultiRcpt::Accept, 1 << 20);
+        let replies = exchange(milter, "agency@example.gov", &["you@example.com"]).await;
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].0, SMFIR_CONTINUE);
+        let (cmd, data) = &replies[0];
+        assert_eq!(*cmd, SMFIR_ADDHEADER);
+        let fields = nul_strings(data);
+        assert_eq!(fields[0], "X-ZK-DAT");
+
+        let (hdr, dat) = jws_verify(&fields[1], &|_| Some(vk)).unwrap();
+        assert_eq!(hdr.kid, "k1");
+        assert_eq!(dat.sender_domain.as_deref(), Some("example.gov"));
+        let eml = [
+            &b"From: Agency <agency@example.gov>\r\nTo: you@example.com\r\nSubject: Notice\r\n\r\n"
+                [..],
+            BODY,
+        ]
+        .concat();
+        assert_eq!(
+            dat.msg_digest_b64,
+            manifest_digest_b64(&part_manifest(&eml).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn other_senders_pass_through() {
+        let (milter, _) = milter(MultiRcpt::Accept, 1 << 20);
+        let replies = exchange(milter, "someone@example.org", &["you@example.com"]).await;
+        assert_eq!(replies, [(SMFIR_CONTINUE, Vec::new())]);
+    }
+
+    #[tokio::test]
+    async fn multi_recipient_mail_is_not_deferred() {
+        let rcpts = ["a@example.com", "b@example.com"];
+        let (milter_accept, _) = milter(MultiRcpt::Accept, 1 << 20);
+        let replies = exchange(milter_accept, "agency@example.gov", &rcpts).await;
+        assert_eq!(replies, [(SMFIR_ACCEPT, Vec::new())]);
+
+        let (milter_reject, _) = milter(MultiRcpt::Reject, 1 << 20);
+        let replies = exchange(milter_reject, "agency@example.gov", &rcpts).await;
+        assert_eq!(replies[0].0, SMFIR_REPLYCODE);
+        assert!(String::from_utf8_lossy(&replies[0].1).starts_with("550 5.5.3 "));
+    }
+
+    #[tokio::test]
+    async fn oversized_message_gets_552() {
+        let (milter, _) = milter(MultiRcpt::Accept, 64);
+        let replies = exchange(milter, "agency@example.gov", &["you@example.com"]).await;
+        assert_eq!(replies[0].0, SMFIR_REPLYCODE);
+        assert!(String::from_utf8_lossy(&replies[0].1).starts_with("552 5.3.4 "));
+    }
+}
@@ -0,0 +1,149 @@
+//! RFC 3464 delivery status notifications for messages we give up on.
+
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use zkack_signer::header_end;
+
+use crate::queue::QueueEntry;
+
+pub enum Failure<'a> {
+    /// 5xx from the next hop
+    Permanent(&'a str),
+    /// still failing when the entry hit --max-age-secs
+    Expired(&'a str),
+}
+
+/// Build the bounce for `entry` (addressed to its envelope sender), quoting the
+/// original header section.
+pub fn build_dsn(
+    reporting_mta: &str,
+    entry: &QueueEntry,
+    original: &[u8],
+    failure: Failure,
+) -> Vec<u8> {
+    let sender = entry.envelope.mail_from.as_deref().unwrap_or_default();
+    let (status, diag) = match failure {
+        Failure::Permanent(e) => ("5.0.0", e),
+        Failure::Expired(e) => ("4.4.7", e),
+    };
+    let diag = diag.replace(['\r', '\n'], " ");
+    let boundary = format!("zkack-dsn-{}", entry.id);
+    let date = |t: OffsetDateTime| t.format(&Rfc2822).unwrap_or_default();
+    let arrival =
+        OffsetDateTime::from_unix_timestamp(entry.created).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    let rcpts = entry.envelope.rcpt_to.join(", ");
+
+    let mut out = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{reporting_mta}>\r\n\
+         To: <{sender}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {}\r\n\
+         Message-ID: <{}@{reporting_mta}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         Your message could not be delivered to {rcpts} after {} attempt(s).\r\n\
+         \r\n\
+         {diag}\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {reporting_mta}\r\n\
+         Arrival-Date: {}\r\n",
+        date(OffsetDateTime::now_utc()),
+        uuid::Uuid::new_v4().simple(),
+        entry.attempts,
+        date(arrival),
+    );
+    for rcpt in &entry.envelope.rcpt_to {
+        out.push_str(&format!(
+            "\r\nFinal-Recipient: rfc822; {rcpt}\r\n\
+             Action: failed\r\n\
+             Status: {status}\r\n\
+             Diagnostic-Code: smtp; {diag}\r\n"
+        ));
+    }
+    out.push_str(&format!(
+        "\r\n--{boundary}\r\nContent-Type: text/rfc822-headers\r\n\r\n"
+    ));
+    let mut out = out.into_bytes();
+    out.extend_from_slice(&original[..header_end(original).0]);
+    out.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::MailHeaderMap;
+    use zkack_signer::smtp::MailEnvelope;
+
+    const ORIGINAL: &[u8] =
+        b"From: agency@example.gov\r\nTo: you@example.com\r\nSubject: Notice\r\n\r\nsecret body\r\n";
+
+    fn entry() -> QueueEntry {
+        let mut e = QueueEntry::new(
+            MailEnvelope {
+                mail_from: Some("agency@example.gov".into()),
+                rcpt_to: vec!["you@example.com".into()],
+            },
+            Some("k1".into()),
+            Some("nonce".into()),
+        );
+        e.attempts = 3;
+        e
+    }
+
+    #[test]
+    fn dsn_reports_each_recipient_and_quotes_headers_only() {
+        let dsn = build_dsn(
+            "relay.example.gov",
+            &entry(),
+            ORIGINAL,
+            Failure::Permanent("550 no such\r\nuser"),
+        );
+        let mail = mailparse::parse_mail(&dsn).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("To").unwrap(),
+            "<agency@example.gov>"
+        );
+        assert_eq!(mail.ctype.mimetype, "multipart/report");
+        assert_eq!(mail.ctype.params["report-type"], "delivery-status");
+        let types: Vec<_> = mail
+            .subparts
+            .iter()
+            .map(|p| p.ctype.mimetype.as_str())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "text/plain",
+                "message/delivery-status",
+                "text/rfc822-headers"
+            ]
+        );
+
+        let status = String::from_utf8(mail.subparts[1].get_body_raw().unwrap()).unwrap();
+        assert!(status.contains("Reporting-MTA: dns; relay.example.gov\r\n"));
+        assert!(status.contains("Final-Recipient: rfc822; you@example.com\r\n"));
+        assert!(status.contains("Status: 5.0.0\r\n"));
+        assert!(status.contains("Diagnostic-Code: smtp; 550 no such  user\r\n"));
+
+        let quoted = mail.subparts[2].get_body_raw().unwrap();
+        assert!(quoted.starts_with(b"From: agency@example.gov\r\n"));
+        assert!(!String::from_utf8_lossy(&dsn).contains("secret body"));
+    }
+
+    #[test]
+    fn expiry_is_a_4_4_7() {
+        let dsn = build_dsn("relay", &entry(), ORIGINAL, Failure::Expired("451 busy"));
+        let text = String::from_utf8(dsn).unwrap();
+        assert!(text.contains("Status: 4.4.7\r\n"));
+        assert!(text.contains("after 3 attempt(s)"));
+    }
+}
@@ -0,0 +1,746 @@
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing_subscriber::{fmt::Subscriber, EnvFilter};
+
+use zkack_signer::ledger::{IssuanceRecord, JsonlLedger};
+use zkack_signer::smtp::{is_permanent, strip_header, MailEnvelope, SubmissionRecord, Submitter};
+use zkack_signer::{
+    dkim, is_refused, load_key, message_id, sign_message, DigestMode, ResignMode, SignOptions,
+    SignerKey,
+};
+
+mod dsn;
+mod queue;
+
+use queue::{now_unix, Queue, QueueEntry};
+
+/// SMTP proxy: accepts mail, adds X-ZK-DAT (same issuance as zkack-signer, one
+/// signed copy per recipient), queues it on disk and relays to the next hop.
+#[derive(Parser, Debug)]
+struct Args {
+    /// SMTP listen address (plain SMTP, no AUTH: keep it on a trusted network)
+    #[arg(long, default_value = "127.0.0.1:2525")]
+    listen: String,
+    /// Next hop (see zkack-signer --smtp-url)
+    #[arg(long)]
+    relay: String,
+    /// Path to private key JSON (kid, sk_b64, vk_b64)
+    #[arg(long)]
+    privkey: String,
+    /// Key id to use (must match priv key file)
+    #[arg(long)]
+    kid: Option<String>,
+    /// ACK deadline seconds (default 900s)
+    #[arg(long, default_value_t = 900)]
+    ack_by_secs: u64,
+    /// Message digest mode; manifest survives header changes made by later hops
+    #[arg(long, value_enum, default_value_t = DigestMode::Manifest)]
+    digest: DigestMode,
+    /// When a submitted message already has X-ZK-DAT: refuse, replace it, or countersign
+    #[arg(long, value_enum, default_value_t = ResignMode::Refuse)]
+    resign: ResignMode,
+    /// DKIM private key to sign after injection (see zkack-signer --dkim-key)
+    #[arg(long, requires_all = ["dkim_domain", "dkim_selector"])]
+    dkim_key: Option<String>,
+    /// DKIM signing domain (d=)
+    #[arg(long)]
+    dkim_domain: Option<String>,
+    /// DKIM selector (s=)
+    #[arg(long)]
+    dkim_selector: Option<String>,
+    /// DKIM algorithm
+    #[arg(long, value_enum, default_value_t = dkim::DkimAlg::RsaSha256)]
+    dkim_alg: dkim::DkimAlg,
+    /// Name used in the greeting and as Reporting-MTA in DSNs
+    #[arg(long, default_value = "localhost")]
+    hostname: String,
+    /// Queue directory
+    #[arg(long, default_value = "./data/relay-queue")]
+    queue_dir: PathBuf,
+    /// Delivery attempts log (JSON lines, same records as zkack-signer --smtp-log)
+    #[arg(long, default_value = "./data/relay-deliveries.jsonl")]
+    log: PathBuf,
+    /// Largest message accepted, in bytes
+    #[arg(long, default_value_t = 25 * 1024 * 1024)]
+    max_size: usize,
+    /// First retry delay; doubles per attempt, up to 64x
+    #[arg(long, default_value_t = 300)]
+    retry_secs: i64,
+    /// Give up (and bounce) after this long in the queue
+    #[arg(long, default_value_t = 5 * 24 * 3600)]
+    max_age_secs: i64,
+    /// Issuance ledger (JSON lines, as zkack-signer writes); query with ledger_query
+    #[arg(long, default_value = "./data/issuance-ledger.jsonl")]
+    ledger: PathBuf,
+    /// Do not write the issuance ledger
+    #[arg(long)]
+    no_ledger: bool,
+}
+
+const MAX_RCPTS: usize = 100;
+/// Longest command line, CRLF included (RFC 5321 4.5.3.1.4)
+const MAX_COMMAND_LINE: usize = 512;
+/// Longest text line (RFC 5321 4.5.3.1.6); used while discarding an oversized message
+const MAX_TEXT_LINE: usize = 1000;
+const QUEUE_POLL: Duration = Duration::from_secs(5);
+
+struct Relay {
+    key: SignerKey,
+    opts: SignOptions,
+    queue: Queue,
+    submitter: Arc<Submitter>,
+    hostname: String,
+    log: PathBuf,
+    max_size: usize,
+    retry_secs: i64,
+    max_age_secs: i64,
+    ledger: Option<JsonlLedger>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _ = Subscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+    let args = Args::parse();
+
+    let dkim = match &args.dkim_key {
+        Some(path) => Some(Arc::new(dkim::DkimSigner::load(
+            path,
+            args.dkim_alg,
+            args.dkim_domain.as_deref().unwrap_or_default(),
+            args.dkim_selector.as_deref().unwrap_or_default(),
+        )?)),
+        None => None,
+    };
+    let relay = Arc::new(Relay {
+        key: load_key(&args.privkey, args.kid.as_deref())?,
+        opts: SignOptions {
+            digest: args.digest,
+            ack_by_secs: args.ack_by_secs,
+            dkim,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: args.resign,
+        },
+        queue: Queue::open(&args.queue_dir)?,
+        submitter: Arc::new(Submitter::from_url(&args.relay)?),
+        hostname: args.hostname,
+        log: args.log,
+        max_size: args.max_size,
+        retry_secs: args.retry_secs,
+        max_age_secs: args.max_age_secs,
+        ledger: match args.no_ledger {
+            true => None,
+            false => Some(JsonlLedger::open(&args.ledger)?),
+        },
+    });
+
+    let worker = relay.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = worker.run_queue().await {
+                tracing::warn!("queue run failed: {e:#}");
+            }
+            tokio::time::sleep(QUEUE_POLL).await;
+        }
+    });
+
+    let listener = TcpListener::bind(&args.listen).await?;
+    tracing::info!(kid=%relay.key.kid, next_hop=%relay.submitter.relay(), "zkack-relay listening on {}", args.listen);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            if let Err(e) = relay.session(stream).await {
+                tracing::warn!(%peer, "smtp session ended: {e:#}");
+            }
+        });
+    }
+}
+
+impl Relay {
+    async fn session(self: Arc<Self>, stream: TcpStream) -> Result<()> {
+        let (r, mut w) = stream.into_split();
+        let mut r = BufReader::new(r);
+        reply(&mut w, format!("220 {} ESMTP zkack-relay", self.hostname)).await?;
+
+        let mut envelope: Option<MailEnvelope> = None;
+        let mut line = Vec::new();
+        loop {
+            match read_line(&mut r, &mut line, MAX_COMMAND_LINE).await? {
+                None => return Ok(()),
+                Some(false) => {
+                    reply(&mut w, "500 5.5.2 line too long".into()).await?;
+                    continue;
+                }
+                Some(true) => {}
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            let verb = text
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_uppercase();
+            let resp = match verb.as_str() {
+                "EHLO" => format!(
+                    "250-{}\r\n250-PIPELINING\r\n250-8BITMIME\r\n250 SIZE {}",
+                    self.hostname, self.max_size
+                ),
+                "HELO" => format!("250 {}", self.hostname),
+                "MAIL" if envelope.is_some() => "503 5.5.1 MAIL already given".into(),
+                "MAIL" => match path_arg(&text, "FROM:") {
+                    Some(from) => {
+                        envelope = Some(MailEnvelope {
+                            mail_from: (!from.is_empty()).then_some(from),
+                            rcpt_to: Vec::new(),
+                        });
+                        "250 2.1.0 OK".into()
+                    }
+                    None => "501 5.5.4 Syntax: MAIL FROM:<address>".into(),
+                },
+                "RCPT" => match (&mut envelope, path_arg(&text, "TO:")) {
+                    (None, _) => "503 5.5.1 need MAIL first".into(),
+                    (Some(_), None) => "501 5.5.4 Syntax: RCPT TO:<address>".into(),
+                    (Some(env), Some(_)) if env.rcpt_to.len() >= MAX_RCPTS => {
+                        "452 4.5.3 too many recipients".into()
+                    }
+                    (Some(env), Some(to)) => {
+                        env.rcpt_to.push(to);
+                        "250 2.1.5 OK".into()
+                    }
+                },
+                "DATA" => match envelope.take() {
+                    Some(env) if !env.rcpt_to.is_empty() => {
+                        reply(&mut w, "354 end data with <CRLF>.<CRLF>".into()).await?;
+                        match read_data(&mut r, self.max_size).await? {
+                            Some(eml) => {
+                                // signing and fsync'd queue writes block
+                                let relay = self.clone();
+                                tokio::task::spawn_blocking(move || relay.accept(env, &eml)).await?
+                            }
+                            None => "552 5.3.4 message too big".into(),
+                        }
+                    }
+                    other => {
+                        envelope = other;
+                        "503 5.5.1 need RCPT first".into()
+                    }
+                },
+                "RSET" => {
+                    envelope = None;
+                    "250 2.0.0 OK".into()
+                }
+                "NOOP" => "250 2.0.0 OK".into(),
+                "VRFY" => "252 2.1.5 cannot verify".into(),
+                "QUIT" => {
+                    reply(&mut w, "221 2.0.0 bye".into()).await?;
+                    return Ok(());
+                }
+                _ => "502 5.5.2 command not recognized".into(),
+            };
+            reply(&mut w, resp).await?;
+        }
+    }
+
+    /// Sign one copy per recipient (a DAT binds one address) and queue them;
+    /// all or nothing, so a 4xx never leaves half the recipients queued.
+    fn accept(&self, env: MailEnvelope, eml: &[u8]) -> String {
+        let eml = strip_header(eml, "Bcc");
+        let mut signed = Vec::with_capacity(env.rcpt_to.len());
+        for rcpt in &env.rcpt_to {
+            match sign_message(&self.key, &eml, rcpt, &self.opts) {
+                Ok(s) => signed.push((rcpt, s)),
+                Err(e) => {
+                    tracing::warn!(from=?env.mail_from, %rcpt, "signing failed: {e:#}");
+                    // a policy refusal will not change on retry; do not keep the sender waiting
+                    if is_refused(&e) {
+                        return format!("550 5.7.1 not signed: {}", e.root_cause())
+                            .replace(['\r', '\n'], " ");
+                    }
+                    return "451 4.3.0 signing failed".into();
+                }
+            }
+        }
+        let entries: Vec<QueueEntry> = signed
+            .iter()
+            .map(|(rcpt, s)| {
+                QueueEntry::new(
+                    MailEnvelope {
+                        mail_from: env.mail_from.clone(),
+                        rcpt_to: vec![(*rcpt).clone()],
+                    },
+                    Some(self.key.kid.clone()),
+                    Some(s.dat.nonce_b64.clone()),
+                )
+            })
+            .collect();
+        // ledger first: once queued, an entry may be delivered before we answer, and
+        // a 451 after that would have the client send it again
+        if let Some(ledger) = &self.ledger {
+            for ((_, s), entry) in signed.iter().zip(&entries) {
+                let mut rec = IssuanceRecord::new(&self.key.kid, &s.dat, "zkack-relay");
+                rec.message_id = message_id(&s.message);
+                rec.output = Some(format!("{}#{}", self.submitter.relay(), entry.id));
+                if let Err(e) = ledger.append(&rec) {
+                    tracing::warn!("ledger write failed: {e:#}");
+                    return "451 4.3.0 ledger write failed".into();
+                }
+            }
+        }
+        let items: Vec<(&QueueEntry, &[u8])> = entries
+            .iter()
+            .zip(&signed)
+            .map(|(entry, (_, s))| (entry, s.message.as_slice()))
+            .collect();
+        if let Err(e) = self.queue.push_all(&items) {
+            tracing::warn!("queue write failed: {e:#}");
+            return "451 4.3.0 queue write failed".into();
+        }
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        tracing::info!(from=?env.mail_from, rcpts=ids.len(), "queued with X-ZK-DAT");
+        format!("250 2.0.0 queued as {}", ids.join(","))
+    }
+
+    async fn run_queue(&self) -> Result<()> {
+        for mut entry in self.queue.due(now_unix())? {
+            let message = self.queue.message(&entry.id)?;
+            let submitter = self.submitter.clone();
+            let (env, msg) = (entry.envelope.clone(), message.clone());
+            let sent = tokio::task::spawn_blocking(move || submitter.send(&env, &msg)).await?;
+            entry.attempts += 1;
+            let (response, error) = match &sent {
+                Ok(r) => (Some(r.clone()), None),
+                Err(e) => (None, Some(format!("{e:#}"))),
+            };
+            // the attempt has happened either way; a log write failure must not
+            // stop the bookkeeping below, or a delivered entry would be sent again
+            let logged = SubmissionRecord {
+                ts: time::OffsetDateTime::now_utc()
+                    .format(&time::format_description::well_known::Rfc3339)?,
+                relay: self.submitter.relay(),
+                kid: entry.kid.as_deref().unwrap_or_default(),
+                nonce_b64: entry.nonce_b64.as_deref().unwrap_or_default(),
+                envelope: &entry.envelope,
+                ok: sent.is_ok(),
+                response,
+                error: error.clone(),
+            }
+            .append_to(&self.log);
+            if let Err(e) = logged {
+                tracing::warn!(id=%entry.id, log=%self.log.display(), "delivery log write failed: {e:#}");
+            }
+
+            let Err(err) = sent else {
+                tracing::info!(id=%entry.id, rcpt=?entry.envelope.rcpt_to, "delivered");
+                self.queue.remove(&entry.id)?;
+                continue;
+            };
+            let error = error.unwrap_or_default();
+            let failure = if is_permanent(&err) {
+                dsn::Failure::Permanent(&error)
+            } else if now_unix() - entry.created >= self.max_age_secs {
+                dsn::Failure::Expired(&error)
+            } else {
+                entry.next_attempt = now_unix() + backoff(self.retry_secs, entry.attempts);
+                entry.last_error = Some(error.clone());
+                tracing::info!(id=%entry.id, attempts=entry.attempts, "deferred: {error}");
+                self.queue.update(&entry)?;
+                continue;
+            };
+            tracing::warn!(id=%entry.id, rcpt=?entry.envelope.rcpt_to, "giving up: {error}");
+            // never bounce a bounce, and a null sender has nowhere to go
+            if let (false, Some(sender)) = (entry.is_bounce(), &entry.envelope.mail_from) {
+                let bounce = dsn::build_dsn(&self.hostname, &entry, &message, failure);
+                let dsn_entry = QueueEntry::new(
+                    MailEnvelope {
+                        mail_from: None,
+                        rcpt_to: vec![sender.clone()],
+                    },
+                    None,
+                    None,
+                );
+                self.queue.push(&dsn_entry, &bounce)?;
+            }
+            self.queue.remove(&entry.id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delay before the next attempt after `attempts` failed ones: doubling, up to 64x.
+fn backoff(retry_secs: i64, attempts: u32) -> i64 {
+    retry_secs << attempts.saturating_sub(1).min(6)
+}
+
+async fn reply<W: AsyncWriteExt + Unpin>(w: &mut W, text: String) -> Result<()> {
+    w.write_all(format!("{text}\r\n").as_bytes()).await?;
+    Ok(())
+}
+
+/// `MAIL FROM:<a@b> SIZE=..` -> `a@b` (empty for the null path).
+fn path_arg(line: &str, prefix: &str) -> Option<String> {
+    let rest = line.split_once(' ')?.1.trim_start();
+    if rest.len() < prefix.len() || !rest[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = rest[prefix.len()..].trim_start();
+    let path = rest.strip_prefix('<')?.split_once('>')?.0;
+    Some(path.to_string())
+}
+
+/// Read one line of at most `max` bytes into `buf`; None at end of input. A longer
+/// line is consumed to its end without being kept, and Some(false) returned.
+async fn read_line<R: AsyncBufReadExt + Unpin>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    max: usize,
+) -> Result<Option<bool>> {
+    buf.clear();
+    let n = (&mut *r).take(max as u64).read_until(b'\n', buf).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < max || buf.ends_with(b"\n") {
+        return Ok(Some(true));
+    }
+    let mut rest = Vec::new();
+    loop {
+        rest.clear();
+        let n = (&mut *r).take(4096).read_until(b'\n', &mut rest).await?;
+        if n == 0 || rest.ends_with(b"\n") {
+            return Ok(Some(false));
+        }
+    }
+}
+
+/// Read DATA up to the lone dot, undoing dot-stuffing; None if over `max`. No more
+/// than `max` bytes (plus one line) are ever held.
+async fn read_data<R: AsyncBufReadExt + Unpin>(r: &mut R, max: usize) -> Result<Option<Vec<u8>>> {
+    let mut out = Vec::new();
+    let mut line = Vec::new();
+    let mut too_big = false;
+    loop {
+        // room for what is left of `max`, a stuffed dot and CRLF
+        let room = if too_big {
+            MAX_TEXT_LINE
+        } else {
+            max - out.len() + 3
+        };
+        let fits = read_line(r, &mut line, room)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("connection closed during DATA"))?;
+        if fits && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+        let data = line.strip_prefix(b".").unwrap_or(&line);
+        if !too_big && (!fits || out.len() + data.len() > max) {
+            too_big = true;
+            out = Vec::new();
+        }
+        if !too_big {
+            out.extend_from_slice(data);
+        }
+    }
+    Ok((!too_big).then_some(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use std::io::{BufRead, Write};
+    use std::sync::Mutex;
+
+    const EML: &str = "From: Agency <agency@example.gov>\r\n\
+                       To: you@example.com\r\n\
+                       Bcc: audit@example.gov\r\n\
+                       Subject: Notice\r\n\
+                       \r\n\
+                       Hello\r\n\
+                       .leading dot\r\n";
+
+    /// Transactions the next-hop stand-in accepted: MAIL FROM, RCPT TOs, message.
+    type Delivered = Arc<Mutex<Vec<(String, Vec<String>, Vec<u8>)>>>;
+
+    /// SMTP stand-in for the next hop, answering every RCPT with `rcpt_reply`.
+    fn next_hop(rcpt_reply: &'static str) -> (String, Delivered) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let delivered = Delivered::default();
+        let seen = delivered.clone();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let conn = conn.unwrap();
+                let seen = seen.clone();
+                std::thread::spawn(move || {
+                    let mut out = conn.try_clone().unwrap();
+                    let mut r = std::io::BufReader::new(conn);
+                    let (mut from, mut rcpts) = (String::new(), Vec::new());
+                    out.write_all(b"220 next-hop ESMTP\r\n").unwrap();
+                    let mut line = String::new();
+                    while {
+                        line.clear();
+                        r.read_line(&mut line).unwrap_or(0) > 0
+                    } {
+                        let cmd = line.trim_end();
+                        let arg = |p: &str| path_arg(cmd, p).unwrap_or_default();
+                        let verb = cmd.split([' ', ':']).next().unwrap().to_ascii_uppercase();
+                        let reply = match verb.as_str() {
+                            "EHLO" => "250 next-hop",
+                            "MAIL" => {
+                                (from, rcpts) = (arg("FROM:"), Vec::new());
+                                "250 ok"
+                            }
+                            "RCPT" => {
+                                rcpts.push(arg("TO:"));
+                                rcpt_reply
+                            }
+                            "DATA" => {
+                                out.write_all(b"354 go ahead\r\n").unwrap();
+                                let mut data = Vec::new();
+                                let mut l = Vec::new();
+                                loop {
+                                    l.clear();
+                                    r.read_until(b'\n', &mut l).unwrap();
+                                    if l == b".\r\n" {
+                                        break;
+                                    }
+                                    data.extend_from_slice(l.strip_prefix(b".").unwrap_or(&l));
+                                }
+                                let rcpts = std::mem::take(&mut rcpts);
+                                seen.lock().unwrap().push((from.clone(), rcpts, data));
+                                "250 queued"
+                            }
+                            "QUIT" => {
+                                let _ = out.write_all(b"221 bye\r\n");
+                                break;
+                            }
+                            _ => "250 ok",
+                        };
+                        if out.write_all(format!("{reply}\r\n").as_bytes()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (url, delivered)
+    }
+
+    fn test_relay(name: &str, next_hop: &str) -> (Arc<Relay>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("zkack-relay-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let b64 = |b: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(b);
+        let key = zkack_spec::PrivKeyJson {
+            kid: "k1".into(),
+            sk_b64: b64(&sk.to_bytes()),
+            vk_b64: b64(sk.verifying_key().as_bytes()),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        };
+        let key_path = dir.join("key.json");
+        std::fs::write(&key_path, serde_json::to_vec(&key).unwrap()).unwrap();
+        let relay = Relay {
+            key: load_key(key_path.to_str().unwrap(), None).unwrap(),
+            opts: SignOptions {
+                digest: DigestMode::Manifest,
+                ack_by_secs: 900,
+                dkim: None,
+                from: None,
+                fallbacks: zkack_spec::default_fallbacks(),
+                footer_url: None,
+                resign: ResignMode::Refuse,
+            },
+            queue: Queue::open(&dir.join("queue")).unwrap(),
+            submitter: Arc::new(Submitter::from_url(next_hop).unwrap()),
+            hostname: "relay.example.gov".into(),
+            log: dir.join("deliveries.jsonl"),
+            max_size: 1 << 20,
+            retry_secs: 300,
+            max_age_secs: 3600,
+            ledger: Some(JsonlLedger::open(&dir.join("ledger.jsonl")).unwrap()),
+        };
+        (Arc::new(relay), dir)
+    }
+
+    /// Read one (possibly multi-line) reply and check its code.
+    async fn expect<R: AsyncBufReadExt + Unpin>(r: &mut R, code: &str) -> String {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            r.read_line(&mut line).await.unwrap();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        assert!(line.starts_with(code), "{line}");
+        line.trim_end().to_string()
+    }
+
+    /// Submit `eml` over SMTP to the relay; returns the reply to the final dot.
+    async fn submit(relay: &Arc<Relay>, from: &str, rcpts: &[&str], eml: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = relay.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.session(stream).await.unwrap();
+        });
+        let (r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut r = BufReader::new(r);
+        expect(&mut r, "220").await;
+        let mut lines = vec!["EHLO client".to_string(), format!("MAIL FROM:<{from}>")];
+        lines.extend(rcpts.iter().map(|r| format!("RCPT TO:<{r}>")));
+        lines.push("DATA".into());
+        for l in &lines {
+            w.write_all(format!("{l}\r\n").as_bytes()).await.unwrap();
+            expect(&mut r, if l == "DATA" { "354" } else { "250" }).await;
+        }
+        let stuffed = eml.replace("\r\n.", "\r\n..");
+        w.write_all(format!("{stuffed}.\r\nQUIT\r\n").as_bytes())
+            .await
+            .unwrap();
+        let resp = expect(&mut r, "").await;
+        expect(&mut r, "221").await;
+        resp
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_64x() {
+        let steps: Vec<_> = (1..=9).map(|n| backoff(300, n)).collect();
+        assert_eq!(
+            steps,
+            [300, 600, 1200, 2400, 4800, 9600, 19200, 19200, 19200]
+        );
+        assert_eq!(backoff(300, 0), 300);
+    }
+
+    #[tokio::test]
+    async fn accepted_mail_is_signed_per_recipient_and_delivered() {
+        let (url, delivered) = next_hop("250 ok");
+        let (relay, dir) = test_relay("deliver", &url);
+        let rcpts = ["you@example.com", "audit@example.gov"];
+        let resp = submit(&relay, "agency@example.gov", &rcpts, EML).await;
+        assert!(resp.starts_with("250 2.0.0 queued as "), "{resp}");
+
+        // the ledger is written before the copies are queued
+        let ledger = zkack_signer::ledger::read_records(&dir.join("ledger.jsonl")).unwrap();
+        assert_eq!(ledger.len(), 2);
+        let queued = relay.queue.due(now_unix()).unwrap();
+        assert_eq!(queued.len(), 2);
+
+        relay.run_queue().await.unwrap();
+        assert!(relay.queue.due(i64::MAX).unwrap().is_empty());
+        let delivered = delivered.lock().unwrap().clone();
+        assert_eq!(delivered.len(), 2);
+        for (from, to, data) in &delivered {
+            assert_eq!(from, "agency@example.gov");
+            assert_eq!(to.len(), 1);
+            let text = String::from_utf8_lossy(data);
+            assert!(text.contains("\r\nX-ZK-DAT: "), "{text}");
+            assert!(!text.contains("Bcc:"));
+            assert!(text.ends_with("\r\n.leading dot\r\n"));
+            assert!(ledger.iter().any(|r| r.is_for(&to[0])));
+        }
+        let log = std::fs::read_to_string(dir.join("deliveries.jsonl")).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sender_outside_the_key_domains_gets_550() {
+        let (url, _) = next_hop("250 ok");
+        let (relay, dir) = test_relay("refuse", &url);
+        let eml = EML.replace("agency@example.gov", "someone@example.org");
+        let resp = submit(&relay, "someone@example.org", &["you@example.com"], &eml).await;
+        assert!(resp.starts_with("550 5.7.1 "), "{resp}");
+        assert!(relay.queue.due(i64::MAX).unwrap().is_empty());
+        let ledger = zkack_signer::ledger::read_records(&dir.join("ledger.jsonl")).unwrap();
+        assert!(ledger.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn queued(relay: &Relay, created: i64) -> QueueEntry {
+        let entry = QueueEntry {
+            created,
+            ..QueueEntry::new(
+                MailEnvelope {
+                    mail_from: Some("agency@example.gov".into()),
+                    rcpt_to: vec!["you@example.com".into()],
+                },
+                Some("k1".into()),
+                Some("nonce".into()),
+            )
+        };
+        relay.queue.push(&entry, EML.as_bytes()).unwrap();
+        entry
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_bounces_once_and_never_bounces_a_bounce() {
+        let (url, delivered) = next_hop("550 5.1.1 no such user");
+        let (relay, dir) = test_relay("bounce", &url);
+        let original = queued(&relay, now_unix());
+
+        relay.run_queue().await.unwrap();
+        let dsns = relay.queue.due(i64::MAX).unwrap();
+        assert_eq!(dsns.len(), 1);
+        assert!(dsns[0].is_bounce());
+        assert_ne!(dsns[0].id, original.id);
+        assert_eq!(dsns[0].envelope.mail_from, None);
+        assert_eq!(dsns[0].envelope.rcpt_to, ["agency@example.gov"]);
+        let dsn = String::from_utf8(relay.queue.message(&dsns[0].id).unwrap()).unwrap();
+        assert!(dsn.contains("Status: 5.0.0"));
+        assert!(dsn.contains("no such user"));
+
+        // the bounce is refused too: it is dropped, not bounced again
+        relay.run_queue().await.unwrap();
+        assert!(relay.queue.due(i64::MAX).unwrap().is_empty());
+        assert!(delivered.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transient_failure_backs_off_then_expires_into_a_dsn() {
+        let (url, _) = next_hop("451 4.3.0 try later");
+        let (relay, dir) = test_relay("defer", &url);
+        let entry = queued(&relay, now_unix());
+
+        let before = now_unix();
+        relay.run_queue().await.unwrap();
+        assert!(relay.queue.due(before + 299).unwrap().is_empty());
+        let mut deferred = relay.queue.due(i64::MAX).unwrap();
+        assert_eq!(deferred.len(), 1);
+        let mut deferred = deferred.remove(0);
+        assert_eq!(deferred.id, entry.id);
+        assert_eq!(deferred.attempts, 1);
+        assert!(deferred.next_attempt >= before + 300);
+        assert!(deferred
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("try later"));
+
+        // past --max-age-secs the next failure gives up
+        deferred.created -= relay.max_age_secs;
+        deferred.next_attempt = now_unix();
+        relay.queue.update(&deferred).unwrap();
+        relay.run_queue().await.unwrap();
+        let left = relay.queue.due(i64::MAX).unwrap();
+        assert_eq!(left.len(), 1);
+        assert!(left[0].is_bounce());
+        let dsn = String::from_utf8(relay.queue.message(&left[0].id).unwrap()).unwrap();
+        assert!(dsn.contains("Status: 4.4.7"));
+        assert!(dsn.contains("after 2 attempt(s)"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! On-disk delivery queue: `<id>.eml` holds the signed message, `<id>.json` its
+//! envelope and retry state. The JSON is written last (via rename), so only
+//! complete entries are ever picked up. Both files and the directory are synced
+//! before push returns: a message the relay has answered 250 for survives a crash.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zkack_signer::smtp::MailEnvelope;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub envelope: MailEnvelope,
+    /// None for DSNs we generate ourselves (they carry no DAT)
+    pub kid: Option<String>,
+    pub nonce_b64: Option<String>,
+    pub created: i64,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+}
+
+impl QueueEntry {
+    pub fn new(envelope: MailEnvelope, kid: Option<String>, nonce_b64: Option<String>) -> Self {
+        let now = now_unix();
+        QueueEntry {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            envelope,
+            kid,
+            nonce_b64,
+            created: now,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+        }
+    }
+
+    pub fn is_bounce(&self) -> bool {
+        self.kid.is_none()
+    }
+}
+
+pub struct Queue {
+    dir: PathBuf,
+}
+
+impl Queue {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Queue { dir: dir.into() })
+    }
+
+    pub fn push(&self, entry: &QueueEntry, message: &[u8]) -> Result<()> {
+        self.push_all(&[(entry, message)])
+    }
+
+    /// Queue several entries together: all their files are written and synced before
+    /// any of them becomes visible, so a failure leaves none queued.
+    pub fn push_all(&self, items: &[(&QueueEntry, &[u8])]) -> Result<()> {
+        let staged = items.iter().try_for_each(|(entry, message)| {
+            write_synced(&self.path(&entry.id, "eml"), message)?;
+            write_synced(
+                &self.path(&entry.id, "json.tmp"),
+                &serde_json::to_vec_pretty(entry)?,
+            )
+        });
+        if let Err(e) = staged {
+            for (entry, _) in items {
+                let _ = fs::remove_file(self.path(&entry.id, "json.tmp"));
+                let _ = fs::remove_file(self.path(&entry.id, "eml"));
+            }
+            return Err(e);
+        }
+        for (entry, _) in items {
+            fs::rename(
+                self.path(&entry.id, "json.tmp"),
+                self.path(&entry.id, "json"),
+            )?;
+        }
+        fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Rewrite an entry's state (after a failed attempt).
+    pub fn update(&self, entry: &QueueEntry) -> Result<()> {
+        let tmp = self.path(&entry.id, "json.tmp");
+        write_synced(&tmp, &serde_json::to_vec_pretty(entry)?)?;
+        fs::rename(tmp, self.path(&entry.id, "json"))?;
+        // the new directory entries (eml, renamed json) must be durable too
+        fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn message(&self, id: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.path(id, "eml"))?)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        fs::remove_file(self.path(id, "json"))?;
+        fs::remove_file(self.path(id, "eml"))?;
+        Ok(())
+    }
+
+    /// Entries whose next attempt is due, oldest first.
+    pub fn due(&self, now: i64) -> Result<Vec<QueueEntry>> {
+        let mut out = Vec::new();
+        for ent in fs::read_dir(&self.dir)? {
+            let path = ent?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match serde_json::from_slice::<QueueEntry>(&fs::read(&path)?) {
+                Ok(e) if e.next_attempt <= now => out.push(e),
+                Ok(_) => {}
+                Err(e) => tracing::warn!(path=%path.display(), "skipping bad queue entry: {e}"),
+            }
+        }
+        out.sort_by_key(|e| e.created);
+        Ok(out)
+    }
+
+    fn path(&self, id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{ext}"))
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+    let mut f = fs::File::create(path)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+pub fn now_unix() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(name: &str) -> (Queue, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("zkack-relay-queue-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (Queue::open(&dir).unwrap(), dir)
+    }
+
+    fn entry(rcpt: &str, created: i64) -> QueueEntry {
+        let mut e = QueueEntry::new(
+            MailEnvelope {
+                mail_from: Some("agency@example.gov".into()),
+                rcpt_to: vec![rcpt.into()],
+            },
+            Some("k1".into()),
+            Some("nonce".into()),
+        );
+        e.created = created;
+        e.next_attempt = created;
+        e
+    }
+
+    #[test]
+    fn pushed_entries_come_back_due_oldest_first() {
+        let (q, dir) = queue("due");
+        let (late, early) = (entry("b@example.com", 200), entry("a@example.com", 100));
+        q.push(&late, b"second").unwrap();
+        q.push_all(&[(&early, b"first".as_slice())]).unwrap();
+        // a half-written entry is never picked up
+        fs::write(q.path("stray", "json.tmp"), b"{").unwrap();
+
+        let due = q.due(1000).unwrap();
+        let ids: Vec<_> = due.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, [early.id.as_str(), late.id.as_str()]);
+        assert_eq!(due[0].envelope.rcpt_to, ["a@example.com"]);
+        assert_eq!(q.message(&early.id).unwrap(), b"first");
+        assert!(q.due(99).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn update_defers_and_remove_forgets() {
+        let (q, dir) = queue("update");
+        let mut e = entry("a@example.com", 100);
+        q.push(&e, b"msg").unwrap();
+        e.attempts = 1;
+        e.next_attempt = 500;
+        e.last_error = Some("451 try later".into());
+        q.update(&e).unwrap();
+        assert!(q.due(499).unwrap().is_empty());
+        let due = q.due(500).unwrap();
+        assert_eq!(due[0].attempts, 1);
+        assert_eq!(due[0].last_error.as_deref(), Some("451 try later"));
+
+        q.remove(&e.id).unwrap();
+        assert!(q.due(i64::MAX).unwrap().is_empty());
+        assert!(q.message(&e.id).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_our_own_dsns_are_bounces() {
+        assert!(!entry("a@example.com", 0).is_bounce());
+        let dsn = QueueEntry::new(
+            MailEnvelope {
+                mail_from: None,
+                rcpt_to: vec!["agency@example.gov".into()],
+            },
+            None,
+            None,
+        );
+        assert!(dsn.is_bounce());
+    }
+}
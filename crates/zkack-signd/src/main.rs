@@ -0,0 +1,548 @@
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{
+    routing::{get, post},
+    Json, Router,
+};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing_subscriber::{fmt::Subscriber, EnvFilter};
+
+use zkack_signer::ledger::{IssuanceRecord, Ledger};
+use zkack_signer::{
+    dkim, issue_dat_for_digest, load_key, message_id, sign_message, DigestMode, ResignMode,
+    SignOptions, SignerKey,
+};
+use zkack_spec::PartDigest;
+
+/// HTTP signing service: apps send a message (or a digest) and a recipient and
+/// get back the signed message (or the DAT); keys never leave this process.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Listen address
+    #[arg(long, default_value = "127.0.0.1:8788")]
+    listen: String,
+    /// Private key JSON (repeatable); the first is the default, others by ?kid=
+    #[arg(long, required = true)]
+    privkey: Vec<String>,
+    /// Clients file: {"<client name>": {"token_sha256": "<sha256 hex of its bearer token>",
+    /// "kids": [<kids it may sign with; the first is its default>]}}. A bare hash string
+    /// instead of the object allows the default key only.
+    #[arg(long)]
+    clients: PathBuf,
+    /// Issuance ledger (sled) path
+    #[arg(long, default_value = "./data/issuance-ledger")]
+    ledger: PathBuf,
+    /// ACK deadline seconds (default 900s)
+    #[arg(long, default_value_t = 900)]
+    ack_by_secs: u64,
+    /// Default digest mode for /sign (override per request with ?digest=)
+    #[arg(long, value_enum, default_value_t = DigestMode::Auto)]
+    digest: DigestMode,
+    /// Default for a message that already has X-ZK-DAT (override per request with ?resign=)
+    #[arg(long, value_enum, default_value_t = ResignMode::Refuse)]
+    resign: ResignMode,
+    /// Largest message accepted by /sign, in bytes
+    #[arg(long, default_value_t = 25 * 1024 * 1024)]
+    max_size: usize,
+    /// DKIM private key to sign after injection (see zkack-signer --dkim-key)
+    #[arg(long, requires_all = ["dkim_domain", "dkim_selector"])]
+    dkim_key: Option<String>,
+    /// DKIM signing domain (d=)
+    #[arg(long)]
+    dkim_domain: Option<String>,
+    /// DKIM selector (s=)
+    #[arg(long)]
+    dkim_selector: Option<String>,
+    /// DKIM algorithm
+    #[arg(long, value_enum, default_value_t = dkim::DkimAlg::RsaSha256)]
+    dkim_alg: dkim::DkimAlg,
+}
+
+/// A clients file entry.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ClientEntry {
+    Hash(String),
+    Full {
+        token_sha256: String,
+        kids: Vec<String>,
+    },
+}
+
+struct Client {
+    name: String,
+    /// Kids this client may sign with; the first is its default
+    kids: Vec<String>,
+}
+
+struct Service {
+    keys: Vec<SignerKey>,
+    /// sha256 hex of token -> client
+    clients: HashMap<String, Client>,
+    ledger: Ledger,
+    opts: SignOptions,
+}
+
+type AppState = Arc<Service>;
+type ApiError = (StatusCode, String);
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl Service {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<&Client, ApiError> {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or((StatusCode::UNAUTHORIZED, "missing bearer token".to_string()))?;
+        self.clients
+            .get(&sha256_hex(token.trim().as_bytes()))
+            .ok_or((StatusCode::UNAUTHORIZED, "unknown token".to_string()))
+    }
+
+    /// Index into `keys` of the key `client` asked for (its default if none), if it may use it.
+    fn key(&self, client: &Client, kid: Option<&str>) -> Result<usize, ApiError> {
+        let kid = kid.unwrap_or(&client.kids[0]);
+        if !client.kids.iter().any(|k| k == kid) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("client {} may not sign with kid {kid}", client.name),
+            ));
+        }
+        self.keys
+            .iter()
+            .position(|key| key.kid == kid)
+            .ok_or((StatusCode::BAD_REQUEST, format!("unknown kid {kid}")))
+    }
+
+    /// Write the ledger record; nothing is returned to the client unless this succeeds.
+    fn record(&self, rec: IssuanceRecord) -> Result<(), ApiError> {
+        self.ledger.record(&rec).map_err(|e| {
+            tracing::error!("ledger write failed: {e:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "ledger write failed".to_string(),
+            )
+        })?;
+        tracing::info!(client=%rec.issuer, kid=%rec.kid, nonce=%rec.nonce_b64, "issued");
+        Ok(())
+    }
+}
+
+/// Run signing (Ed25519, MIME parsing, the ledger flush) on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        tracing::error!("signing task failed: {e}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "signing task failed".to_string(),
+        )
+    })?
+}
+
+fn unprocessable<T: std::fmt::Display>(e: T) -> ApiError {
+    (StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}"))
+}
+
+#[derive(Debug, Deserialize)]
+struct SignQuery {
+    to: String,
+    kid: Option<String>,
+    digest: Option<String>,
+    resign: Option<String>,
+}
+
+/// Raw message in, signed message out (message/rfc822 both ways).
+async fn handle_sign(
+    State(svc): State<AppState>,
+    Query(q): Query<SignQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    let client = svc.authenticate(&headers)?;
+    let (k, issuer) = (svc.key(client, q.kid.as_deref())?, client.name.clone());
+    let mut opts = svc.opts.clone();
+    if let Some(d) = &q.digest {
+        opts.digest = DigestMode::from_str(d, true)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("digest: {e}")))?;
+    }
+    if let Some(r) = &q.resign {
+        opts.resign = ResignMode::from_str(r, true)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("resign: {e}")))?;
+    }
+    let (kid, signed) = blocking(move || {
+        let key = &svc.keys[k];
+        let signed = sign_message(key, &body, &q.to, &opts).map_err(unprocessable)?;
+        let mut rec = IssuanceRecord::new(&key.kid, &signed.dat, &issuer);
+        rec.message_id = message_id(&signed.message);
+        svc.record(rec)?;
+        Ok((key.kid.clone(), signed))
+    })
+    .await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "message/rfc822".to_string()),
+            (header::HeaderName::from_static("x-zk-kid"), kid),
+            (
+                header::HeaderName::from_static("x-zk-nonce"),
+                signed.dat.nonce_b64.clone(),
+            ),
+        ],
+        signed.message,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueReq {
+    to: String,
+    // sender address; its domain must be allowed for the key
+    from: String,
+    msg_digest_b64: String,
+    digest_alg: String,
+    // required for digest_alg "manifest"
+    parts: Option<Vec<PartDigest>>,
+    kid: Option<String>,
+}
+
+/// Digest + recipient in, DAT out; the caller injects X-ZK-DAT itself.
+async fn handle_issue(
+    State(svc): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<IssueReq>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let client = svc.authenticate(&headers)?;
+    let (k, issuer) = (svc.key(client, req.kid.as_deref())?, client.name.clone());
+    blocking(move || {
+        let key = &svc.keys[k];
+        let issued = issue_dat_for_digest(
+            key,
+            &req.to,
+            &req.from,
+            &req.digest_alg,
+            &req.msg_digest_b64,
+            req.parts,
+            &svc.opts,
+        )
+        .map_err(unprocessable)?;
+        svc.record(IssuanceRecord::new(&key.kid, &issued.dat, &issuer))?;
+        Ok(Json(serde_json::json!({
+            "kid": key.kid,
+            "jws": issued.jws,
+            "dat": issued.dat,
+        })))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct IssuanceQuery {
+    nonce: Option<String>,
+    recipient: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn list_issuances(
+    State(svc): State<AppState>,
+    Query(q): Query<IssuanceQuery>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let client = svc.authenticate(&headers)?;
+    // a client sees only what it issued
+    let mut items: Vec<IssuanceRecord> = svc
+        .ledger
+        .iter()
+        .filter(|r| r.issuer == client.name)
+        .filter(|r| q.nonce.as_ref().map_or(true, |n| &r.nonce_b64 == n))
+        .filter(|r| q.recipient.as_ref().map_or(true, |t| r.is_for(t)))
+        .collect();
+    // RFC 3339 UTC timestamps sort lexically; newest first
+    items.sort_by(|a, b| b.issued_at.cmp(&a.issued_at));
+    if let Some(lim) = q.limit {
+        items.truncate(lim);
+    }
+    Ok(Json(serde_json::json!({ "issuances": items })))
+}
+
+async fn healthz(State(svc): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status": "ok",
+        "kids": svc.keys.iter().map(|k| k.kid.as_str()).collect::<Vec<_>>(),
+        "issuances": svc.ledger.len(),
+        "time": time::OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap(),
+    }))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _ = Subscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+    let args = Args::parse();
+
+    let keys = args
+        .privkey
+        .iter()
+        .map(|p| load_key(p, None))
+        .collect::<Result<Vec<_>>>()?;
+    let by_name: HashMap<String, ClientEntry> =
+        serde_json::from_str(&std::fs::read_to_string(&args.clients)?)?;
+    if by_name.is_empty() {
+        anyhow::bail!("no clients in {}", args.clients.display());
+    }
+    let mut clients = HashMap::new();
+    for (name, entry) in by_name {
+        let (hash, kids) = match entry {
+            ClientEntry::Hash(hash) => (hash, vec![keys[0].kid.clone()]),
+            ClientEntry::Full { token_sha256, kids } => (token_sha256, kids),
+        };
+        if kids.is_empty() {
+            anyhow::bail!("client {name}: no kids");
+        }
+        if let Some(k) = kids.iter().find(|k| !keys.iter().any(|key| &key.kid == *k)) {
+            anyhow::bail!("client {name}: kid {k} is not loaded");
+        }
+        let client = Client { name, kids };
+        if let Some(other) = clients.insert(hash.to_ascii_lowercase(), client) {
+            anyhow::bail!("client {}: token shared with another client", other.name);
+        }
+    }
+    let dkim = match &args.dkim_key {
+        Some(path) => Some(Arc::new(dkim::DkimSigner::load(
+            path,
+            args.dkim_alg,
+            args.dkim_domain.as_deref().unwrap_or_default(),
+            args.dkim_selector.as_deref().unwrap_or_default(),
+        )?)),
+        None => None,
+    };
+    let ledger = Ledger::open(&args.ledger)?;
+    tracing::info!(ledger=%args.ledger.display(), "opened issuance ledger");
+
+    let svc = Arc::new(Service {
+        keys,
+        clients,
+        ledger,
+        opts: SignOptions {
+            digest: args.digest,
+            ack_by_secs: args.ack_by_secs,
+            dkim,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: args.resign,
+        },
+    });
+
+    let app = Router::new()
+        .route("/zk-ack/v1/sign", post(handle_sign))
+        .route("/zk-ack/v1/issue", post(handle_issue))
+        .route("/zk-ack/v1/issuances", get(list_issuances))
+        .route("/healthz", get(healthz))
+        .layer(DefaultBodyLimit::max(args.max_size))
+        .with_state(svc);
+
+    let listener = tokio::net::TcpListener::bind(&args.listen).await?;
+    tracing::info!("zkack-signd listening on http://{}", args.listen);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use zkack_spec::PrivKeyJson;
+
+    const EML: &[u8] =
+        b"From: agency@example.gov\r\nTo: you@example.com\r\nSubject: s\r\n\r\nHello\r\n";
+
+    fn key(dir: &std::path::Path, kid: &str, seed: u8) -> SignerKey {
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let pkj = PrivKeyJson {
+            kid: kid.into(),
+            sk_b64: b64.encode(sk.to_bytes()),
+            vk_b64: b64.encode(sk.verifying_key().to_bytes()),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        };
+        let path = dir.join(format!("{kid}.json"));
+        std::fs::write(&path, serde_json::to_vec(&pkj).unwrap()).unwrap();
+        load_key(path.to_str().unwrap(), None).unwrap()
+    }
+
+    /// Two keys; "app" may use both (k1 by default), "other" only k2.
+    fn service(name: &str) -> AppState {
+        let dir = std::env::temp_dir().join(format!("zkack-signd-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = vec![key(&dir, "k1", 1), key(&dir, "k2", 2)];
+        let client = |name: &str, kids: &[&str]| Client {
+            name: name.into(),
+            kids: kids.iter().map(|k| k.to_string()).collect(),
+        };
+        let clients = HashMap::from([
+            (sha256_hex(b"app-token"), client("app", &["k1", "k2"])),
+            (sha256_hex(b"other-token"), client("other", &["k2"])),
+        ]);
+        Arc::new(Service {
+            keys,
+            clients,
+            ledger: Ledger::open(&dir.join("ledger")).unwrap(),
+            opts: SignOptions {
+                digest: DigestMode::Blake3,
+                ack_by_secs: 900,
+                dkim: None,
+                from: None,
+                fallbacks: zkack_spec::default_fallbacks(),
+                footer_url: None,
+                resign: ResignMode::Refuse,
+            },
+        })
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        h
+    }
+
+    fn sign_query(kid: Option<&str>) -> Query<SignQuery> {
+        Query(SignQuery {
+            to: "you@example.com".into(),
+            kid: kid.map(String::from),
+            digest: None,
+            resign: None,
+        })
+    }
+
+    async fn sign(svc: &AppState, token: &str, kid: Option<&str>) -> Result<String, StatusCode> {
+        match handle_sign(
+            State(svc.clone()),
+            sign_query(kid),
+            bearer(token),
+            Bytes::from_static(EML),
+        )
+        .await
+        {
+            Ok(r) => {
+                let r = r.into_response();
+                Ok(r.headers()["x-zk-kid"].to_str().unwrap().to_string())
+            }
+            Err((status, _)) => Err(status),
+        }
+    }
+
+    async fn issuances(svc: &AppState, token: &str, q: IssuanceQuery) -> Vec<IssuanceRecord> {
+        let Json(v) = list_issuances(State(svc.clone()), Query(q), bearer(token))
+            .await
+            .unwrap();
+        serde_json::from_value(v["issuances"].clone()).unwrap()
+    }
+
+    fn all() -> IssuanceQuery {
+        IssuanceQuery {
+            nonce: None,
+            recipient: None,
+            limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_token_is_required() {
+        let svc = service("auth");
+        let err = |r: Result<_, ApiError>| r.err().unwrap().0;
+        assert_eq!(
+            err(svc.authenticate(&HeaderMap::new()).map(|_| ())),
+            StatusCode::UNAUTHORIZED
+        );
+        let mut basic = HeaderMap::new();
+        basic.insert(header::AUTHORIZATION, "Basic YXBwOng=".parse().unwrap());
+        assert_eq!(
+            err(svc.authenticate(&basic).map(|_| ())),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            sign(&svc, "wrong", None).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(svc.ledger.len(), 0);
+        assert_eq!(svc.authenticate(&bearer("app-token")).unwrap().name, "app");
+    }
+
+    #[tokio::test]
+    async fn clients_sign_only_with_their_kids() {
+        let svc = service("kids");
+        assert_eq!(sign(&svc, "app-token", None).await.unwrap(), "k1");
+        assert_eq!(sign(&svc, "app-token", Some("k2")).await.unwrap(), "k2");
+        assert_eq!(sign(&svc, "other-token", None).await.unwrap(), "k2");
+        assert_eq!(
+            sign(&svc, "other-token", Some("k1")).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        let req = |kid: &str| IssueReq {
+            to: "you@example.com".into(),
+            from: "agency@example.gov".into(),
+            msg_digest_b64: zkack_spec::blake3_b64(b"m"),
+            digest_alg: "blake3".into(),
+            parts: None,
+            kid: Some(kid.into()),
+        };
+        let denied = handle_issue(State(svc.clone()), bearer("other-token"), Json(req("k1"))).await;
+        assert_eq!(denied.err().unwrap().0, StatusCode::FORBIDDEN);
+        let Json(ok) = handle_issue(State(svc.clone()), bearer("other-token"), Json(req("k2")))
+            .await
+            .unwrap();
+        assert_eq!(ok["kid"], "k2");
+        // every issuance is on the ledger, none for the refused requests
+        assert_eq!(svc.ledger.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn clients_see_only_their_issuances() {
+        let svc = service("list");
+        sign(&svc, "app-token", None).await.unwrap();
+        sign(&svc, "app-token", None).await.unwrap();
+        sign(&svc, "other-token", None).await.unwrap();
+
+        let mine = issuances(&svc, "app-token", all()).await;
+        assert_eq!(mine.len(), 2);
+        assert!(mine.iter().all(|r| r.issuer == "app"));
+        let theirs = issuances(&svc, "other-token", all()).await;
+        assert_eq!(theirs.len(), 1);
+
+        // another client's nonce is not found even when asked for directly
+        let q = IssuanceQuery {
+            nonce: Some(theirs[0].nonce_b64.clone()),
+            ..all()
+        };
+        assert!(issuances(&svc, "app-token", q).await.is_empty());
+        let q = IssuanceQuery {
+            recipient: Some("<You@Example.com>".into()),
+            limit: Some(1),
+            ..all()
+        };
+        assert_eq!(issuances(&svc, "app-token", q).await.len(), 1);
+        let denied = list_issuances(State(svc.clone()), Query(all()), HeaderMap::new()).await;
+        assert_eq!(denied.err().unwrap().0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,614 @@
+//! Batch signing: directories of .eml files, mbox files and Maildirs.
+
+use anyhow::{anyhow, Result};
+use mailparse::parse_headers;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::{fs, thread};
+
+use zkack_signer::{header_addrs, sign_message, SignOptions, Signed, SignerKey};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputKind {
+    /// Directory tree of *.eml files
+    Dir,
+    /// Single mbox file
+    Mbox,
+    /// Maildir (cur/ and new/)
+    Maildir,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputKind {
+    /// Mirror the input layout under --out
+    Mirror,
+    /// Write one mbox file at --out
+    Mbox,
+}
+
+pub struct BatchArgs<'a> {
+    pub input: &'a Path,
+    pub kind: InputKind,
+    pub out: &'a Path,
+    pub out_kind: OutputKind,
+    pub report: PathBuf,
+    pub jobs: usize,
+    /// Fixed recipient; when None it is taken from each message's To header.
+    pub to: Option<&'a str>,
+    pub sinks: crate::Sinks<'a>,
+}
+
+/// One message to sign. `rel` is its path relative to the output root in mirror mode.
+struct Item {
+    source: String,
+    rel: PathBuf,
+    from_line: Option<Vec<u8>>,
+    data: Source,
+}
+
+/// ("From " separator line, signed message) awaiting in-order mbox output.
+type MboxEntry = (Option<Vec<u8>>, Vec<u8>);
+
+enum Source {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Serialize)]
+struct ItemReport {
+    source: String,
+    output: Option<String>,
+    ok: bool,
+    error: Option<String>,
+    recipient: Option<String>,
+    digest_alg: Option<String>,
+    nonce_b64: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    total: usize,
+    ok: usize,
+    failed: usize,
+    items: Vec<ItemReport>,
+}
+
+pub fn run(key: &SignerKey, opts: &SignOptions, args: &BatchArgs) -> Result<()> {
+    let items: Box<dyn Iterator<Item = Result<Item>> + Send> = match args.kind {
+        InputKind::Dir => Box::new(collect_dir(args.input)?.into_iter().map(Ok)),
+        InputKind::Maildir => Box::new(collect_maildir(args.input)?.into_iter().map(Ok)),
+        InputKind::Mbox => Box::new(MboxReader::new(BufReader::new(
+            File::open(args.input).map_err(|e| anyhow!("{}: {e}", args.input.display()))?,
+        ))),
+    };
+
+    if args.kind == InputKind::Maildir && args.out_kind == OutputKind::Mirror {
+        for sub in ["tmp", "new", "cur"] {
+            fs::create_dir_all(args.out.join(sub))?;
+        }
+    }
+    let mut mbox_out = match args.out_kind {
+        OutputKind::Mbox => {
+            if let Some(parent) = args.out.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Some(BufWriter::new(File::create(args.out)?))
+        }
+        OutputKind::Mirror => None,
+    };
+
+    // Messages are read, signed and written one at a time; at most `window` are in
+    // flight, which also bounds the reorder buffer that keeps mbox output in input order.
+    let jobs = args.jobs.max(1);
+    let window = jobs * 4;
+    let written = (Mutex::new(0usize), Condvar::new());
+    let (work_tx, work_rx) = mpsc::sync_channel::<(usize, Item)>(jobs);
+    let work_rx = Mutex::new(work_rx);
+    let (done_tx, done_rx) = mpsc::channel::<(usize, ItemReport, Option<MboxEntry>)>();
+    let mut reports = Vec::new();
+    let mut write_err = None;
+
+    let read_result = thread::scope(|s| {
+        for _ in 0..jobs {
+            let done_tx = done_tx.clone();
+            let work_rx = &work_rx;
+            s.spawn(move || loop {
+                let next = work_rx.lock().unwrap().recv();
+                let Ok((i, item)) = next else { break };
+                let (report, entry) = process(key, opts, args, i, item);
+                if done_tx.send((i, report, entry)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_tx);
+
+        let written_ref = &written;
+        let producer = s.spawn(move || -> Result<()> {
+            for (i, item) in items.enumerate() {
+                let item = item?;
+                let (count, cv) = written_ref;
+                let mut n = count.lock().unwrap();
+                while i >= *n + window {
+                    n = cv.wait(n).unwrap();
+                }
+                drop(n);
+                if work_tx.send((i, item)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let mut pending = BTreeMap::new();
+        let mut next_out = 0;
+        for (i, report, entry) in done_rx {
+            pending.insert(i, (report, entry));
+            while let Some((report, entry)) = pending.remove(&next_out) {
+                if let (Some(out), Some((from_line, message)), None) =
+                    (mbox_out.as_mut(), entry, &write_err)
+                {
+                    if let Err(e) = write_mbox_entry(out, from_line.as_deref(), &message) {
+                        write_err = Some(e);
+                    }
+                }
+                reports.push(report);
+                next_out += 1;
+                *written.0.lock().unwrap() = next_out;
+                written.1.notify_all();
+            }
+        }
+        producer.join().unwrap()
+    });
+    if let Some(e) = write_err {
+        return Err(anyhow!("write {}: {e}", args.out.display()));
+    }
+    if let Some(mut out) = mbox_out {
+        out.flush()?;
+    }
+    read_result?;
+
+    let ok = reports.iter().filter(|r| r.ok).count();
+    let report = Report {
+        total: reports.len(),
+        ok,
+        failed: reports.len() - ok,
+        items: reports,
+    };
+    fs::write(&args.report, serde_json::to_vec_pretty(&report)?)?;
+    eprintln!(
+        "-- signed {}/{} message(s), {} failed; report: {}",
+        report.ok,
+        report.total,
+        report.failed,
+        args.report.display()
+    );
+    if report.failed > 0 {
+        anyhow::bail!("{} message(s) failed to sign", report.failed);
+    }
+    Ok(())
+}
+
+/// Sign one item and write it out (mirror mode) or hand it back for the mbox writer.
+fn process(
+    key: &SignerKey,
+    opts: &SignOptions,
+    args: &BatchArgs,
+    i: usize,
+    item: Item,
+) -> (ItemReport, Option<MboxEntry>) {
+    let source = item.source.clone();
+    let mut entry = None;
+    let report = sign_item(key, opts, args, item).and_then(|(item, to, signed)| {
+        let output = match args.out_kind {
+            OutputKind::Mirror => {
+                let path = args.out.join(&item.rel);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, &signed.message)
+                    .map_err(|e| anyhow!("write {}: {e}", path.display()))?;
+                path.display().to_string()
+            }
+            OutputKind::Mbox => format!("{}#{}", args.out.display(), i + 1),
+        };
+        crate::record(args.sinks, key, &signed, &output)?;
+        let dat = signed.dat;
+        if args.out_kind == OutputKind::Mbox {
+            entry = Some((item.from_line, signed.message));
+        }
+        Ok(ItemReport {
+            source: source.clone(),
+            output: Some(output),
+            ok: true,
+            error: None,
+            recipient: Some(to),
+            digest_alg: Some(dat.digest_alg),
+            nonce_b64: Some(dat.nonce_b64),
+        })
+    });
+    match report {
+        Ok(report) => (report, entry),
+        Err(e) => (failed(source, e), None),
+    }
+}
+
+fn failed(source: String, e: anyhow::Error) -> ItemReport {
+    ItemReport {
+        source,
+        output: None,
+        ok: false,
+        error: Some(format!("{e:#}")),
+        recipient: None,
+        digest_alg: None,
+        nonce_b64: None,
+    }
+}
+
+fn sign_item(
+    key: &SignerKey,
+    opts: &SignOptions,
+    args: &BatchArgs,
+    mut item: Item,
+) -> Result<(Item, String, Signed)> {
+    let eml = match &mut item.data {
+        Source::File(p) => fs::read(p)?,
+        Source::Bytes(b) => std::mem::take(b),
+    };
+    let to = match args.to {
+        Some(t) => t.to_string(),
+        None => single_recipient(&eml)?,
+    };
+    let signed = sign_message(key, &eml, &to, opts)?;
+    Ok((item, to, signed))
+}
+
+/// The one address in the To header (batch mode without --to).
+fn single_recipient(eml: &[u8]) -> Result<String> {
+    let (headers, _) = parse_headers(eml)?;
+    let mut addrs = Vec::new();
+    for h in headers
+        .iter()
+        .filter(|h| h.get_key_ref().eq_ignore_ascii_case("To"))
+    {
+        addrs.extend(header_addrs(h)?);
+    }
+    match addrs.as_slice() {
+        [one] => Ok(one.clone()),
+        [] => Err(anyhow!("no To address (pass --to)")),
+        _ => Err(anyhow!(
+            "{} To addresses; expected exactly one",
+            addrs.len()
+        )),
+    }
+}
+
+fn collect_dir(root: &Path) -> Result<Vec<Item>> {
+    let mut out = Vec::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.'))
+            {
+                continue;
+            }
+            if path.is_dir() {
+                stack.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("eml"))
+            {
+                out.push(file_item(root, path));
+            }
+        }
+    }
+    out.sort_by(|a, b| a.rel.cmp(&b.rel));
+    Ok(out)
+}
+
+fn collect_maildir(root: &Path) -> Result<Vec<Item>> {
+    let mut out = Vec::new();
+    for sub in ["new", "cur"] {
+        let dir = root.join(sub);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                out.push(file_item(root, path));
+            }
+        }
+    }
+    if out.is_empty() && !root.join("cur").is_dir() && !root.join("new").is_dir() {
+        anyhow::bail!("{} is not a Maildir (no cur/ or new/)", root.display());
+    }
+    out.sort_by(|a, b| a.rel.cmp(&b.rel));
+    Ok(out)
+}
+
+fn file_item(root: &Path, path: PathBuf) -> Item {
+    Item {
+        source: path.display().to_string(),
+        rel: path.strip_prefix(root).unwrap_or(&path).to_path_buf(),
+        from_line: None,
+        data: Source::File(path),
+    }
+}
+
+/// Reads an mbox (mboxrd) file one message at a time; `>From ` quoting is undone so
+/// the signed bytes are the message as it will be delivered.
+struct MboxReader<R> {
+    input: R,
+    /// "From " line of the message being read
+    from_line: Option<Vec<u8>>,
+    count: usize,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    fn new(input: R) -> MboxReader<R> {
+        MboxReader {
+            input,
+            from_line: None,
+            count: 0,
+        }
+    }
+
+    fn next_message(&mut self) -> Result<Option<Item>> {
+        let mut msg = Vec::new();
+        let mut line = Vec::new();
+        let mut prev_blank = self.from_line.is_none();
+        loop {
+            line.clear();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                // writers end the last message with the same separator blank line
+                strip_separator(&mut msg);
+                return Ok(self.from_line.take().map(|from| self.item(from, msg)));
+            }
+            if prev_blank && line.starts_with(b"From ") {
+                if let Some(from) = self.from_line.replace(line.clone()) {
+                    strip_separator(&mut msg);
+                    return Ok(Some(self.item(from, msg)));
+                }
+                prev_blank = false;
+                continue;
+            }
+            prev_blank = line == b"\n" || line == b"\r\n";
+            if self.from_line.is_some() {
+                let gt = line.iter().take_while(|&&b| b == b'>').count();
+                let quoted = gt > 0 && line[gt..].starts_with(b"From ");
+                msg.extend_from_slice(if quoted { &line[1..] } else { &line });
+            }
+        }
+    }
+
+    fn item(&mut self, from_line: Vec<u8>, msg: Vec<u8>) -> Item {
+        self.count += 1;
+        Item {
+            source: format!("mbox#{}", self.count),
+            rel: PathBuf::from(format!("{:06}.eml", self.count)),
+            from_line: Some(from_line),
+            data: Source::Bytes(msg),
+        }
+    }
+}
+
+/// The blank line that ends each mbox entry belongs to the mbox, not the message.
+fn strip_separator(msg: &mut Vec<u8>) {
+    if msg.ends_with(b"\r\n\r\n") {
+        msg.truncate(msg.len() - 2);
+    } else if msg.ends_with(b"\n\n") {
+        msg.truncate(msg.len() - 1);
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Result<Item>> {
+        self.next_message().transpose()
+    }
+}
+
+fn write_mbox_entry(
+    out: &mut impl Write,
+    from_line: Option<&[u8]>,
+    message: &[u8],
+) -> std::io::Result<()> {
+    out.write_all(from_line.unwrap_or(b"From MAILER-DAEMON Thu Jan  1 00:00:00 1970\n"))?;
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        let gt = line.iter().take_while(|&&b| b == b'>').count();
+        if line[gt..].starts_with(b"From ") {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+    }
+    if !message.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use mailparse::MailHeaderMap;
+    use zkack_signer::{load_key, DigestMode, ResignMode};
+    use zkack_spec::PrivKeyJson;
+
+    fn tmp(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zkack-batch-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key(dir: &Path) -> SignerKey {
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let pkj = PrivKeyJson {
+            kid: "k1".into(),
+            sk_b64: b64.encode(sk.to_bytes()),
+            vk_b64: b64.encode(sk.verifying_key().to_bytes()),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        };
+        let path = dir.join("priv.json");
+        fs::write(&path, serde_json::to_vec(&pkj).unwrap()).unwrap();
+        load_key(path.to_str().unwrap(), None).unwrap()
+    }
+
+    fn opts() -> SignOptions {
+        SignOptions {
+            digest: DigestMode::Blake3,
+            ack_by_secs: 900,
+            dkim: None,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: ResignMode::Refuse,
+        }
+    }
+
+    fn eml(n: usize) -> Vec<u8> {
+        format!(
+            "From: a@example.gov\r\nTo: you{n}@example.com\r\nSubject: m{n}\r\n\r\nBody {n}\r\n"
+        )
+        .into_bytes()
+    }
+
+    fn read_mbox(bytes: &[u8]) -> Vec<Vec<u8>> {
+        MboxReader::new(bytes)
+            .map(|item| match item.unwrap().data {
+                Source::Bytes(b) => b,
+                Source::File(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn write_mbox(messages: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for m in messages {
+            write_mbox_entry(&mut out, None, m).unwrap();
+        }
+        out
+    }
+
+    fn args<'a>(
+        input: &'a Path,
+        kind: InputKind,
+        out: &'a Path,
+        out_kind: OutputKind,
+    ) -> BatchArgs<'a> {
+        BatchArgs {
+            input,
+            kind,
+            out,
+            out_kind,
+            report: out.with_extension("report.json"),
+            jobs: 4,
+            to: None,
+            sinks: crate::Sinks {
+                ledger: None,
+                codes: None,
+            },
+        }
+    }
+
+    #[test]
+    fn mbox_round_trip_is_byte_exact() {
+        let messages = vec![
+            eml(1),
+            b"Subject: lf\n\nline\n\n".to_vec(),
+            eml(3),
+            b"Subject: last\n\nbye\n".to_vec(),
+        ];
+        assert_eq!(read_mbox(&write_mbox(&messages)), messages);
+        // a single message keeps its bytes too
+        assert_eq!(read_mbox(&write_mbox(&messages[3..])), messages[3..]);
+    }
+
+    #[test]
+    fn from_lines_are_quoted_and_unquoted() {
+        let msg = b"Subject: q\n\nFrom here\n>From there\nFromage\n".to_vec();
+        let out = write_mbox(std::slice::from_ref(&msg));
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.contains("\n>From here\n>>From there\nFromage\n"));
+        assert_eq!(read_mbox(&out), vec![msg]);
+        // an unquoted "From " after a blank line starts a new message
+        let split = read_mbox(b"From x\nSubject: a\n\nFrom y\nSubject: b\n");
+        assert_eq!(
+            split,
+            vec![b"Subject: a\n".to_vec(), b"Subject: b\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn mbox_output_keeps_input_order() {
+        let dir = tmp("order");
+        let key = key(&dir);
+        let messages: Vec<Vec<u8>> = (0..40).map(eml).collect();
+        let input = dir.join("in.mbox");
+        fs::write(&input, write_mbox(&messages)).unwrap();
+        let out = dir.join("out.mbox");
+        run(
+            &key,
+            &opts(),
+            &args(&input, InputKind::Mbox, &out, OutputKind::Mbox),
+        )
+        .unwrap();
+
+        let signed = read_mbox(&fs::read(&out).unwrap());
+        assert_eq!(signed.len(), 40);
+        for (n, m) in signed.iter().enumerate() {
+            let (headers, _) = parse_headers(m).unwrap();
+            assert_eq!(headers.get_first_value("Subject"), Some(format!("m{n}")));
+            assert!(headers.get_first_value("X-ZK-DAT").is_some());
+            assert!(m.ends_with(format!("Body {n}\r\n").as_bytes()));
+        }
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(out.with_extension("report.json")).unwrap()).unwrap();
+        assert_eq!(report["ok"], 40);
+        assert_eq!(report["items"][39]["source"], "mbox#40");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn maildir_is_mirrored() {
+        let dir = tmp("maildir");
+        let key = key(&dir);
+        let input = dir.join("in");
+        for sub in ["new", "cur", "tmp"] {
+            fs::create_dir_all(input.join(sub)).unwrap();
+        }
+        fs::write(input.join("new/1.host"), eml(1)).unwrap();
+        fs::write(input.join("cur/2.host:2,S"), eml(2)).unwrap();
+        fs::write(input.join("tmp/3.host"), eml(3)).unwrap();
+        let out = dir.join("out");
+        run(
+            &key,
+            &opts(),
+            &args(&input, InputKind::Maildir, &out, OutputKind::Mirror),
+        )
+        .unwrap();
+
+        for (rel, n) in [("new/1.host", 1), ("cur/2.host:2,S", 2)] {
+            let m = fs::read(out.join(rel)).unwrap();
+            let (headers, _) = parse_headers(&m).unwrap();
+            assert_eq!(headers.get_first_value("Subject"), Some(format!("m{n}")));
+            assert!(headers.get_first_value("X-ZK-DAT").is_some());
+        }
+        assert!(out.join("tmp").is_dir());
+        assert!(!out.join("tmp/3.host").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
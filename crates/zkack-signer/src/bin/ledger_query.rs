@@ -0,0 +1,199 @@
+use anyhow::Result;
+use clap::Parser;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use zkack_signer::ledger::{read_records, IssuanceRecord};
+
+/// Query the issuance ledger ("what did we send"); prints matching records as
+/// JSON lines, oldest first. Reads the signer's JSON-lines ledger or zkack-signd's
+/// sled ledger directory.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Ledger path: JSON-lines file or sled directory
+    #[arg(long, default_value = "./data/issuance-ledger.jsonl")]
+    ledger: PathBuf,
+    #[arg(long)]
+    nonce: Option<String>,
+    #[arg(long)]
+    kid: Option<String>,
+    /// Recipient address, matched against each record's salted address hash
+    #[arg(long)]
+    recipient: Option<String>,
+    #[arg(long)]
+    message_id: Option<String>,
+    /// Issued at or after (RFC 3339)
+    #[arg(long)]
+    since: Option<String>,
+    /// Issued before (RFC 3339)
+    #[arg(long)]
+    until: Option<String>,
+    /// Verifier receipts (GET /zk-ack/v1/receipts output); adds "acked" to each record
+    #[arg(long)]
+    receipts: Option<PathBuf>,
+    /// Only records without a receipt (needs --receipts)
+    #[arg(long, requires = "receipts")]
+    unacked: bool,
+    /// Keep only the newest N matches
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Print only the number of matches
+    #[arg(long)]
+    count: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let acked: Option<HashSet<String>> = match &args.receipts {
+        Some(p) => Some(receipt_nonces(&std::fs::read_to_string(p)?)?),
+        None => None,
+    };
+    let records = select(&args, read_records(&args.ledger)?, acked.as_ref())?;
+
+    if args.count {
+        println!("{}", records.len());
+        return Ok(());
+    }
+    for r in records {
+        let mut line = serde_json::to_value(&r)?;
+        if let Some(a) = &acked {
+            line["acked"] = a.contains(&r.nonce_b64).into();
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// The records matching every filter in `args`, oldest first.
+fn select(
+    args: &Args,
+    records: Vec<IssuanceRecord>,
+    acked: Option<&HashSet<String>>,
+) -> Result<Vec<IssuanceRecord>> {
+    let since = args
+        .since
+        .as_deref()
+        .map(|s| OffsetDateTime::parse(s, &Rfc3339))
+        .transpose()?;
+    let until = args
+        .until
+        .as_deref()
+        .map(|s| OffsetDateTime::parse(s, &Rfc3339))
+        .transpose()?;
+
+    let mut records: Vec<(OffsetDateTime, IssuanceRecord)> = records
+        .into_iter()
+        .filter_map(|r| Some((OffsetDateTime::parse(&r.issued_at, &Rfc3339).ok()?, r)))
+        .filter(|(ts, r)| {
+            args.nonce.as_ref().map_or(true, |n| &r.nonce_b64 == n)
+                && args.kid.as_ref().map_or(true, |k| &r.kid == k)
+                && args.recipient.as_ref().map_or(true, |t| r.is_for(t))
+                && args
+                    .message_id
+                    .as_ref()
+                    .map_or(true, |m| r.message_id.as_ref() == Some(m))
+                && since.map_or(true, |s| *ts >= s)
+                && until.map_or(true, |u| *ts < u)
+                && !(args.unacked && acked.is_some_and(|a| a.contains(&r.nonce_b64)))
+        })
+        .collect();
+    records.sort_by_key(|(ts, _)| *ts);
+    if let Some(lim) = args.limit {
+        records.drain(..records.len().saturating_sub(lim));
+    }
+    Ok(records.into_iter().map(|(_, r)| r).collect())
+}
+
+/// Nonces of the DATs in a verifier receipts listing ({"receipts": [{dat: {nonce_b64}}]}).
+fn receipt_nonces(json: &str) -> Result<HashSet<String>> {
+    let v: serde_json::Value = serde_json::from_str(json)?;
+    Ok(v["receipts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|r| r["dat"]["nonce_b64"].as_str().map(String::from))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(nonce: &str, kid: &str, issued_at: &str, to: &str) -> IssuanceRecord {
+        let mut dat: zkack_spec::DatPayload = serde_json::from_value(serde_json::json!({
+            "v": 1, "salt_b64": "BAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQ",
+            "addr_hash_b64": "", "msg_digest_b64": "d", "digest_alg": "blake3",
+            "exp": "2099-01-01T00:00:00Z", "nonce_b64": nonce,
+            "policy": {"ack_by_secs": 900, "fallbacks": ["portal"]}
+        }))
+        .unwrap();
+        dat.addr_hash_b64 = zkack_spec::addr_hash_b64(&[4u8; 32], to);
+        let mut r = IssuanceRecord::new(kid, &dat, "local");
+        r.issued_at = issued_at.into();
+        r
+    }
+
+    fn records() -> Vec<IssuanceRecord> {
+        let mut m = rec("n3", "k1", "2026-03-01T00:00:00Z", "c@example.com");
+        m.message_id = Some("<m3@example.gov>".into());
+        vec![
+            m,
+            rec("n1", "k1", "2026-01-01T00:00:00Z", "a@example.com"),
+            rec("n2", "k2", "2026-02-01T00:00:00Z", "b@example.com"),
+            rec("bad", "k1", "not a date", "a@example.com"),
+        ]
+    }
+
+    fn query(flags: &[&str], acked: Option<&HashSet<String>>) -> Vec<String> {
+        let args = Args::try_parse_from([&["ledger_query"], flags].concat()).unwrap();
+        select(&args, records(), acked)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.nonce_b64)
+            .collect()
+    }
+
+    #[test]
+    fn filters_combine_and_sort_oldest_first() {
+        assert_eq!(query(&[], None), ["n1", "n2", "n3"]);
+        assert_eq!(query(&["--kid", "k1"], None), ["n1", "n3"]);
+        assert_eq!(query(&["--nonce", "n2"], None), ["n2"]);
+        assert_eq!(query(&["--recipient", "<B@Example.com>"], None), ["n2"]);
+        assert_eq!(query(&["--message-id", "<m3@example.gov>"], None), ["n3"]);
+        assert_eq!(
+            query(
+                &[
+                    "--since",
+                    "2026-02-01T00:00:00Z",
+                    "--until",
+                    "2026-03-01T00:00:00Z"
+                ],
+                None
+            ),
+            ["n2"]
+        );
+        assert_eq!(query(&["--limit", "2"], None), ["n2", "n3"]);
+        assert_eq!(query(&["--kid", "k1", "--limit", "1"], None), ["n3"]);
+    }
+
+    #[test]
+    fn unacked_drops_records_with_a_receipt() {
+        let acked = receipt_nonces(
+            r#"{"receipts":[{"dat":{"nonce_b64":"n1"}},{"dat":{"nonce_b64":"n3"}},{}]}"#,
+        )
+        .unwrap();
+        assert_eq!(acked.len(), 2);
+        let flags = ["--receipts", "r.json", "--unacked"];
+        assert_eq!(query(&flags, Some(&acked)), ["n2"]);
+        assert_eq!(query(&flags[..2], Some(&acked)), ["n1", "n2", "n3"]);
+        assert!(Args::try_parse_from(["ledger_query", "--unacked"]).is_err());
+    }
+
+    #[test]
+    fn bad_time_bounds_are_errors() {
+        let args = Args::try_parse_from(["ledger_query", "--since", "yesterday"]).unwrap();
+        assert!(select(&args, records(), None).is_err());
+    }
+}
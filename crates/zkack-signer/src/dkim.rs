@@ -0,0 +1,418 @@
+//! Minimal DKIM signer (RFC 6376, relaxed/relaxed) with rsa-sha256 and
+//! ed25519-sha256 (RFC 8463). Used to add a DKIM-Signature that covers X-ZK-DAT.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use clap::ValueEnum;
+use ed25519_dalek::Signer as _;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use sha2::{Digest, Sha256};
+use std::fs;
+use time::OffsetDateTime;
+use zkack_spec::PrivKeyJson;
+
+use crate::header_end;
+
+/// Headers signed by default (only those present are listed in h=); x-zk-dat is always added.
+pub const DEFAULT_SIGNED_HEADERS: &[&str] = &[
+    "from",
+    "to",
+    "cc",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+    "x-zk-dat",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DkimAlg {
+    #[value(name = "rsa-sha256")]
+    RsaSha256,
+    #[value(name = "ed25519-sha256")]
+    Ed25519Sha256,
+}
+
+impl DkimAlg {
+    fn tag(self) -> &'static str {
+        match self {
+            DkimAlg::RsaSha256 => "rsa-sha256",
+            DkimAlg::Ed25519Sha256 => "ed25519-sha256",
+        }
+    }
+}
+
+enum DkimKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    alg: DkimAlg,
+    key: DkimKey,
+    headers: Vec<String>,
+}
+
+impl DkimSigner {
+    /// Load a DKIM key: PKCS#8 or PKCS#1 PEM for RSA, PKCS#8 PEM or a zkack
+    /// private key JSON for Ed25519.
+    pub fn load(path: &str, alg: DkimAlg, domain: &str, selector: &str) -> Result<Self> {
+        let raw = fs::read_to_string(path)?;
+        let key = match alg {
+            DkimAlg::RsaSha256 => {
+                let k = RsaPrivateKey::from_pkcs8_pem(&raw)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&raw))
+                    .map_err(|e| anyhow!("dkim rsa key: {e}"))?;
+                DkimKey::Rsa(k)
+            }
+            DkimAlg::Ed25519Sha256 => {
+                if let Ok(pkj) = serde_json::from_str::<PrivKeyJson>(&raw) {
+                    let sk = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(pkj.sk_b64)?;
+                    DkimKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                        &sk.try_into().map_err(|_| anyhow!("bad sk length"))?,
+                    ))
+                } else {
+                    DkimKey::Ed25519(
+                        ed25519_dalek::SigningKey::from_pkcs8_pem(&raw)
+                            .map_err(|e| anyhow!("dkim ed25519 key: {e}"))?,
+                    )
+                }
+            }
+        };
+        Ok(DkimSigner {
+            domain: domain.into(),
+            selector: selector.into(),
+            alg,
+            key,
+            headers: DEFAULT_SIGNED_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+        })
+    }
+
+    /// Override the signed header list; x-zk-dat is always kept.
+    pub fn with_headers(mut self, headers: &[String]) -> Self {
+        self.headers = headers
+            .iter()
+            .map(|h| h.trim().to_ascii_lowercase())
+            .collect();
+        if !self.headers.iter().any(|h| h == "x-zk-dat") {
+            self.headers.push("x-zk-dat".into());
+        }
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Prepend a DKIM-Signature to `eml`; the rest of the message is unchanged.
+    pub fn sign(&self, eml: &[u8]) -> Result<Vec<u8>> {
+        let (_, eol) = header_end(eml);
+        let value = self.signature_value(eml)?;
+        let mut out = Vec::with_capacity(eml.len() + 1024);
+        out.extend_from_slice(b"DKIM-Signature: ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(eol);
+        out.extend_from_slice(eml);
+        Ok(out)
+    }
+
+    /// DKIM-Signature header value for `eml`, folded with the message's line endings.
+    pub fn signature_value(&self, eml: &[u8]) -> Result<String> {
+        let (hdr_end, eol) = header_end(eml);
+        let fields = split_fields(&eml[..hdr_end]);
+        let body_start = if eml[hdr_end..].starts_with(b"\r\n") {
+            hdr_end + 2
+        } else {
+            (hdr_end + 1).min(eml.len())
+        };
+        let body = &eml[body_start..];
+        let bh = b64(&Sha256::digest(relaxed_body(body)));
+
+        if !fields
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("x-zk-dat"))
+        {
+            anyhow::bail!("dkim: message has no X-ZK-DAT header to cover");
+        }
+        // RFC 6376 5.4.2: each h= entry takes the next instance from the bottom up.
+        let mut used = vec![false; fields.len()];
+        let mut signed_names = Vec::new();
+        let mut signed_input = Vec::new();
+        for want in &self.headers {
+            // Every X-ZK-DAT is signed, and listed once more than it occurs (the extra
+            // entry signs an empty field), so a later hop cannot add an unsigned DAT
+            let oversign = want == "x-zk-dat";
+            while let Some((i, (name, raw))) = fields
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(i, (name, _))| !used[*i] && name.eq_ignore_ascii_case(want))
+            {
+                used[i] = true;
+                signed_names.push(want.clone());
+                signed_input.extend_from_slice(relaxed_header(name, raw).as_bytes());
+                signed_input.extend_from_slice(b"\r\n");
+                if !oversign {
+                    break;
+                }
+            }
+            if oversign {
+                signed_names.push(want.clone());
+            }
+        }
+
+        let fold = format!("{}\t", String::from_utf8_lossy(eol));
+        let value = format!(
+            "v=1; a={}; c=relaxed/relaxed; d={}; s={};{fold}t={}; h={};{fold}bh={};{fold}b=",
+            self.alg.tag(),
+            self.domain,
+            self.selector,
+            OffsetDateTime::now_utc().unix_timestamp(),
+            signed_names.join(":"),
+            bh,
+        );
+        signed_input.extend_from_slice(relaxed_header("DKIM-Signature", &value).as_bytes());
+
+        let hash = Sha256::digest(&signed_input);
+        let sig = match &self.key {
+            DkimKey::Rsa(k) => k
+                .sign(Pkcs1v15Sign::new::<Sha256>(), &hash)
+                .map_err(|e| anyhow!("dkim rsa sign: {e}"))?,
+            // RFC 8463: PureEdDSA over the SHA-256 hash of the signing input
+            DkimKey::Ed25519(k) => k.sign(&hash).to_bytes().to_vec(),
+        };
+        let sig_b64 = b64(&sig);
+        let folded: Vec<&str> = sig_b64
+            .as_bytes()
+            .chunks(64)
+            .map(|c| std::str::from_utf8(c).expect("base64 is ascii"))
+            .collect();
+
+        Ok(format!("{}{}", value, folded.join(&fold)))
+    }
+
+    /// Public key for the `<selector>._domainkey.<domain>` TXT record.
+    pub fn dns_record(&self) -> Result<String> {
+        let (k, p) = match &self.key {
+            DkimKey::Rsa(key) => {
+                use rsa::pkcs8::EncodePublicKey;
+                let der = key
+                    .to_public_key()
+                    .to_public_key_der()
+                    .map_err(|e| anyhow!("dkim rsa public key: {e}"))?;
+                ("rsa", b64(der.as_bytes()))
+            }
+            DkimKey::Ed25519(key) => ("ed25519", b64(key.verifying_key().as_bytes())),
+        };
+        Ok(format!("v=DKIM1; k={k}; p={p}"))
+    }
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+/// Split a header section into (name, raw value incl. folding) pairs.
+fn split_fields(hdrs: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(hdrs);
+    let mut out: Vec<(String, String)> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, v)) = out.last_mut() {
+                v.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            out.push((name.to_string(), value.to_string()));
+        }
+    }
+    out
+}
+
+/// RFC 6376 3.4.2 relaxed header canonicalization (without the trailing CRLF).
+fn relaxed_header(name: &str, value: &str) -> String {
+    let unfolded = value.replace(['\r', '\n'], "");
+    let mut v = String::with_capacity(unfolded.len());
+    let mut in_wsp = false;
+    for c in unfolded.chars() {
+        if c == ' ' || c == '\t' {
+            in_wsp = true;
+        } else {
+            if in_wsp && !v.is_empty() {
+                v.push(' ');
+            }
+            in_wsp = false;
+            v.push(c);
+        }
+    }
+    format!("{}:{}", name.trim().to_ascii_lowercase(), v)
+}
+
+/// RFC 6376 3.4.4 relaxed body canonicalization, with lines normalized to CRLF.
+fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = Vec::new();
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut out = Vec::with_capacity(line.len());
+        let mut in_wsp = false;
+        for &b in line {
+            if b == b' ' || b == b'\t' {
+                in_wsp = true;
+            } else {
+                if in_wsp {
+                    out.push(b' ');
+                }
+                in_wsp = false;
+                out.push(b);
+            }
+        }
+        lines.push(out);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    let mut out = Vec::new();
+    for l in lines {
+        out.extend_from_slice(&l);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Verifier as _;
+
+    #[test]
+    fn relaxed_canonicalization_matches_rfc6376_example() {
+        // RFC 6376 3.4.5
+        let fields = split_fields(b"A: X\r\nB : Y\t\r\n\tZ  \r\n");
+        let canon: Vec<String> = fields.iter().map(|(n, v)| relaxed_header(n, v)).collect();
+        assert_eq!(canon, ["a:X", "b:Y Z"]);
+        assert_eq!(relaxed_body(b" C \r\nD \t E\r\n\r\n\r\n"), b" C\r\nD E\r\n");
+    }
+
+    #[test]
+    fn relaxed_body_ignores_line_endings_and_trailing_blank_lines() {
+        assert_eq!(relaxed_body(b"a  b\nc\n\n"), relaxed_body(b"a b\r\nc\r\n"));
+        assert_eq!(relaxed_body(b""), b"");
+        assert_eq!(relaxed_body(b"\r\n\r\n"), b"");
+    }
+
+    #[test]
+    fn ed25519_signature_verifies_and_covers_x_zk_dat() {
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let signer = DkimSigner {
+            domain: "example.gov".into(),
+            selector: "s1".into(),
+            alg: DkimAlg::Ed25519Sha256,
+            key: DkimKey::Ed25519(sk.clone()),
+            headers: DEFAULT_SIGNED_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+        };
+        let eml = b"From: a@example.gov\nSubject:  hi\nX-ZK-DAT: a.b.c\n\nbody  text\n\n";
+        let value = signer.signature_value(eml).unwrap();
+        assert!(value.contains("h=from:subject:x-zk-dat:x-zk-dat;"));
+        assert!(value.contains(&format!("bh={};", b64(&Sha256::digest(b"body text\r\n")))));
+
+        let (unsigned, sig) = value.split_at(value.rfind("b=").unwrap() + 2);
+        let mut input = Vec::new();
+        for (name, raw) in split_fields(&eml[..header_end(eml).0]) {
+            input.extend_from_slice(relaxed_header(&name, &raw).as_bytes());
+            input.extend_from_slice(b"\r\n");
+        }
+        input.extend_from_slice(relaxed_header("DKIM-Signature", unsigned).as_bytes());
+        let sig: String = sig.chars().filter(|c| !c.is_whitespace()).collect();
+        let sig = ed25519_dalek::Signature::from_slice(
+            &base64::engine::general_purpose::STANDARD
+                .decode(sig)
+                .unwrap(),
+        )
+        .unwrap();
+        sk.verifying_key()
+            .verify(&Sha256::digest(&input), &sig)
+            .unwrap();
+
+        assert!(signer
+            .signature_value(b"From: a@example.gov\n\nbody\n")
+            .is_err());
+    }
+
+    #[test]
+    fn every_x_zk_dat_is_signed_and_oversigned() {
+        let signer = DkimSigner {
+            domain: "example.gov".into(),
+            selector: "s1".into(),
+            alg: DkimAlg::Ed25519Sha256,
+            key: DkimKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[7u8; 32])),
+            headers: vec!["from".into(), "x-zk-dat".into()],
+        };
+        let eml = b"From: a@example.gov\r\nX-ZK-DAT: agency\r\nX-ZK-DAT: vendor\r\n\r\nbody\r\n";
+        let value = signer.signature_value(eml).unwrap();
+        assert!(
+            value.contains("h=from:x-zk-dat:x-zk-dat:x-zk-dat;"),
+            "{value}"
+        );
+
+        let vk = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        let signed = signer.sign(eml).unwrap();
+        assert!(verifies(&signed, &vk));
+        // a DAT added below the signed ones fills the oversigned slot
+        let added = [
+            &signed[..signed.len() - 8],
+            b"X-ZK-DAT: injected\r\n\r\nbody\r\n",
+        ]
+        .concat();
+        assert!(added.ends_with(b"X-ZK-DAT: vendor\r\nX-ZK-DAT: injected\r\n\r\nbody\r\n"));
+        assert!(!verifies(&added, &vk));
+    }
+
+    /// Header check of an Ed25519 DKIM signature as a receiver makes it (RFC 6376 5.4.2:
+    /// h= entries take instances bottom-up; a missing instance signs nothing).
+    fn verifies(eml: &[u8], vk: &ed25519_dalek::VerifyingKey) -> bool {
+        let fields = split_fields(&eml[..header_end(eml).0]);
+        let (_, sig_raw) = &fields[0];
+        let value = sig_raw.as_str();
+        let tag = |t: &str| {
+            value
+                .split(';')
+                .map(|kv| kv.split_once('=').unwrap_or_default())
+                .find(|(k, _)| k.trim() == t)
+                .map(|(_, v)| v.chars().filter(|c| !c.is_whitespace()).collect::<String>())
+                .unwrap()
+        };
+        let mut used = vec![false; fields.len()];
+        let mut input = Vec::new();
+        for want in tag("h").split(':') {
+            if let Some((i, (name, raw))) = fields
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(i, (name, _))| *i > 0 && !used[*i] && name.eq_ignore_ascii_case(want))
+            {
+                used[i] = true;
+                input.extend_from_slice(relaxed_header(name, raw).as_bytes());
+                input.extend_from_slice(b"\r\n");
+            }
+        }
+        let unsigned = &value[..value.rfind("b=").unwrap() + 2];
+        input.extend_from_slice(relaxed_header("DKIM-Signature", unsigned).as_bytes());
+        let sig = base64::engine::general_purpose::STANDARD
+            .decode(tag("b"))
+            .unwrap();
+        let sig = ed25519_dalek::Signature::from_slice(&sig).unwrap();
+        vk.verify(&Sha256::digest(&input), &sig).is_ok()
+    }
+}
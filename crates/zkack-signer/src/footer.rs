@@ -0,0 +1,488 @@
+//! Visible verification footer: a short code and portal link appended to the
+//! text/plain and text/html parts before the digest is taken, so the DAT covers it.
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use mailparse::{parse_mail, DispositionType, ParsedMail};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::header_end;
+
+const CROCKFORD: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// A fresh verification code: 50 random bits as Crockford base32, `XXXXX-XXXXX`.
+pub fn new_code() -> String {
+    let mut b = [0u8; 8];
+    OsRng.fill_bytes(&mut b);
+    let n = u64::from_be_bytes(b);
+    let chars: String = (0..10)
+        .map(|i| CROCKFORD[((n >> (i * 5)) & 31) as usize] as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+/// Portal URLs must be https; `{code}` is replaced, otherwise `code=` is appended.
+pub fn check_url(url: &str) -> Result<()> {
+    if !url.starts_with("https://") || url.contains(char::is_whitespace) {
+        anyhow::bail!("footer URL must be an https:// URL without spaces: {url}");
+    }
+    Ok(())
+}
+
+/// Registers each issued DAT's code with the verifier (POST /zk-ack/v1/codes), so the
+/// code resolves before, or without, an ACK.
+pub struct CodeRegistry {
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl CodeRegistry {
+    pub fn new(verifier_url: &str) -> Result<Self> {
+        if !verifier_url.starts_with("https://") && !verifier_url.starts_with("http://") {
+            anyhow::bail!("--register-codes needs an http(s):// verifier URL: {verifier_url}");
+        }
+        Ok(CodeRegistry {
+            url: format!("{}/zk-ack/v1/codes", verifier_url.trim_end_matches('/')),
+            client: reqwest::blocking::Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()?,
+        })
+    }
+
+    pub fn register(&self, dat_jws: &str) -> Result<()> {
+        let resp: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .json(&serde_json::json!({ "dat_jws": dat_jws }))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .map_err(|e| anyhow!("register code at {}: {e}", self.url))?;
+        if resp["registered"] != true {
+            anyhow::bail!(
+                "register code at {}: code already taken by another notice",
+                self.url
+            );
+        }
+        Ok(())
+    }
+}
+
+fn link(url: &str, code: &str) -> String {
+    if url.contains("{code}") {
+        url.replace("{code}", code)
+    } else if url.contains('?') {
+        format!("{url}&code={code}")
+    } else {
+        format!("{url}?code={code}")
+    }
+}
+
+fn text_footer(link: &str, code: &str, eol: &str) -> String {
+    format!("{eol}Verify this notice at {link}{eol}Verification code: {code}{eol}")
+}
+
+fn html_footer(link: &str, code: &str) -> String {
+    let href = link.replace('&', "&amp;").replace('"', "&quot;");
+    format!(
+        "<div class=\"zkack-verify\" style=\"margin-top:1em;font-size:small\">\
+         Verify this notice at <a href=\"{href}\">{href}</a><br>\
+         Verification code: <b>{code}</b></div>"
+    )
+}
+
+/// Splice the footer into every inline text/plain and text/html part. Headers and
+/// all other parts are kept byte-for-byte; base64 parts are re-encoded.
+pub fn add_footer(eml: &[u8], url: &str, code: &str) -> Result<Vec<u8>> {
+    let eol = std::str::from_utf8(header_end(eml).1).unwrap();
+    let link = link(url, code);
+    let parsed = parse_mail(eml)?;
+    let mut edits = Vec::new();
+    collect(eml, &parsed, &mut edits)?;
+    if edits.is_empty() {
+        anyhow::bail!("footer: message has no inline text/plain or text/html part");
+    }
+    let plain = text_footer(&link, code, eol);
+    let html = html_footer(&link, code);
+    let mut out = eml.to_vec();
+    // back to front so earlier offsets stay valid
+    for (range, kind, enc) in edits.into_iter().rev() {
+        let body = &eml[range.clone()];
+        let footer = match kind {
+            Kind::Plain => &plain,
+            Kind::Html => &html,
+        };
+        let new = match enc {
+            Encoding::Identity => kind.apply(body, footer.as_bytes(), eol),
+            Encoding::QuotedPrintable => kind.apply(body, &qp_encode(footer, eol), eol),
+            Encoding::Base64 => {
+                let compact: Vec<u8> = body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                let decoded = base64::engine::general_purpose::STANDARD.decode(compact)?;
+                let encoded = base64::engine::general_purpose::STANDARD.encode(kind.apply(
+                    &decoded,
+                    footer.as_bytes(),
+                    eol,
+                ));
+                let mut lines = encoded
+                    .as_bytes()
+                    .chunks(76)
+                    .collect::<Vec<_>>()
+                    .join(eol.as_bytes());
+                if body.ends_with(b"\n") {
+                    lines.extend_from_slice(eol.as_bytes());
+                }
+                lines
+            }
+        };
+        out.splice(range, new);
+    }
+    Ok(out)
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Identity,
+    QuotedPrintable,
+    Base64,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Plain,
+    Html,
+}
+
+impl Kind {
+    fn apply(self, body: &[u8], footer: &[u8], eol: &str) -> Vec<u8> {
+        match self {
+            // the footer opens with a blank line; the body may not end its last line
+            Kind::Plain if !body.is_empty() && !body.ends_with(b"\n") => {
+                [body, eol.as_bytes(), footer].concat()
+            }
+            Kind::Plain => [body, footer].concat(),
+            Kind::Html => insert_html(body, footer),
+        }
+    }
+}
+
+fn collect(
+    eml: &[u8],
+    part: &ParsedMail,
+    out: &mut Vec<(std::ops::Range<usize>, Kind, Encoding)>,
+) -> Result<()> {
+    if !part.subparts.is_empty() {
+        for sub in &part.subparts {
+            collect(eml, sub, out)?;
+        }
+        return Ok(());
+    }
+    if part.get_content_disposition().disposition == DispositionType::Attachment {
+        return Ok(());
+    }
+    let enc = match part
+        .headers
+        .iter()
+        .find(|h| {
+            h.get_key_ref()
+                .eq_ignore_ascii_case("Content-Transfer-Encoding")
+        })
+        .map(|h| h.get_value().trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("7bit" | "8bit" | "binary") => Encoding::Identity,
+        Some("quoted-printable") => Encoding::QuotedPrintable,
+        Some("base64") => Encoding::Base64,
+        Some(other) => return Err(anyhow!("footer: unsupported transfer encoding {other}")),
+    };
+    let kind = match part.ctype.mimetype.as_str() {
+        "text/plain" => Kind::Plain,
+        "text/html" => Kind::Html,
+        _ => return Ok(()),
+    };
+    let start = part.raw_bytes.as_ptr() as usize - eml.as_ptr() as usize;
+    let body_start = start + header_end(part.raw_bytes).0;
+    let mut end = start + part.raw_bytes.len();
+    // a subpart ends with the line break that belongs to the next boundary
+    if end < eml.len() {
+        end -= line_break_len(&eml[body_start..end]);
+    }
+    // skip the blank separator line
+    let body_start = (body_start + line_break_len_at(&eml[body_start..end])).min(end);
+    out.push((body_start..end, kind, enc));
+    Ok(())
+}
+
+fn line_break_len(b: &[u8]) -> usize {
+    if b.ends_with(b"\r\n") {
+        2
+    } else if b.ends_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+fn line_break_len_at(b: &[u8]) -> usize {
+    if b.starts_with(b"\r\n") {
+        2
+    } else if b.starts_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+/// Quoted-printable for the footer: `=` escaped, soft breaks before 76 columns.
+fn qp_encode(s: &str, eol: &str) -> Vec<u8> {
+    let mut out = String::new();
+    for (i, line) in s.split(eol).enumerate() {
+        if i > 0 {
+            out.push_str(eol);
+        }
+        let mut col = 0;
+        for b in line.bytes() {
+            let enc = match b {
+                b'=' => "=3D".to_string(),
+                b' '..=b'~' => (b as char).to_string(),
+                b => format!("={b:02X}"),
+            };
+            if col + enc.len() > 75 {
+                out.push('=');
+                out.push_str(eol);
+                col = 0;
+            }
+            col += enc.len();
+            out.push_str(&enc);
+        }
+        // trailing space would be stripped in transit
+        if out.ends_with(' ') {
+            out.pop();
+            out.push_str("=20");
+        }
+    }
+    out.into_bytes()
+}
+
+/// Insert `footer` before the last `</body>` (or `</html>`), else append it.
+fn insert_html(body: &[u8], footer: &[u8]) -> Vec<u8> {
+    let lower = body.to_ascii_lowercase();
+    let at = rfind(&lower, b"</body").or_else(|| rfind(&lower, b"</html"));
+    let mut out = Vec::with_capacity(body.len() + footer.len());
+    match at {
+        Some(i) => {
+            out.extend_from_slice(&body[..i]);
+            out.extend_from_slice(footer);
+            out.extend_from_slice(&body[i..]);
+        }
+        None => {
+            out.extend_from_slice(body);
+            out.extend_from_slice(footer);
+        }
+    }
+    out
+}
+
+fn rfind(hay: &[u8], needle: &[u8]) -> Option<usize> {
+    hay.windows(needle.len()).rposition(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sign_message, DigestMode, ResignMode, SignOptions, SignerKey};
+
+    const URL: &str = "https://portal.example.gov/verify";
+    const CODE: &str = "ABCDE-12345";
+
+    fn body_of(eml: &[u8], path: &[usize]) -> String {
+        let mail = parse_mail(eml).unwrap();
+        let mut part = &mail;
+        for i in path {
+            part = &part.subparts[*i];
+        }
+        part.get_body().unwrap()
+    }
+
+    #[test]
+    fn codes_are_crockford_pairs() {
+        let code = new_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(&code[5..6], "-");
+        assert!(code
+            .replace('-', "")
+            .bytes()
+            .all(|b| CROCKFORD.contains(&b)));
+        assert_ne!(new_code(), code);
+    }
+
+    #[test]
+    fn link_fills_or_appends_the_code() {
+        assert_eq!(link(URL, CODE), format!("{URL}?code={CODE}"));
+        assert_eq!(
+            link("https://p/v?lang=en", CODE),
+            format!("https://p/v?lang=en&code={CODE}")
+        );
+        assert_eq!(
+            link("https://p/c/{code}", CODE),
+            format!("https://p/c/{CODE}")
+        );
+        assert!(check_url("http://portal.example.gov").is_err());
+        assert!(CodeRegistry::new("ftp://verifier").is_err());
+    }
+
+    #[test]
+    fn plain_part_gets_the_footer_on_its_own_lines() {
+        let eml = b"From: a@example.gov\r\nSubject: s\r\n\r\nHello";
+        let out = add_footer(eml, URL, CODE).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "From: a@example.gov\r\nSubject: s\r\n\r\nHello\r\n\r\n\
+                 Verify this notice at {URL}?code={CODE}\r\nVerification code: {CODE}\r\n"
+            )
+            .as_bytes()
+        );
+        // LF-only messages keep LF
+        let out = add_footer(b"Subject: s\n\nHello\n", URL, CODE).unwrap();
+        assert!(!out.contains(&b'\r'));
+        assert!(out.ends_with(format!("\nVerification code: {CODE}\n").as_bytes()));
+    }
+
+    #[test]
+    fn multipart_edits_keep_other_parts_byte_for_byte() {
+        let attachment = "--b\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: attachment; filename=\"a.txt\"\r\n\
+            \r\n\
+            attached text\r\n";
+        let eml = format!(
+            "MIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: multipart/alternative; boundary=\"alt\"\r\n\
+             \r\n\
+             --alt\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             Hello\r\n\
+             --alt\r\n\
+             Content-Type: text/html\r\n\
+             \r\n\
+             <html><BODY><p>Hello</p></BODY></html>\r\n\
+             --alt--\r\n\
+             {attachment}\
+             --b--\r\n"
+        );
+        let out = add_footer(eml.as_bytes(), URL, CODE).unwrap();
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.contains(attachment));
+        assert!(text.ends_with("\r\n--b--\r\n"));
+
+        let plain = body_of(&out, &[0, 0]);
+        assert!(
+            plain.starts_with("Hello\r\n\r\nVerify this notice at "),
+            "{plain}"
+        );
+        let html = body_of(&out, &[0, 1]);
+        assert!(
+            html.contains("<p>Hello</p><div class=\"zkack-verify\""),
+            "{html}"
+        );
+        assert!(
+            html.trim_end().ends_with("</b></div></BODY></html>"),
+            "{html}"
+        );
+        assert_eq!(body_of(&out, &[1]).trim_end(), "attached text");
+    }
+
+    #[test]
+    fn encoded_parts_stay_in_their_encoding() {
+        let qp = "Content-Type: text/plain; charset=utf-8\r\n\
+                  Content-Transfer-Encoding: quoted-printable\r\n\
+                  \r\n\
+                  caf=C3=A9\r\n";
+        let out = add_footer(qp.as_bytes(), "https://p/v?a=b", CODE).unwrap();
+        let raw = String::from_utf8(out.clone()).unwrap();
+        assert!(raw.contains("?a=3Db&code=3D"), "{raw}");
+        assert!(raw.lines().all(|l| l.len() <= 76));
+        let decoded = parse_mail(&out).unwrap().get_body().unwrap();
+        assert!(decoded.starts_with("café\r\n\r\nVerify this notice at https://p/v?a=b&code="));
+
+        let b64 = format!(
+            "Content-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+            base64::engine::general_purpose::STANDARD.encode("Hello\r\n")
+        );
+        let out = add_footer(b64.as_bytes(), URL, CODE).unwrap();
+        let decoded = parse_mail(&out).unwrap().get_body_raw().unwrap();
+        assert_eq!(
+            decoded,
+            format!("Hello\r\n\r\nVerify this notice at {URL}?code={CODE}\r\nVerification code: {CODE}\r\n")
+                .as_bytes()
+        );
+        assert!(out.ends_with(b"\r\n"));
+    }
+
+    #[test]
+    fn messages_without_inline_text_are_refused() {
+        let eml = b"Content-Type: application/pdf\r\n\r\nJVBERi0=\r\n";
+        assert!(add_footer(eml, URL, CODE).is_err());
+    }
+
+    #[test]
+    fn qp_encode_escapes_and_soft_breaks() {
+        let long = format!("{}=x ", "a".repeat(80));
+        let out = String::from_utf8(qp_encode(&long, "\r\n")).unwrap();
+        let lines: Vec<&str> = out.split("\r\n").collect();
+        assert!(lines.iter().all(|l| l.len() <= 76));
+        assert!(lines[0].ends_with('='));
+        assert!(out.ends_with("=3Dx=20"));
+        assert_eq!(qp_encode("é", "\n"), b"=C3=A9");
+    }
+
+    #[test]
+    fn html_footer_goes_before_the_closing_tag() {
+        assert_eq!(
+            insert_html(b"<p>x</p></Body></html>", b"F"),
+            b"<p>x</p>F</Body></html>"
+        );
+        assert_eq!(insert_html(b"<p>x</p></html>", b"F"), b"<p>x</p>F</html>");
+        assert_eq!(insert_html(b"<p>x</p>", b"F"), b"<p>x</p>F");
+    }
+
+    #[test]
+    fn digest_covers_the_footer() {
+        let key = SignerKey {
+            kid: "k1".into(),
+            sk: ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        };
+        let opts = SignOptions {
+            digest: DigestMode::Manifest,
+            ack_by_secs: 900,
+            dkim: None,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: Some(URL.into()),
+            resign: ResignMode::Refuse,
+        };
+        let eml = b"From: a@example.gov\r\nTo: you@example.com\r\n\r\nHello\r\n";
+        let signed = sign_message(&key, eml, "you@example.com", &opts).unwrap();
+        let code = signed.dat.verify_code.clone().unwrap();
+        let message = String::from_utf8(signed.message.clone()).unwrap();
+        assert!(message.contains(&format!("Verification code: {code}")));
+
+        let digest =
+            |m: &[u8]| zkack_spec::manifest_digest_b64(&zkack_spec::part_manifest(m).unwrap());
+        assert_eq!(digest(&signed.message), signed.dat.msg_digest_b64);
+        // a different code in the footer no longer matches the DAT
+        let tampered = message.replace(&code, "ZZZZZ-ZZZZZ");
+        assert_ne!(digest(tampered.as_bytes()), signed.dat.msg_digest_b64);
+    }
+}
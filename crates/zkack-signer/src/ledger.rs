@@ -0,0 +1,255 @@
+//! Issuance ledger: one record per DAT issued. The signing service keeps it in
+//! sled (keyed by nonce); CLI tools append JSON lines, which tolerates several
+//! signer processes sharing one file. Recipients are kept only as the DAT's salted
+//! address hash; [`IssuanceRecord::is_for`] matches an address against it.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use zkack_spec::{addr_hash_b64, normalize_addr, DatPayload};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuanceRecord {
+    pub issued_at: String,
+    pub kid: String,
+    pub nonce_b64: String,
+    pub addr_hash_b64: String,
+    /// The DAT's salt, for matching an address against addr_hash_b64
+    #[serde(default)]
+    pub salt_b64: String,
+    pub digest_alg: String,
+    pub msg_digest_b64: String,
+    pub exp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Where the signed copy went: file path, mbox#n, relay URL or "-" for stdout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Who asked for it: a signing-service client name, or the local tool
+    pub issuer: String,
+}
+
+impl IssuanceRecord {
+    pub fn new(kid: &str, dat: &DatPayload, issuer: &str) -> Self {
+        IssuanceRecord {
+            issued_at: OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap(),
+            kid: kid.into(),
+            nonce_b64: dat.nonce_b64.clone(),
+            addr_hash_b64: dat.addr_hash_b64.clone(),
+            salt_b64: dat.salt_b64.clone(),
+            digest_alg: dat.digest_alg.clone(),
+            msg_digest_b64: dat.msg_digest_b64.clone(),
+            exp: dat.exp.clone(),
+            sender: dat.sender.clone(),
+            message_id: None,
+            output: None,
+            issuer: issuer.into(),
+        }
+    }
+
+    /// Whether the DAT was issued for `addr` (compared in normalized form).
+    pub fn is_for(&self, addr: &str) -> bool {
+        use base64::Engine;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(&self.salt_b64)
+            .is_ok_and(|salt| addr_hash_b64(&salt, &normalize_addr(addr)) == self.addr_hash_b64)
+    }
+}
+
+#[derive(Clone)]
+pub struct Ledger {
+    db: sled::Db,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let db = sled::open(path)
+            .map_err(|e| anyhow!("open ledger failed (path={}): {e}", path.display()))?;
+        Ok(Ledger { db })
+    }
+
+    /// Record and flush; an issuance is not handed out until it is on disk.
+    pub fn record(&self, rec: &IssuanceRecord) -> Result<()> {
+        self.db
+            .insert(rec.nonce_b64.as_bytes(), serde_json::to_vec(rec)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, nonce_b64: &str) -> Result<Option<IssuanceRecord>> {
+        match self.db.get(nonce_b64.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = IssuanceRecord> + '_ {
+        self.db
+            .iter()
+            .flatten()
+            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+}
+
+/// Append-only JSON-lines ledger; one write per record, synced to disk before returning.
+pub struct JsonlLedger {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl JsonlLedger {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlLedger {
+            path: path.into(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, rec: &IssuanceRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(rec)?;
+        line.push(b'\n');
+        let mut f = self.file.lock().unwrap();
+        f.write_all(&line)
+            .and_then(|_| f.flush())
+            .and_then(|_| f.sync_data())
+            .map_err(|e| anyhow!("ledger {}: {e}", self.path.display()))
+    }
+}
+
+/// All records of a ledger: a sled directory (signing service) or a JSON-lines file.
+pub fn read_records(path: &Path) -> Result<Vec<IssuanceRecord>> {
+    if path.is_dir() {
+        return Ok(Ledger::open(path)?.iter().collect());
+    }
+    let raw =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("ledger {}: {e}", path.display()))?;
+    raw.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            serde_json::from_str(l).map_err(|e| anyhow!("{}:{}: {e}", path.display(), i + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    fn rec(nonce: &str, addr: &str) -> IssuanceRecord {
+        let salt = [4u8; 32];
+        IssuanceRecord {
+            issued_at: "2026-01-01T00:00:00Z".into(),
+            kid: "k1".into(),
+            nonce_b64: nonce.into(),
+            addr_hash_b64: addr_hash_b64(&salt, &normalize_addr(addr)),
+            salt_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(salt),
+            digest_alg: "blake3".into(),
+            msg_digest_b64: "d".into(),
+            exp: "2099-01-01T00:00:00Z".into(),
+            sender: None,
+            message_id: None,
+            output: None,
+            issuer: "local".into(),
+        }
+    }
+
+    fn tmp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zkack-ledger-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn is_for_matches_normalized_addresses() {
+        let r = rec("n1", "You@Example.com");
+        assert!(r.is_for("<you@example.com>"));
+        assert!(!r.is_for("other@example.com"));
+        let mut bad = r.clone();
+        bad.salt_b64 = "!".into();
+        assert!(!bad.is_for("you@example.com"));
+    }
+
+    #[test]
+    fn jsonl_append_and_read_back() {
+        let path = tmp("append.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let ledger = JsonlLedger::open(&path).unwrap();
+        ledger.append(&rec("n1", "a@example.com")).unwrap();
+        ledger.append(&rec("n2", "b@example.com")).unwrap();
+        drop(ledger);
+        // reopening appends rather than truncating
+        JsonlLedger::open(&path)
+            .unwrap()
+            .append(&rec("n3", "c@example.com"))
+            .unwrap();
+
+        let nonces: Vec<String> = read_records(&path)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.nonce_b64)
+            .collect();
+        assert_eq!(nonces, ["n1", "n2", "n3"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_records_reports_the_bad_line() {
+        let path = tmp("bad.jsonl");
+        let good = serde_json::to_string(&rec("n1", "a@example.com")).unwrap();
+        std::fs::write(&path, format!("{good}\n\n{{oops\n")).unwrap();
+        let err = read_records(&path).unwrap_err().to_string();
+        assert!(err.contains("bad.jsonl:3:"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_records(&path).is_err());
+    }
+
+    #[test]
+    fn sled_ledger_records_and_reads_back() {
+        let path = tmp("sled");
+        let _ = std::fs::remove_dir_all(&path);
+        {
+            let ledger = Ledger::open(&path).unwrap();
+            assert!(ledger.is_empty());
+            ledger.record(&rec("n1", "a@example.com")).unwrap();
+            ledger.record(&rec("n2", "b@example.com")).unwrap();
+            // same nonce: the record is replaced, not duplicated
+            let mut again = rec("n2", "b@example.com");
+            again.issuer = "svc".into();
+            ledger.record(&again).unwrap();
+            assert_eq!(ledger.len(), 2);
+            assert_eq!(ledger.get("n2").unwrap().unwrap().issuer, "svc");
+            assert!(ledger.get("n9").unwrap().is_none());
+        }
+        // read_records opens a sled directory as well as a JSON-lines file
+        let nonces: Vec<String> = read_records(&path)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.nonce_b64)
+            .collect();
+        assert_eq!(nonces, ["n1", "n2"]);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
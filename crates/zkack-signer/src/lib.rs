@@ -0,0 +1,827 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use clap::ValueEnum;
+use ed25519_dalek::SigningKey;
+use mailparse::{addrparse_header, parse_headers, MailAddr, MailHeader};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use regex::Regex;
+use std::fs;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use zkack_spec::*;
+
+pub mod dkim;
+pub mod footer;
+pub mod ledger;
+pub mod smtp;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DigestMode {
+    Auto,
+    Blake3,
+    DkimBh,
+    /// Per-MIME-part manifest committed in the DAT
+    Manifest,
+    /// Salted per-part commitments; disclosures go in X-ZK-DAT-SD headers
+    ManifestSd,
+}
+
+/// What to do with a message that already carries X-ZK-DAT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ResignMode {
+    /// Fail: signing twice is usually a pipeline mistake
+    #[default]
+    Refuse,
+    /// Drop the existing X-ZK-DAT / X-ZK-DAT-SD fields and issue a fresh DAT
+    Replace,
+    /// Keep the existing DATs and add ours (e.g. an agency and its delivery vendor)
+    Countersign,
+}
+
+/// The key's policy will not sign this message as it stands (sender outside the
+/// allowlist, an existing X-ZK-DAT under --resign refuse, ...); retrying cannot help.
+#[derive(Debug)]
+pub struct Refused(pub String);
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// True when a signing error is a policy refusal rather than a transient failure.
+pub fn is_refused(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Refused>().is_some()
+}
+
+/// A loaded issuer signing key.
+pub struct SignerKey {
+    pub kid: String,
+    sk: SigningKey,
+    /// Sender domains this key may sign for (From and DKIM d=, subdomains included).
+    pub domains: Vec<String>,
+    /// Published JWK Set URL, carried in each DAT's jku header.
+    pub jku: Option<String>,
+}
+
+impl SignerKey {
+    /// Refuse `domain` unless it aligns with the key's allowlist; keys without one sign nothing.
+    pub fn check_domain(&self, what: &str, domain: &str) -> Result<()> {
+        if self.domains.is_empty() {
+            return Err(Refused(format!(
+                "key {} has no domain allowlist; add \"domains\": [..] to its key file \
+                 (see docs/OPERATIONS.md) or run keygen <domain>",
+                self.kid
+            ))
+            .into());
+        }
+        if !domain_aligned(domain, &self.domains) {
+            return Err(Refused(format!(
+                "{what} domain {domain} is not allowed for key {} (allowed: {})",
+                self.kid,
+                self.domains.join(", ")
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Load a private key JSON (kid, sk_b64, vk_b64); `kid`, if given, must match the file.
+pub fn load_key(path: &str, kid: Option<&str>) -> Result<SignerKey> {
+    let raw = fs::read_to_string(path).map_err(|e| anyhow!("privkey {path}: {e}"))?;
+    let pkj: PrivKeyJson = serde_json::from_str(&raw)?;
+
+    let kid = match kid {
+        Some(k) => {
+            if k != pkj.kid {
+                anyhow::bail!("kid mismatch between --kid and privkey file");
+            }
+            k.to_string()
+        }
+        None => pkj.kid.clone(),
+    };
+
+    let sk_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(pkj.sk_b64)?;
+    let sk = SigningKey::from_bytes(&sk_bytes.try_into().map_err(|_| anyhow!("bad sk length"))?);
+    if let Some(u) = &pkj.jku {
+        if !(u.starts_with("https://") || u.starts_with("http://")) || !u.ends_with(JWKS_PATH) {
+            anyhow::bail!("privkey {path}: jku must be https://<host>{JWKS_PATH}");
+        }
+    }
+    Ok(SignerKey {
+        kid,
+        sk,
+        domains: pkj.domains,
+        jku: pkj.jku,
+    })
+}
+
+/// Per-issuance settings shared by every message signed in one run.
+#[derive(Clone)]
+pub struct SignOptions {
+    pub digest: DigestMode,
+    pub ack_by_secs: u64,
+    /// Add a DKIM-Signature covering X-ZK-DAT after injection.
+    pub dkim: Option<Arc<dkim::DkimSigner>>,
+    /// Expected sender (--from); the From header must match it.
+    pub from: Option<String>,
+    /// Escalation path carried in the DAT policy.
+    pub fallbacks: Vec<FallbackStep>,
+    /// Portal URL for a visible verification footer (code + link) in the text parts.
+    pub footer_url: Option<String>,
+    /// Handling of X-ZK-DAT already on the message.
+    pub resign: ResignMode,
+}
+
+/// Policy file (--policy-file): {"ack_by_secs"?: n, "fallbacks": [{channel, after_secs?, contact?}]}
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyFile {
+    pub ack_by_secs: Option<u64>,
+    pub fallbacks: Vec<FallbackStep>,
+}
+
+impl PolicyFile {
+    pub fn load(path: &str) -> Result<Self> {
+        let raw = fs::read_to_string(path).map_err(|e| anyhow!("policy file {path}: {e}"))?;
+        serde_json::from_str(&raw).map_err(|e| anyhow!("policy file {path}: {e}"))
+    }
+}
+
+/// Result of signing one message.
+pub struct Signed {
+    pub message: Vec<u8>,
+    pub dat: DatPayload,
+    pub jws: String,
+}
+
+pub fn find_dkim_bh(hdr_str: &str) -> Option<String> {
+    find_dkim_tag(hdr_str, "bh")
+}
+
+/// Signing domain (d=) of the first DKIM-Signature.
+pub fn find_dkim_d(hdr_str: &str) -> Option<String> {
+    find_dkim_tag(hdr_str, "d")
+}
+
+fn find_dkim_tag(hdr_str: &str, tag: &str) -> Option<String> {
+    // grab DKIM-Signature header (with folded lines)
+    let mut collecting = false;
+    let mut buf = String::new();
+    for line in hdr_str.lines() {
+        if !collecting {
+            if line.to_ascii_lowercase().starts_with("dkim-signature:") {
+                collecting = true;
+                buf.push_str(line);
+                buf.push_str("\r\n");
+            }
+        } else {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                buf.push_str(line);
+                buf.push_str("\r\n");
+            } else {
+                break;
+            }
+        }
+    }
+    if buf.is_empty() {
+        return None;
+    }
+    // regex for tag=...; (no spaces, ends at ; or end)
+    let re = Regex::new(&format!(r"(?i)(?:^|[;:\s]){tag}=\s*([^;\s\r\n]+)")).ok()?;
+    let caps = re.captures(&buf)?;
+    Some(caps.get(1)?.as_str().to_string())
+}
+
+/// Locate the end of the RFC 5322 header section.
+/// Returns (offset just past the last header line, line ending used by the message).
+/// The offset points at the empty separator line, or at EOF for a header-only message.
+pub fn header_end(eml: &[u8]) -> (usize, &'static [u8]) {
+    let eol: &'static [u8] = match eml.iter().position(|&b| b == b'\n') {
+        Some(i) if i > 0 && eml[i - 1] == b'\r' => b"\r\n",
+        Some(_) => b"\n",
+        None => b"\r\n",
+    };
+    let mut pos = 0;
+    while pos < eml.len() {
+        let line_end = eml[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(eml.len(), |i| pos + i + 1);
+        let line = &eml[pos..line_end];
+        if line == b"\n" || line == b"\r\n" {
+            return (pos, eol);
+        }
+        pos = line_end;
+    }
+    (eml.len(), eol)
+}
+
+/// Insert header fields at the end of the header section, in the message's own
+/// line-ending style. Every byte of the original message is kept as-is.
+pub fn inject_headers(eml: &[u8], fields: &[(&str, String)]) -> Vec<u8> {
+    let (at, eol) = header_end(eml);
+    let mut out = Vec::with_capacity(eml.len() + 1024);
+    out.extend_from_slice(&eml[..at]);
+    // header-only message whose last line lacks a terminator
+    if at == eml.len() && at > 0 && eml[at - 1] != b'\n' {
+        out.extend_from_slice(eol);
+    }
+    for (name, value) in fields {
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(eol);
+    }
+    out.extend_from_slice(&eml[at..]);
+    out
+}
+
+/// The message without X-ZK-DAT and X-ZK-DAT-SD fields.
+pub fn strip_dats(eml: &[u8]) -> Vec<u8> {
+    smtp::strip_header(&smtp::strip_header(eml, "X-ZK-DAT"), "X-ZK-DAT-SD")
+}
+
+/// Message-ID header value, if any (angle brackets kept).
+pub fn message_id(eml: &[u8]) -> Option<String> {
+    let (headers, _) = parse_headers(eml).ok()?;
+    headers
+        .iter()
+        .find(|h| h.get_key_ref().eq_ignore_ascii_case("Message-ID"))
+        .map(|h| h.get_value().trim().to_string())
+}
+
+/// Addresses of one To/Cc/Bcc header (groups flattened).
+pub fn header_addrs(h: &MailHeader) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    for a in addrparse_header(h)?.iter() {
+        match a {
+            MailAddr::Single(s) => out.push(s.addr.clone()),
+            MailAddr::Group(g) => out.extend(g.addrs.iter().map(|s| s.addr.clone())),
+        }
+    }
+    if let Some(bad) = out.iter().find(|a| !is_single_addr(a)) {
+        anyhow::bail!("{}: {bad:?} is not one address", h.get_key_ref());
+    }
+    Ok(out)
+}
+
+/// Whether `addr` is one bare addr-spec; addrparse reads `<a@x, b@y>` as a single address.
+pub fn is_single_addr(addr: &str) -> bool {
+    addr.matches('@').count() == 1
+        && !addr.contains(|c: char| c.is_whitespace() || ",;<>\"".contains(c))
+}
+
+/// Every To/Cc/Bcc recipient, normalized and de-duplicated, in header order.
+pub fn header_recipients(eml: &[u8]) -> Result<Vec<String>> {
+    let (headers, _) = parse_headers(eml)?;
+    let mut out: Vec<String> = Vec::new();
+    for h in &headers {
+        let key = h.get_key_ref();
+        if ["to", "cc", "bcc"]
+            .iter()
+            .any(|k| key.eq_ignore_ascii_case(k))
+        {
+            for a in header_addrs(h)? {
+                let a = normalize_addr(&a);
+                if !out.contains(&a) {
+                    out.push(a);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// One signed copy per recipient, each with its own DAT; Bcc is removed from
+/// every copy so no recipient learns about the others.
+pub fn sign_per_recipient(
+    key: &SignerKey,
+    eml: &[u8],
+    recipients: &[String],
+    opts: &SignOptions,
+) -> Result<Vec<(String, Signed)>> {
+    let eml = smtp::strip_header(eml, "Bcc");
+    recipients
+        .iter()
+        .map(|to| Ok((to.clone(), sign_message(key, &eml, to, opts)?)))
+        .collect()
+}
+
+/// A DAT issued over a message, with the header fields that carry it.
+pub struct Issued {
+    pub dat: DatPayload,
+    pub jws: String,
+    /// X-ZK-DAT, plus one X-ZK-DAT-SD per disclosure (holder keeps these)
+    pub headers: Vec<(&'static str, String)>,
+}
+
+/// Issue a DAT for `to` over `eml` and return the message with X-ZK-DAT injected.
+/// With a footer URL, the footer is added first so the digest covers it.
+pub fn sign_message(key: &SignerKey, eml: &[u8], to: &str, opts: &SignOptions) -> Result<Signed> {
+    let stripped;
+    let eml = if opts.resign == ResignMode::Replace {
+        stripped = strip_dats(eml);
+        &stripped
+    } else {
+        eml
+    };
+    let (eml, code) = match &opts.footer_url {
+        Some(url) => {
+            if find_dkim_bh(&String::from_utf8_lossy(&eml[..header_end(eml).0])).is_some() {
+                anyhow::bail!(
+                    "footer: message already has a DKIM-Signature, which the footer would break"
+                );
+            }
+            let code = footer::new_code();
+            (footer::add_footer(eml, url, &code)?, Some(code))
+        }
+        None => (eml.to_vec(), None),
+    };
+    let issued = issue(key, &eml, to, opts, code)?;
+    let mut message = inject_headers(&eml, &issued.headers);
+    if let Some(d) = &opts.dkim {
+        message = d.sign(&message)?;
+    }
+    Ok(Signed {
+        message,
+        dat: issued.dat,
+        jws: issued.jws,
+    })
+}
+
+/// Issue a DAT for `to` over `eml` without modifying the message; callers that
+/// cannot rewrite bytes themselves (e.g. a milter) add `headers` on their own.
+pub fn issue_dat(key: &SignerKey, eml: &[u8], to: &str, opts: &SignOptions) -> Result<Issued> {
+    issue(key, eml, to, opts, None)
+}
+
+fn issue(
+    key: &SignerKey,
+    eml: &[u8],
+    to: &str,
+    opts: &SignOptions,
+    verify_code: Option<String>,
+) -> Result<Issued> {
+    let existing = dat_headers(eml).len();
+    if existing > 0 {
+        match opts.resign {
+            ResignMode::Refuse => {
+                return Err(Refused(format!(
+                    "message already has {existing} X-ZK-DAT field(s); use --resign replace or countersign"
+                ))
+                .into())
+            }
+            ResignMode::Replace => {
+                anyhow::bail!("--resign replace: existing X-ZK-DAT fields must be removed first")
+            }
+            ResignMode::Countersign => {}
+        }
+    }
+    // Every DAT commits to the message without any X-ZK-DAT fields, so countersigned
+    // DATs cover the same bytes
+    let unsigned = strip_dats(eml);
+    let eml = unsigned.as_slice();
+    // header section only; the body is never decoded or rewritten
+    let hdr_str = String::from_utf8_lossy(&eml[..header_end(eml).0]);
+
+    // auto: prefer DKIM body hash if present, else blake3 of the raw .eml
+    let mut parts = None;
+    let mut parts_sd = None;
+    let mut disclosures = Vec::new();
+    let (digest_alg, msg_digest_b64) = match (opts.digest, find_dkim_bh(&hdr_str)) {
+        (DigestMode::Auto | DigestMode::DkimBh, Some(bh)) => ("dkim-bh".to_string(), bh),
+        (DigestMode::DkimBh, None) => anyhow::bail!("--digest dkim-bh: no DKIM-Signature bh="),
+        (DigestMode::Auto | DigestMode::Blake3, _) => ("blake3".to_string(), blake3_b64(eml)),
+        (DigestMode::Manifest, _) => {
+            let manifest = part_manifest(eml)?;
+            let digest = manifest_digest_b64(&manifest);
+            parts = Some(manifest);
+            (DIGEST_ALG_MANIFEST.to_string(), digest)
+        }
+        (DigestMode::ManifestSd, _) => {
+            disclosures = make_disclosures(&part_manifest(eml)?);
+            let commitments = sd_commitments(&disclosures);
+            let digest = sd_digest_b64(&commitments);
+            parts_sd = Some(commitments);
+            (DIGEST_ALG_MANIFEST_SD.to_string(), digest)
+        }
+    };
+
+    if digest_alg == "dkim-bh" {
+        let d = find_dkim_d(&hdr_str).ok_or_else(|| anyhow!("DKIM-Signature has no d="))?;
+        key.check_domain("DKIM d=", &d)?;
+    }
+    let sender = bind_sender(key, eml, opts)?;
+
+    let commitment = Commitment {
+        digest_alg,
+        msg_digest_b64,
+        parts,
+        parts_sd,
+        verify_code,
+    };
+    let (dat, jws) = build_dat(key, to, &sender, commitment, opts)?;
+    let mut headers = vec![("X-ZK-DAT", jws.clone())];
+    for d in disclosures {
+        headers.push(("X-ZK-DAT-SD", d));
+    }
+    Ok(Issued { dat, jws, headers })
+}
+
+/// Issue a DAT over a digest the caller computed (blake3, dkim-bh, or a manifest
+/// whose `parts` must hash to `msg_digest_b64`); no message bytes are needed.
+pub fn issue_dat_for_digest(
+    key: &SignerKey,
+    to: &str,
+    sender: &str,
+    digest_alg: &str,
+    msg_digest_b64: &str,
+    parts: Option<Vec<PartDigest>>,
+    opts: &SignOptions,
+) -> Result<Issued> {
+    let b64 = match digest_alg {
+        "dkim-bh" => &base64::engine::general_purpose::STANDARD,
+        _ => &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+    };
+    if !b64.decode(msg_digest_b64).is_ok_and(|d| d.len() == 32) {
+        anyhow::bail!("msg_digest_b64 is not a base64 32-byte digest");
+    }
+    match (digest_alg, &parts) {
+        ("blake3" | "dkim-bh", None) => {}
+        (DIGEST_ALG_MANIFEST, Some(p)) if manifest_digest_b64(p) == msg_digest_b64 => {}
+        (DIGEST_ALG_MANIFEST, Some(_)) => anyhow::bail!("parts do not hash to msg_digest_b64"),
+        (DIGEST_ALG_MANIFEST, None) => anyhow::bail!("digest_alg manifest needs parts"),
+        (alg, _) => anyhow::bail!("unsupported digest_alg for digest-only issuance: {alg}"),
+    }
+    let sender = normalize_addr(sender);
+    key.check_domain("sender", addr_domain(&sender)?)?;
+    let commitment = Commitment {
+        digest_alg: digest_alg.to_string(),
+        msg_digest_b64: msg_digest_b64.to_string(),
+        parts,
+        parts_sd: None,
+        verify_code: None,
+    };
+    let (dat, jws) = build_dat(key, to, &sender, commitment, opts)?;
+    Ok(Issued {
+        headers: vec![("X-ZK-DAT", jws.clone())],
+        dat,
+        jws,
+    })
+}
+
+/// Check the From header (and --from, and the DKIM d= we will sign with) against
+/// the key's allowlist; returns the normalized From address bound into the DAT.
+fn bind_sender(key: &SignerKey, eml: &[u8], opts: &SignOptions) -> Result<String> {
+    let (headers, _) = parse_headers(eml)?;
+    let mut from = Vec::new();
+    for h in headers
+        .iter()
+        .filter(|h| h.get_key_ref().eq_ignore_ascii_case("From"))
+    {
+        from.extend(header_addrs(h)?.iter().map(|a| normalize_addr(a)));
+    }
+    let sender = from
+        .first()
+        .cloned()
+        .ok_or_else(|| Refused("message has no From address".into()))?;
+    if let Some(want) = &opts.from {
+        if normalize_addr(want) != sender {
+            return Err(
+                Refused(format!("From header {sender} does not match --from {want}")).into(),
+            );
+        }
+    }
+    for addr in &from {
+        key.check_domain("From", addr_domain(addr)?)?;
+    }
+    if let Some(d) = &opts.dkim {
+        key.check_domain("DKIM d=", d.domain())?;
+    }
+    Ok(sender)
+}
+
+fn addr_domain(addr: &str) -> Result<&str> {
+    addr.rsplit_once('@')
+        .map(|(_, d)| d)
+        .filter(|d| !d.is_empty())
+        .ok_or_else(|| Refused(format!("sender {addr} has no domain")).into())
+}
+
+/// What a DAT commits to about the message.
+struct Commitment {
+    digest_alg: String,
+    msg_digest_b64: String,
+    parts: Option<Vec<PartDigest>>,
+    parts_sd: Option<Vec<String>>,
+    verify_code: Option<String>,
+}
+
+fn build_dat(
+    key: &SignerKey,
+    to: &str,
+    sender: &str,
+    c: Commitment,
+    opts: &SignOptions,
+) -> Result<(DatPayload, String)> {
+    let policy = Policy::new(opts.ack_by_secs, opts.fallbacks.clone());
+    policy.validate()?;
+    // Prepare DAT
+    let mut salt = [0u8; 32];
+    OsRng.fill_bytes(&mut salt);
+    let addr_hash = addr_hash_b64(&salt, &normalize_addr(to));
+    let exp = (OffsetDateTime::now_utc() + time::Duration::seconds(opts.ack_by_secs as i64))
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap();
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let dat = DatPayload {
+        v: 1,
+        salt_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(salt),
+        addr_hash_b64: addr_hash,
+        msg_digest_b64: c.msg_digest_b64,
+        digest_alg: c.digest_alg,
+        exp,
+        nonce_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(nonce),
+        policy,
+        parts: c.parts,
+        parts_sd: c.parts_sd,
+        sender: Some(sender.to_string()),
+        sender_domain: Some(addr_domain(sender)?.to_string()),
+        verify_code: c.verify_code,
+    };
+    let dat_json = serde_json::to_string(&dat)?;
+    let jws = jws_sign_jku(&dat_json, &key.kid, key.jku.as_deref(), &key.sk);
+    Ok((dat, jws))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::MailHeaderMap;
+
+    fn fields() -> Vec<(&'static str, String)> {
+        vec![("X-ZK-DAT", "a.b.c".to_string())]
+    }
+
+    #[test]
+    fn header_end_finds_separator_and_line_ending() {
+        let crlf = b"From: a@x\r\nTo: b@y\r\n\r\nbody\r\n";
+        assert_eq!(header_end(crlf), (20, &b"\r\n"[..]));
+        let lf = b"From: a@x\nTo: b@y\n\nbody\n";
+        assert_eq!(header_end(lf), (18, &b"\n"[..]));
+        let header_only = b"From: a@x\nTo: b@y";
+        assert_eq!(header_end(header_only), (header_only.len(), &b"\n"[..]));
+    }
+
+    #[test]
+    fn inject_headers_keeps_line_endings_and_body() {
+        let crlf = b"From: a@x\r\nSubject: s\r\n\r\nline 1\nline 2\r\n";
+        assert_eq!(
+            inject_headers(crlf, &fields()),
+            b"From: a@x\r\nSubject: s\r\nX-ZK-DAT: a.b.c\r\n\r\nline 1\nline 2\r\n"
+        );
+        let lf = b"From: a@x\n\n\nbody";
+        assert_eq!(
+            inject_headers(lf, &fields()),
+            b"From: a@x\nX-ZK-DAT: a.b.c\n\n\nbody"
+        );
+    }
+
+    #[test]
+    fn inject_headers_terminates_header_only_message() {
+        assert_eq!(
+            inject_headers(b"From: a@x", &fields()),
+            b"From: a@x\r\nX-ZK-DAT: a.b.c\r\n"
+        );
+        assert_eq!(
+            inject_headers(b"From: a@x\n", &fields()),
+            b"From: a@x\nX-ZK-DAT: a.b.c\n"
+        );
+    }
+
+    #[test]
+    fn strip_dats_undoes_inject_headers() {
+        let eml = b"From: a@x\r\nSubject: s\r\n\r\nbody\r\n";
+        let signed = inject_headers(
+            eml,
+            &[
+                ("X-ZK-DAT", "a.b.c".to_string()),
+                ("X-ZK-DAT-SD", "d".to_string()),
+            ],
+        );
+        assert_eq!(strip_dats(&signed), eml);
+    }
+
+    #[test]
+    fn sample_policy_file_parses_and_validates() {
+        let file: PolicyFile =
+            serde_json::from_str(include_str!("../../../samples/policy.json")).unwrap();
+        assert_eq!(file.ack_by_secs, Some(86400));
+        Policy::new(86400, file.fallbacks).validate().unwrap();
+        assert!(serde_json::from_str::<PolicyFile>(r#"{"fallbacks":[],"extra":1}"#).is_err());
+    }
+
+    fn key(domains: &[&str]) -> SignerKey {
+        SignerKey {
+            kid: "k1".into(),
+            sk: SigningKey::from_bytes(&[6u8; 32]),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            jku: None,
+        }
+    }
+
+    fn opts() -> SignOptions {
+        SignOptions {
+            digest: DigestMode::Blake3,
+            ack_by_secs: 900,
+            dkim: None,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: ResignMode::Refuse,
+        }
+    }
+
+    #[test]
+    fn domain_alignment_includes_subdomains_and_ignores_trailing_dots() {
+        let allowed = vec!["Example.GOV.".to_string()];
+        for ok in [
+            "example.gov",
+            "mail.example.gov",
+            "a.b.example.gov.",
+            "EXAMPLE.gov",
+        ] {
+            assert!(domain_aligned(ok, &allowed), "{ok}");
+        }
+        for bad in [
+            "badexample.gov",
+            "example.gov.evil.com",
+            "gov",
+            "example.com",
+        ] {
+            assert!(!domain_aligned(bad, &allowed), "{bad}");
+        }
+        assert!(!domain_aligned("example.gov", &[]));
+    }
+
+    #[test]
+    fn check_domain_refuses_unlisted_domains_and_keys_without_a_list() {
+        let k = key(&["example.gov"]);
+        k.check_domain("From", "notices.example.gov").unwrap();
+        let err = k.check_domain("DKIM d=", "example.org").unwrap_err();
+        assert!(is_refused(&err));
+        assert!(err
+            .to_string()
+            .contains("DKIM d= domain example.org is not allowed"));
+
+        let err = key(&[]).check_domain("From", "example.gov").unwrap_err();
+        assert!(is_refused(&err));
+        assert!(err.to_string().contains("no domain allowlist"), "{err}");
+    }
+
+    #[test]
+    fn bind_sender_checks_every_from_address() {
+        let k = key(&["example.gov"]);
+        let eml = b"From: Agency <Notices@Mail.Example.gov>\r\nTo: you@example.com\r\n\r\nx\r\n";
+        assert_eq!(
+            bind_sender(&k, eml, &opts()).unwrap(),
+            "notices@mail.example.gov"
+        );
+
+        let two = b"From: a@example.gov, b@example.org\r\n\r\nx\r\n";
+        assert!(is_refused(&bind_sender(&k, two, &opts()).unwrap_err()));
+        let none = b"To: you@example.com\r\n\r\nx\r\n";
+        assert!(is_refused(&bind_sender(&k, none, &opts()).unwrap_err()));
+        let no_domain = b"From: agency@\r\n\r\nx\r\n";
+        assert!(bind_sender(&k, no_domain, &opts()).is_err());
+
+        // --from must name the From header's address
+        let mut o = opts();
+        o.from = Some("<NOTICES@mail.example.gov>".into());
+        bind_sender(&k, eml, &o).unwrap();
+        o.from = Some("other@example.gov".into());
+        let err = bind_sender(&k, eml, &o).unwrap_err();
+        assert!(is_refused(&err) && err.to_string().contains("does not match --from"));
+    }
+
+    #[test]
+    fn bind_sender_checks_the_dkim_domain() {
+        let path =
+            std::env::temp_dir().join(format!("zkack-bind-dkim-{}.json", std::process::id()));
+        let sk = SigningKey::from_bytes(&[8u8; 32]);
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let pkj = PrivKeyJson {
+            kid: "dkim".into(),
+            sk_b64: b64.encode(sk.to_bytes()),
+            vk_b64: b64.encode(sk.verifying_key().to_bytes()),
+            domains: vec![],
+            jku: None,
+        };
+        fs::write(&path, serde_json::to_vec(&pkj).unwrap()).unwrap();
+        let dkim = |domain: &str| {
+            let p = path.to_str().unwrap();
+            Some(Arc::new(
+                dkim::DkimSigner::load(p, dkim::DkimAlg::Ed25519Sha256, domain, "s1").unwrap(),
+            ))
+        };
+        let k = key(&["example.gov"]);
+        let eml = b"From: a@example.gov\r\n\r\nx\r\n";
+        let mut o = opts();
+        o.dkim = dkim("mail.example.gov");
+        bind_sender(&k, eml, &o).unwrap();
+        o.dkim = dkim("esp.example.net");
+        let err = bind_sender(&k, eml, &o).unwrap_err();
+        assert!(is_refused(&err) && err.to_string().contains("DKIM d="));
+        fs::remove_file(&path).unwrap();
+    }
+
+    const MULTI: &[u8] = b"From: a@example.gov\r\n\
+To: \"Doe, Jane\" <Jane@Example.com>, bob@example.com\r\n\
+Cc: team: carol@example.com, <JANE@example.com>;, undisclosed-recipients:;\r\n\
+Bcc: audit@example.gov,\r\n BOB@example.com\r\n\
+Subject: s\r\n\
+\r\n\
+Bcc: in the body stays\r\n";
+
+    #[test]
+    fn header_recipients_parse_groups_dedupe_and_normalize() {
+        assert_eq!(
+            header_recipients(MULTI).unwrap(),
+            [
+                "jane@example.com",
+                "bob@example.com",
+                "carol@example.com",
+                "audit@example.gov"
+            ]
+        );
+        assert!(header_recipients(b"From: a@example.gov\r\n\r\nx\r\n")
+            .unwrap()
+            .is_empty());
+        assert!(header_recipients(b"To: <a@x, b@y>\r\n\r\nx\r\n").is_err());
+    }
+
+    #[test]
+    fn per_recipient_copies_have_their_own_dat_and_no_bcc() {
+        let k = key(&["example.gov"]);
+        let recipients = header_recipients(MULTI).unwrap();
+        let copies = sign_per_recipient(&k, MULTI, &recipients, &opts()).unwrap();
+        assert_eq!(copies.len(), 4);
+        let mut nonces = std::collections::HashSet::new();
+        for (to, signed) in &copies {
+            let (headers, _) = parse_headers(&signed.message).unwrap();
+            assert!(headers.get_first_value("Bcc").is_none(), "{to}");
+            assert!(headers.get_first_value("Cc").unwrap().contains("carol@"));
+            let text = String::from_utf8_lossy(&signed.message);
+            assert!(!text.contains("audit@example.gov"), "{to}");
+            assert!(text.ends_with("\r\n\r\nBcc: in the body stays\r\n"));
+
+            let salt = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(&signed.dat.salt_b64)
+                .unwrap();
+            assert_eq!(signed.dat.addr_hash_b64, addr_hash_b64(&salt, to));
+            nonces.insert(signed.dat.nonce_b64.clone());
+        }
+        assert_eq!(nonces.len(), 4);
+    }
+
+    #[test]
+    fn resign_modes_refuse_replace_or_countersign() {
+        let k = key(&["example.gov"]);
+        let eml = b"From: a@example.gov\r\nTo: you@example.com\r\n\r\nHello\r\n";
+        let first = sign_message(&k, eml, "you@example.com", &opts()).unwrap();
+        assert_eq!(dat_headers(&first.message), [first.jws.as_str()]);
+
+        let err = sign_message(&k, &first.message, "you@example.com", &opts())
+            .err()
+            .unwrap();
+        assert!(is_refused(&err) && err.to_string().contains("1 X-ZK-DAT"));
+
+        let mut o = opts();
+        o.resign = ResignMode::Replace;
+        let replaced = sign_message(&k, &first.message, "you@example.com", &o).unwrap();
+        assert_eq!(dat_headers(&replaced.message), [replaced.jws.as_str()]);
+        assert_ne!(replaced.dat.nonce_b64, first.dat.nonce_b64);
+        assert_eq!(replaced.dat.msg_digest_b64, first.dat.msg_digest_b64);
+
+        o.resign = ResignMode::Countersign;
+        let both = sign_message(&k, &first.message, "you@example.com", &o).unwrap();
+        assert_eq!(
+            dat_headers(&both.message),
+            [first.jws.clone(), both.jws.clone()]
+        );
+        // every DAT commits to the message without X-ZK-DAT fields
+        assert_eq!(both.dat.msg_digest_b64, first.dat.msg_digest_b64);
+        assert_eq!(strip_dats(&both.message), eml);
+        let third = sign_message(&k, &both.message, "you@example.com", &o).unwrap();
+        assert_eq!(dat_headers(&third.message).len(), 3);
+        o.resign = ResignMode::Replace;
+        let fresh = sign_message(&k, &third.message, "you@example.com", &o).unwrap();
+        assert_eq!(dat_headers(&fresh.message), [fresh.jws.as_str()]);
+    }
+}
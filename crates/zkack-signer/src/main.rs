@@ -1,133 +1,504 @@
-use anyhow::{anyhow, Result};
-use base64::Engine;
+use anyhow::Result;
 use clap::Parser;
-use ed25519_dalek::SigningKey;
-use rand::rngs::OsRng;
-use rand::RngCore;
-use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::fs;
-use time::OffsetDateTime;
-use zkack_spec::*;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zkack_signer::*;
+
+mod batch;
+mod merge;
+mod profile;
 
 /// Simple signer: reads an RFC5322 message (.eml), injects X-ZK-DAT header, prints to stdout.
+/// The output is the input byte-for-byte plus the injected header lines.
+/// With --batch, signs every message of a directory, mbox or Maildir instead;
+/// with --merge, renders and signs one notice per row of a CSV/JSON dataset.
 #[derive(Parser, Debug)]
 struct Args {
-    /// Path to private key JSON (kid, sk_b64, vk_b64)
+    /// Signer profile from --config; options given here override it
     #[arg(long)]
-    privkey: String,
+    profile: Option<String>,
+    /// Profiles file (TOML, [profiles.<name>] tables)
+    #[arg(long, default_value = "./zkack-signer.toml")]
+    config: PathBuf,
+    /// Path to private key JSON (kid, sk_b64, vk_b64)
+    #[arg(long, required_unless_present = "profile")]
+    privkey: Option<String>,
     /// Key id to use (must match priv key file)
     #[arg(long)]
     kid: Option<String>,
-    /// Recipient address (for addr_hash computation)
+    /// Recipient address (for addr_hash computation; batch mode defaults to each message's To)
+    #[arg(long, required_unless_present_any = ["batch", "dkim_print_record", "per_recipient", "profile", "merge"])]
+    to: Option<String>,
+    /// Issue one DAT and signed copy per recipient: --rcpt if given, else To/Cc/Bcc.
+    /// Copies go to --out <dir>, or each is submitted to its own recipient with --smtp-url.
+    #[arg(long, conflicts_with_all = ["to", "batch"])]
+    per_recipient: bool,
+    /// Expected sender: the From header must match, and its domain (and the DKIM d=)
+    /// must be in the key's domain allowlist
+    #[arg(long)]
+    from: Option<String>,
+    /// Input .eml file path (the batch input with --batch, the dataset with --merge)
+    #[arg(required_unless_present = "dkim_print_record")]
+    eml: Option<String>,
+    /// ACK deadline seconds (default: --policy-file's, else 900s)
+    #[arg(long)]
+    ack_by_secs: Option<u64>,
+    /// Fallback channel, repeatable, in escalation order: channel[:after_secs][=contact],
+    /// e.g. --fallback portal=https://portal.example.gov/ack --fallback sms:3600
+    /// (default: portal, sms)
+    #[arg(long, conflicts_with = "policy_file")]
+    fallback: Vec<zkack_spec::FallbackStep>,
+    /// Add a visible verification footer (short code + link to this https portal URL;
+    /// "{code}" in the URL is replaced, else ?code= is appended) to the text/plain and
+    /// text/html parts before the digest is taken
+    #[arg(long)]
+    footer_url: Option<String>,
+    /// Register each footer code with this verifier (POST /zk-ack/v1/codes) as it is
+    /// issued, so the portal resolves it before any ACK
+    #[arg(long)]
+    register_codes: Option<String>,
+    /// Policy JSON: {"ack_by_secs"?: n, "fallbacks": [{"channel", "after_secs"?, "contact"?}]}
+    #[arg(long)]
+    policy_file: Option<String>,
+    /// Message digest mode (auto = DKIM bh if present, else blake3) [default: auto]
+    #[arg(long, value_enum)]
+    digest: Option<DigestMode>,
+    /// When the message already has X-ZK-DAT: refuse, replace it, or countersign
+    /// (add ours next to it) [default: refuse]
+    #[arg(long, value_enum)]
+    resign: Option<ResignMode>,
+    /// Batch mode: treat the input as a directory of .eml files, an mbox or a Maildir
+    #[arg(long, value_enum)]
+    batch: Option<batch::InputKind>,
+    /// Batch output: mirrored directory, or mbox file (default: mbox for mbox input);
+    /// with --per-recipient or --merge, the directory for the signed copies
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// Mail merge: render a notice per dataset row from this TOML template
+    /// (from, to, subject, text/html bodies, attachments; {{field}} placeholders)
+    /// and sign it for the row's To address
+    #[arg(long, conflicts_with_all = ["to", "batch", "per_recipient"])]
+    merge: Option<PathBuf>,
+    /// Batch output format
+    #[arg(long, value_enum, requires = "batch")]
+    out_format: Option<batch::OutputKind>,
+    /// Batch report path (default: <out>.report.json)
+    #[arg(long, requires = "batch")]
+    report: Option<PathBuf>,
+    /// Batch worker threads (default: available CPUs)
+    #[arg(long, requires = "batch")]
+    jobs: Option<usize>,
+    /// DKIM private key (PEM; zkack key JSON also accepted for ed25519) to sign after injection
+    #[arg(long)]
+    dkim_key: Option<String>,
+    /// DKIM signing domain (d=)
+    #[arg(long)]
+    dkim_domain: Option<String>,
+    /// DKIM selector (s=)
+    #[arg(long)]
+    dkim_selector: Option<String>,
+    /// DKIM algorithm [default: rsa-sha256]
+    #[arg(long, value_enum)]
+    dkim_alg: Option<dkim::DkimAlg>,
+    /// Comma-separated headers to sign (h=); x-zk-dat is always included
+    #[arg(long, value_delimiter = ',')]
+    dkim_headers: Vec<String>,
+    /// Submit the signed message to this relay instead of printing it
+    /// (smtp://host:25, smtp://host:587?tls=required, smtps://user@host:465)
+    #[arg(long, conflicts_with = "batch")]
+    smtp_url: Option<String>,
+    /// Envelope sender (default: Sender or From header)
+    #[arg(long)]
+    mail_from: Option<String>,
+    /// Envelope recipient, repeatable (default: To, Cc and Bcc headers);
+    /// with --per-recipient, the recipients to issue for
+    #[arg(long)]
+    rcpt: Vec<String>,
+    /// Append one JSON line per submission (success or failure) here
+    /// [default: ./data/smtp-submissions.jsonl]
+    #[arg(long)]
+    smtp_log: Option<PathBuf>,
+    /// Issuance ledger (JSON lines): one record per DAT issued; query with ledger_query
+    /// [default: ./data/issuance-ledger.jsonl]
+    #[arg(long)]
+    ledger: Option<PathBuf>,
+    /// Do not write the issuance ledger
     #[arg(long)]
-    to: String,
-    /// From address (not used in skeleton hashing, but future DKIM alignment)
+    no_ledger: bool,
+    /// Sign for a --to that is not among the message's To/Cc/Bcc addresses
     #[arg(long)]
-    from: String,
-    /// Input .eml file path
-    eml: String,
-    /// ACK deadline seconds (default 900s)
-    #[arg(long, default_value_t = 900)]
-    ack_by_secs: u64,
+    allow_unlisted_to: bool,
+    /// Print the DKIM DNS TXT record for --dkim-key and exit
+    #[arg(long)]
+    dkim_print_record: bool,
 }
 
-fn find_dkim_bh(eml_str: &str) -> Option<String> {
-    // grab DKIM-Signature header (with folded lines)
-    let mut collecting = false;
-    let mut buf = String::new();
-    for line in eml_str.lines() {
-        if !collecting {
-            if line.to_ascii_lowercase().starts_with("dkim-signature:") {
-                collecting = true;
-                buf.push_str(line);
-                buf.push_str("\r\n");
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    let domains = profile::apply(&mut args)?;
+    args.check()?;
+    let mut key = load_key(
+        args.privkey.as_deref().expect("checked by Args::check"),
+        args.kid.as_deref(),
+    )?;
+    if !domains.is_empty() {
+        for d in &domains {
+            key.check_domain("profile", d)?;
+        }
+        key.domains = domains;
+    }
+    // Checked again per message by the library; this catches a bad profile up front
+    if let Some(from) = &args.from {
+        let from = zkack_spec::normalize_addr(from);
+        key.check_domain("--from", from.rsplit_once('@').map_or("", |(_, d)| d))?;
+    }
+    let dkim = match &args.dkim_key {
+        Some(path) => {
+            let mut d = dkim::DkimSigner::load(
+                path,
+                args.dkim_alg.unwrap_or(dkim::DkimAlg::RsaSha256),
+                args.dkim_domain.as_deref().unwrap_or_default(),
+                args.dkim_selector.as_deref().unwrap_or_default(),
+            )?;
+            if !args.dkim_headers.is_empty() {
+                d = d.with_headers(&args.dkim_headers);
+            }
+            if args.dkim_print_record {
+                println!("{}", d.dns_record()?);
+                return Ok(());
             }
+            key.check_domain("DKIM d=", d.domain())?;
+            Some(Arc::new(d))
+        }
+        None => None,
+    };
+    let (ack_by_secs, fallbacks) = match &args.policy_file {
+        Some(path) => {
+            let file = PolicyFile::load(path)?;
+            (args.ack_by_secs.or(file.ack_by_secs), file.fallbacks)
+        }
+        None if args.fallback.is_empty() => (args.ack_by_secs, zkack_spec::default_fallbacks()),
+        None => (args.ack_by_secs, args.fallback.clone()),
+    };
+    let ack_by_secs = ack_by_secs.unwrap_or(900);
+    // Fail before reading any input rather than on the first message.
+    zkack_spec::Policy::new(ack_by_secs, fallbacks.clone()).validate()?;
+    if let Some(url) = &args.footer_url {
+        footer::check_url(url)?;
+    }
+    let opts = SignOptions {
+        digest: args.digest.unwrap_or(DigestMode::Auto),
+        ack_by_secs,
+        dkim,
+        from: args.from.clone(),
+        fallbacks,
+        footer_url: args.footer_url.clone(),
+        resign: args.resign.unwrap_or_default(),
+    };
+    let ledger = if args.no_ledger {
+        None
+    } else {
+        Some(ledger::JsonlLedger::open(
+            args.ledger
+                .as_deref()
+                .unwrap_or(Path::new("./data/issuance-ledger.jsonl")),
+        )?)
+    };
+    let codes = args
+        .register_codes
+        .as_deref()
+        .map(footer::CodeRegistry::new)
+        .transpose()?;
+    let sinks = Sinks {
+        ledger: ledger.as_ref(),
+        codes: codes.as_ref(),
+    };
+
+    if let Some(kind) = args.batch {
+        let out = args
+            .out
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("--batch requires --out"))?;
+        let out_kind = args.out_format.unwrap_or(match kind {
+            batch::InputKind::Mbox => batch::OutputKind::Mbox,
+            _ => batch::OutputKind::Mirror,
+        });
+        let report = args.report.clone().unwrap_or_else(|| {
+            let mut p = out.clone().into_os_string();
+            p.push(".report.json");
+            p.into()
+        });
+        let jobs = args
+            .jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        return batch::run(
+            &key,
+            &opts,
+            &batch::BatchArgs {
+                input: Path::new(args.eml.as_deref().expect("clap enforces <EML>")),
+                kind,
+                out: &out,
+                out_kind,
+                report,
+                jobs,
+                to: args.to.as_deref(),
+                sinks,
+            },
+        );
+    }
+
+    if let Some(template) = &args.merge {
+        return merge::run(
+            &key,
+            &opts,
+            &merge::MergeArgs {
+                template,
+                data: Path::new(args.eml.as_deref().expect("clap enforces <EML>")),
+                out: args.out.as_deref(),
+                smtp_url: args.smtp_url.as_deref(),
+                smtp_log: args.smtp_log(),
+                sinks,
+            },
+        );
+    }
+
+    let eml = fs::read(args.eml.as_deref().expect("clap enforces <EML>"))?;
+    if args.per_recipient {
+        return per_recipient(&args, &key, &eml, &opts, sinks);
+    }
+    let to = args.to.as_deref().expect("clap enforces --to");
+    let listed = header_recipients(&eml)?;
+    if !listed.is_empty() && !listed.contains(&zkack_spec::normalize_addr(to)) {
+        if !args.allow_unlisted_to {
+            anyhow::bail!(
+                "--to {to} is not in To/Cc/Bcc ({}); see --per-recipient, or pass --allow-unlisted-to",
+                listed.join(", ")
+            );
+        }
+        eprintln!(
+            "warning: --to {to} is not in To/Cc/Bcc ({})",
+            listed.join(", ")
+        );
+    }
+    if let Some(url) = &args.smtp_url {
+        let submitter = smtp::Submitter::from_url(url)?;
+        let envelope = if args.mail_from.is_some() || !args.rcpt.is_empty() {
+            let derived = smtp::envelope_from_headers(&eml)?;
+            Some(smtp::MailEnvelope {
+                mail_from: args.mail_from.clone().or(derived.mail_from),
+                rcpt_to: if args.rcpt.is_empty() {
+                    derived.rcpt_to
+                } else {
+                    args.rcpt.clone()
+                },
+            })
         } else {
-            if line.starts_with(' ') || line.starts_with('\t') {
-                buf.push_str(line);
-                buf.push_str("\r\n");
+            None
+        };
+        let (signed, response) =
+            smtp::sign_and_submit(&key, &eml, to, &opts, &submitter, envelope, args.smtp_log())?;
+        record(sinks, &key, &signed, submitter.relay())?;
+        println!(
+            "{}",
+            serde_json::json!({
+                "relay": submitter.relay(),
+                "kid": key.kid,
+                "nonce_b64": signed.dat.nonce_b64,
+                "response": response,
+            })
+        );
+        return Ok(());
+    }
+    let signed = sign_message(&key, &eml, to, &opts)?;
+    record(sinks, &key, &signed, "-")?;
+    std::io::stdout().write_all(&signed.message)?;
+    Ok(())
+}
+
+impl Args {
+    /// Cross-option checks clap cannot make once a profile may supply the values.
+    fn check(&self) -> Result<()> {
+        let need = |ok: bool, msg: &str| {
+            if ok {
+                Ok(())
             } else {
-                break;
+                Err(anyhow::anyhow!("{msg}"))
             }
+        };
+        need(self.privkey.is_some(), "--privkey is required")?;
+        need(
+            self.dkim_key.is_none() || (self.dkim_domain.is_some() && self.dkim_selector.is_some()),
+            "--dkim-key requires --dkim-domain and --dkim-selector",
+        )?;
+        need(
+            !self.dkim_print_record || self.dkim_key.is_some(),
+            "--dkim-print-record requires --dkim-key",
+        )?;
+        if self.dkim_print_record {
+            return Ok(());
         }
+        need(
+            self.to.is_some() || self.per_recipient || self.batch.is_some() || self.merge.is_some(),
+            "--to is required (or --per-recipient / --batch / --merge)",
+        )?;
+        need(
+            !(self.per_recipient && self.to.is_some()),
+            "--per-recipient cannot be used with --to",
+        )?;
+        need(
+            self.mail_from.is_none() || self.smtp_url.is_some(),
+            "--mail-from requires --smtp-url",
+        )?;
+        need(
+            self.register_codes.is_none() || self.footer_url.is_some(),
+            "--register-codes requires --footer-url",
+        )
     }
-    if buf.is_empty() {
-        return None;
+
+    fn smtp_log(&self) -> &Path {
+        self.smtp_log
+            .as_deref()
+            .unwrap_or(Path::new("./data/smtp-submissions.jsonl"))
     }
-    // regex for bh=...; (no spaces, ends at ; or end)
-    let re = Regex::new(r"(?i)\bbh=([^;\s\r\n]+)").ok()?;
-    let caps = re.captures(&buf)?;
-    Some(caps.get(1)?.as_str().to_string())
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let eml = fs::read(&args.eml)?;
-    let eml_str = String::from_utf8_lossy(&eml);
-
-    // prefer DKIM body hash if present, else blake3 of the raw .eml
-    let (digest_alg, msg_digest_b64) = if let Some(bh) = find_dkim_bh(&eml_str) {
-        ("dkim-bh".to_string(), bh)
-    } else {
-        ("blake3".to_string(), blake3_b64(&eml))
-    };
+fn record(sinks: Sinks, key: &SignerKey, signed: &Signed, output: &str) -> Result<()> {
+    if let Some(ledger) = sinks.ledger {
+        let mut rec = ledger::IssuanceRecord::new(&key.kid, &signed.dat, "zkack-signer");
+        rec.message_id = message_id(&signed.message);
+        rec.output = Some(output.to_string());
+        ledger.append(&rec)?;
+    }
+    if let (Some(codes), Some(_)) = (sinks.codes, &signed.dat.verify_code) {
+        codes.register(&signed.jws)?;
+    }
+    Ok(())
+}
 
-    // Load private key
-    let raw = fs::read_to_string(&args.privkey)?;
-    let pkj: PrivKeyJson = serde_json::from_str(&raw)?;
+/// Where each issued DAT is reported.
+#[derive(Clone, Copy)]
+pub struct Sinks<'a> {
+    pub ledger: Option<&'a ledger::JsonlLedger>,
+    pub codes: Option<&'a footer::CodeRegistry>,
+}
 
-    let kid = match args.kid.clone() {
-        Some(k) => {
-            if k != pkj.kid {
-                anyhow::bail!("kid mismatch between --kid and privkey file");
+/// --per-recipient: one DAT per recipient, written to --out or submitted one by one.
+fn per_recipient(
+    args: &Args,
+    key: &SignerKey,
+    eml: &[u8],
+    opts: &SignOptions,
+    sinks: Sinks,
+) -> Result<()> {
+    let recipients = if args.rcpt.is_empty() {
+        header_recipients(eml)?
+    } else {
+        let mut r: Vec<String> = Vec::new();
+        for a in args.rcpt.iter().map(|a| zkack_spec::normalize_addr(a)) {
+            if !r.contains(&a) {
+                r.push(a);
             }
-            k
         }
-        None => pkj.kid.clone(),
+        r
     };
+    if recipients.is_empty() {
+        anyhow::bail!("no recipients: To/Cc/Bcc are empty and no --rcpt given");
+    }
 
-    let sk_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(pkj.sk_b64)?;
-    let sk = SigningKey::from_bytes(&sk_bytes.try_into().map_err(|_| anyhow!("bad sk length"))?);
-
-    // Prepare DAT
-    let mut salt = [0u8; 32];
-    OsRng.fill_bytes(&mut salt);
-    let addr_hash = addr_hash_b64(&salt, &args.to);
-    let exp = (OffsetDateTime::now_utc() + time::Duration::seconds(args.ack_by_secs as i64))
-        .format(&time::format_description::well_known::Rfc3339)
-        .unwrap();
-    let mut nonce = [0u8; 16];
-    OsRng.fill_bytes(&mut nonce);
-    let dat = DatPayload {
-        v: 1,
-        salt_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(salt),
-        addr_hash_b64: addr_hash,
-        msg_digest_b64,
-        digest_alg,
-        exp,
-        nonce_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(nonce),
-        policy: Policy {
-            ack_by_secs: args.ack_by_secs,
-            fallbacks: vec!["portal".into(), "sms".into()],
-        },
-    };
-    let dat_json = serde_json::to_string(&dat)?;
-    let jws = jws_sign(&dat_json, &kid, &sk);
+    if let Some(url) = &args.smtp_url {
+        let submitter = smtp::Submitter::from_url(url)?;
+        let mail_from = match &args.mail_from {
+            Some(m) => Some(m.clone()),
+            None => smtp::envelope_from_headers(eml)?.mail_from,
+        };
+        let mut failed = 0;
+        for to in &recipients {
+            let envelope = smtp::MailEnvelope {
+                mail_from: mail_from.clone(),
+                rcpt_to: vec![to.clone()],
+            };
+            let res = smtp::sign_and_submit(
+                key,
+                eml,
+                to,
+                opts,
+                &submitter,
+                Some(envelope),
+                args.smtp_log(),
+            );
+            if let Ok((signed, _)) = &res {
+                record(sinks, key, signed, submitter.relay())?;
+            }
+            let line = match &res {
+                Ok((signed, response)) => serde_json::json!({
+                    "recipient": to,
+                    "ok": true,
+                    "nonce_b64": signed.dat.nonce_b64,
+                    "response": response,
+                }),
+                Err(e) => {
+                    failed += 1;
+                    serde_json::json!({ "recipient": to, "ok": false, "error": format!("{e:#}") })
+                }
+            };
+            println!("{line}");
+        }
+        if failed > 0 {
+            anyhow::bail!("{failed} of {} submissions failed", recipients.len());
+        }
+        return Ok(());
+    }
 
-    // Inject header before headers/body blank line
-    let mut out = String::new();
-    let mut inserted = false;
-    for line in eml_str.lines() {
-        if !inserted && line.trim().is_empty() {
-            out.push_str(&format!("X-ZK-DAT: {}\r\n", jws));
-            inserted = true;
+    let out = args
+        .out
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("--per-recipient requires --out <dir> or --smtp-url"))?;
+    let mut names = HashSet::new();
+    for to in &recipients {
+        if !names.insert(file_stem(to)) {
+            anyhow::bail!("two recipients map to the file name {}.eml", file_stem(to));
         }
-        out.push_str(line);
-        out.push_str("\r\n");
     }
-    if !inserted {
-        out = format!("X-ZK-DAT: {}\r\n{}", jws, eml_str);
+    fs::create_dir_all(out)?;
+    for (to, signed) in sign_per_recipient(key, eml, &recipients, opts)? {
+        let path = out.join(format!("{}.eml", file_stem(&to)));
+        fs::write(&path, &signed.message)?;
+        record(sinks, key, &signed, &path.display().to_string())?;
+        println!(
+            "{}",
+            serde_json::json!({
+                "recipient": to,
+                "nonce_b64": signed.dat.nonce_b64,
+                "output": path,
+            })
+        );
     }
-    print!("{}", out);
     Ok(())
 }
+
+/// File name for a recipient's signed copy; an address with characters that had to be
+/// replaced gets a hash suffix, so two addresses never share a name.
+fn file_stem(addr: &str) -> String {
+    let stem: String = addr
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '@' | '.' | '_' | '+' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    if stem == addr {
+        return stem;
+    }
+    let hash = Sha256::digest(addr.as_bytes());
+    format!(
+        "{stem}-{}",
+        hash[..4]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    )
+}
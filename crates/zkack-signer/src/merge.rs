@@ -0,0 +1,593 @@
+//! Mail merge: render one MIME notice per row of a CSV/JSON dataset from a TOML
+//! template, and sign each in the same run.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use zkack_signer::{sign_message, smtp, SignOptions, SignerKey};
+
+/// Notice template. Every string may use `{{field}}` placeholders from the dataset;
+/// file paths are relative to the template's directory.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Template {
+    from: String,
+    /// Must render to exactly one address: the recipient the DAT is issued for
+    to: String,
+    subject: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+    /// Extra header fields
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    text: Option<String>,
+    text_file: Option<PathBuf>,
+    html: Option<String>,
+    html_file: Option<PathBuf>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Attachment {
+    path: String,
+    #[serde(default = "octet_stream")]
+    content_type: String,
+    /// Defaults to the file name of `path`
+    filename: Option<String>,
+}
+
+fn octet_stream() -> String {
+    "application/octet-stream".into()
+}
+
+type Row = BTreeMap<String, String>;
+
+/// Per-run inputs to the MIME boundaries and Message-IDs.
+struct RunSeed {
+    /// Boundaries are the same for the same template and row
+    tpl_hash: Vec<u8>,
+    /// Random per run: a re-run is a new message and must not reuse a Message-ID
+    run_id: String,
+}
+
+pub struct MergeArgs<'a> {
+    pub template: &'a Path,
+    pub data: &'a Path,
+    pub out: Option<&'a Path>,
+    pub smtp_url: Option<&'a str>,
+    pub smtp_log: &'a Path,
+    pub sinks: crate::Sinks<'a>,
+}
+
+pub fn run(key: &SignerKey, opts: &SignOptions, args: &MergeArgs) -> Result<()> {
+    let raw = fs::read_to_string(args.template)
+        .map_err(|e| anyhow!("template {}: {e}", args.template.display()))?;
+    let tpl: Template =
+        toml::from_str(&raw).map_err(|e| anyhow!("template {}: {e}", args.template.display()))?;
+    let base = args.template.parent().unwrap_or(Path::new("."));
+    let text = body(tpl.text.as_deref(), tpl.text_file.as_deref(), base, "text")?;
+    let html = body(tpl.html.as_deref(), tpl.html_file.as_deref(), base, "html")?;
+    if text.is_none() && html.is_none() {
+        anyhow::bail!("template: needs text, text_file, html or html_file");
+    }
+    let rows = read_rows(args.data)?;
+    let submitter = args.smtp_url.map(smtp::Submitter::from_url).transpose()?;
+    let out = match (args.out, &submitter) {
+        (Some(dir), _) => {
+            fs::create_dir_all(dir)?;
+            Some(dir)
+        }
+        (None, Some(_)) => None,
+        (None, None) => anyhow::bail!("--merge requires --out <dir> or --smtp-url"),
+    };
+    let seed = RunSeed {
+        tpl_hash: Sha256::digest(raw.as_bytes()).to_vec(),
+        run_id: format!("{:016x}", rand::random::<u64>()),
+    };
+
+    let mut failed = 0;
+    for (i, row) in rows.iter().enumerate() {
+        let n = i + 1;
+        let res = (|| -> Result<serde_json::Value> {
+            let (eml, to) = render(&tpl, &text, &html, base, row, &seed, n)?;
+            let (signed, output) = match &submitter {
+                Some(sub) => {
+                    let envelope = smtp::MailEnvelope {
+                        mail_from: None,
+                        rcpt_to: vec![to.clone()],
+                    };
+                    let (signed, _) = smtp::sign_and_submit(
+                        key,
+                        &eml,
+                        &to,
+                        opts,
+                        sub,
+                        Some(envelope),
+                        args.smtp_log,
+                    )?;
+                    (signed, sub.relay().to_string())
+                }
+                None => {
+                    let signed = sign_message(key, &eml, &to, opts)?;
+                    let path = out
+                        .expect("checked above")
+                        .join(format!("{n:05}-{}.eml", crate::file_stem(&to)));
+                    fs::write(&path, &signed.message)?;
+                    (signed, path.display().to_string())
+                }
+            };
+            crate::record(args.sinks, key, &signed, &output)?;
+            Ok(serde_json::json!({
+                "row": n,
+                "recipient": to,
+                "ok": true,
+                "nonce_b64": signed.dat.nonce_b64,
+                "output": output,
+            }))
+        })();
+        let line = res.unwrap_or_else(|e| {
+            failed += 1;
+            serde_json::json!({ "row": n, "ok": false, "error": format!("{e:#}") })
+        });
+        println!("{line}");
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} notices failed", rows.len());
+    }
+    Ok(())
+}
+
+fn body(
+    inline: Option<&str>,
+    file: Option<&Path>,
+    base: &Path,
+    what: &str,
+) -> Result<Option<String>> {
+    match (inline, file) {
+        (Some(_), Some(_)) => anyhow::bail!("template: set {what} or {what}_file, not both"),
+        (Some(s), None) => Ok(Some(s.to_string())),
+        (None, Some(p)) => {
+            let p = base.join(p);
+            Ok(Some(fs::read_to_string(&p).with_context(|| {
+                format!("template {what}_file {}", p.display())
+            })?))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// CSV with a header row, or a JSON array of objects (non-string values are stringified).
+fn read_rows(path: &Path) -> Result<Vec<Row>> {
+    let ctx = || format!("dataset {}", path.display());
+    if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+    {
+        let raw = fs::read_to_string(path).with_context(ctx)?;
+        let items: Vec<serde_json::Map<String, serde_json::Value>> =
+            serde_json::from_str(&raw).with_context(ctx)?;
+        return Ok(items
+            .into_iter()
+            .map(|obj| {
+                obj.into_iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            serde_json::Value::String(s) => s,
+                            serde_json::Value::Null => String::new(),
+                            other => other.to_string(),
+                        };
+                        (k, v)
+                    })
+                    .collect()
+            })
+            .collect());
+    }
+    let mut rdr = csv::Reader::from_path(path).with_context(ctx)?;
+    rdr.deserialize().map(|r| r.with_context(ctx)).collect()
+}
+
+/// Replace `{{field}}` placeholders; `escape` HTML-escapes the substituted values.
+fn fill(tpl: &str, row: &Row, escape: bool) -> Result<String> {
+    let mut out = String::with_capacity(tpl.len());
+    let mut rest = tpl;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("unclosed {{{{ in template"))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = row
+            .get(name)
+            .ok_or_else(|| anyhow!("no field {name:?} in dataset"))?;
+        out.push_str(&rest[..start]);
+        if escape {
+            for c in value.chars() {
+                match c {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    '"' => out.push_str("&quot;"),
+                    '\'' => out.push_str("&#39;"),
+                    _ => out.push(c),
+                }
+            }
+        } else {
+            out.push_str(value);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// A rendered header value: no line breaks (a dataset must not add header fields).
+fn header(tpl: &str, row: &Row) -> Result<String> {
+    let v = fill(tpl, row, false)?;
+    if v.contains(['\r', '\n']) {
+        anyhow::bail!("header value {v:?} contains a line break");
+    }
+    Ok(v)
+}
+
+/// RFC 2047 encoded word for non-ASCII header text.
+fn encode_word(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+    format!(
+        "=?utf-8?B?{}?=",
+        base64::engine::general_purpose::STANDARD.encode(s)
+    )
+}
+
+/// The one address in a rendered address header, normalized; groups and lists are refused.
+fn single_addr(what: &str, s: &str) -> Result<String> {
+    let list = mailparse::addrparse(s).map_err(|e| anyhow!("{what} {s:?}: {e}"))?;
+    match list.as_slice() {
+        [mailparse::MailAddr::Single(info)] if zkack_signer::is_single_addr(&info.addr) => {
+            Ok(zkack_spec::normalize_addr(&info.addr))
+        }
+        _ => anyhow::bail!("{what} {s:?} must be exactly one address"),
+    }
+}
+
+/// A rendered attachment path that came from the dataset must stay under the
+/// template directory.
+fn check_attachment_path(tpl: &str, rendered: &str) -> Result<()> {
+    let plain = Path::new(rendered)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if tpl.contains("{{") && !plain {
+        anyhow::bail!(
+            "attachment path {rendered:?}: dataset values may not leave the template directory"
+        );
+    }
+    Ok(())
+}
+
+/// Address header `Name <addr>`: the display name is quoted or RFC 2047 encoded as needed.
+fn encode_addr(s: &str) -> String {
+    let Some((name, addr)) = s.rsplit_once('<') else {
+        return s.to_string();
+    };
+    let name = name.trim().trim_matches('"');
+    if name.is_empty() {
+        return format!("<{addr}");
+    }
+    let name = if !name.is_ascii() {
+        encode_word(name)
+    } else if name.contains(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        name.to_string()
+    };
+    format!("{name} <{addr}")
+}
+
+/// Leaf part headers and body: 7bit when possible, else base64.
+fn leaf(content_type: &str, data: &[u8], filename: Option<&str>) -> String {
+    let mut h = format!("Content-Type: {content_type}\r\n");
+    if let Some(f) = filename {
+        h.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\n",
+            encode_word(&f.replace('"', ""))
+        ));
+    }
+    let plain =
+        data.is_ascii() && !data.contains(&0) && data.split(|b| *b == b'\n').all(|l| l.len() < 998);
+    if plain && filename.is_none() {
+        let text = String::from_utf8_lossy(data)
+            .replace("\r\n", "\n")
+            .replace('\n', "\r\n");
+        format!("{h}Content-Transfer-Encoding: 7bit\r\n\r\n{text}\r\n")
+    } else {
+        let b64 = base64::engine::general_purpose::STANDARD.encode(data);
+        let lines: Vec<&str> = b64
+            .as_bytes()
+            .chunks(76)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+        format!(
+            "{h}Content-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+            lines.join("\r\n")
+        )
+    }
+}
+
+fn multipart(subtype: &str, boundary: &str, parts: &[String]) -> String {
+    let mut s = format!("Content-Type: multipart/{subtype}; boundary=\"{boundary}\"\r\n\r\n");
+    for p in parts {
+        s.push_str(&format!("--{boundary}\r\n{p}"));
+    }
+    s.push_str(&format!("--{boundary}--\r\n"));
+    s
+}
+
+/// Render one notice; returns the message and its normalized recipient.
+fn render(
+    tpl: &Template,
+    text: &Option<String>,
+    html: &Option<String>,
+    base: &Path,
+    row: &Row,
+    run: &RunSeed,
+    n: usize,
+) -> Result<(Vec<u8>, String)> {
+    let seed: String = Sha256::new()
+        .chain_update(&run.tpl_hash)
+        .chain_update(serde_json::to_vec(row)?)
+        .chain_update(n.to_be_bytes())
+        .finalize()
+        .iter()
+        .take(12)
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let from = header(&tpl.from, row)?;
+    let domain = single_addr("from", &from)?
+        .rsplit_once('@')
+        .map(|(_, d)| d.to_string())
+        .unwrap_or_default();
+    let to_header = header(&tpl.to, row)?;
+    let to = single_addr("to", &to_header)?;
+
+    let mut alternatives = Vec::new();
+    if let Some(t) = text {
+        alternatives.push(leaf(
+            "text/plain; charset=utf-8",
+            fill(t, row, false)?.as_bytes(),
+            None,
+        ));
+    }
+    if let Some(h) = html {
+        alternatives.push(leaf(
+            "text/html; charset=utf-8",
+            fill(h, row, true)?.as_bytes(),
+            None,
+        ));
+    }
+    let main = if alternatives.len() == 1 {
+        alternatives.remove(0)
+    } else {
+        multipart("alternative", &format!("=_alt_{seed}"), &alternatives)
+    };
+    let body = if tpl.attachments.is_empty() {
+        main
+    } else {
+        let mut parts = vec![main];
+        for a in &tpl.attachments {
+            let rendered = header(&a.path, row)?;
+            check_attachment_path(&a.path, &rendered)?;
+            let path = base.join(rendered);
+            let data = fs::read(&path).with_context(|| format!("attachment {}", path.display()))?;
+            let name = match &a.filename {
+                Some(f) => header(f, row)?,
+                None => path
+                    .file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            parts.push(leaf(&a.content_type, &data, Some(&name)));
+        }
+        multipart("mixed", &format!("=_mix_{seed}"), &parts)
+    };
+
+    let mut h = String::new();
+    h.push_str(&format!("From: {}\r\n", encode_addr(&from)));
+    h.push_str(&format!("To: {}\r\n", encode_addr(&to_header)));
+    if let Some(r) = &tpl.reply_to {
+        h.push_str(&format!("Reply-To: {}\r\n", encode_addr(&header(r, row)?)));
+    }
+    h.push_str(&format!(
+        "Subject: {}\r\n",
+        encode_word(&header(&tpl.subject, row)?)
+    ));
+    h.push_str(&format!(
+        "Date: {}\r\n",
+        OffsetDateTime::now_utc().format(&Rfc2822)?
+    ));
+    h.push_str(&format!(
+        "Message-ID: <{seed}.{}.{n}@{domain}>\r\n",
+        run.run_id
+    ));
+    for (name, value) in &tpl.headers {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+            anyhow::bail!("template: bad header name {name:?}");
+        }
+        h.push_str(&format!(
+            "{name}: {}\r\n",
+            encode_word(&header(value, row)?)
+        ));
+    }
+    h.push_str("MIME-Version: 1.0\r\n");
+    Ok((format!("{h}{body}").into_bytes(), to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::MailHeaderMap;
+
+    fn row(pairs: &[(&str, &str)]) -> Row {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn seed() -> RunSeed {
+        RunSeed {
+            tpl_hash: vec![0; 32],
+            run_id: "run1".into(),
+        }
+    }
+
+    fn template(extra: &str) -> Template {
+        toml::from_str(&format!(
+            "from = \"Benefits Office <agency@example.gov>\"\n\
+             to = '\"{{{{name}}}}\" <{{{{email}}}}>'\n\
+             subject = \"Case {{{{case}}}}\"\n\
+             {extra}"
+        ))
+        .unwrap()
+    }
+
+    fn render_row(tpl: &Template, row: &Row, base: &Path) -> Result<(String, String)> {
+        let text = Some("Dear {{name}}".to_string());
+        let html = Some("<p>{{name}}</p>".to_string());
+        let (eml, to) = render(tpl, &text, &html, base, row, &seed(), 1)?;
+        Ok((String::from_utf8(eml).unwrap(), to))
+    }
+
+    #[test]
+    fn fill_substitutes_and_escapes() {
+        let r = row(&[("name", "A & <B>"), ("n", "1")]);
+        assert_eq!(
+            fill("Hi {{ name }} #{{n}}", &r, false).unwrap(),
+            "Hi A & <B> #1"
+        );
+        assert_eq!(fill("{{name}}", &r, true).unwrap(), "A &amp; &lt;B&gt;");
+        assert!(fill("{{missing}}", &r, false).is_err());
+        assert!(fill("{{name", &r, false).is_err());
+    }
+
+    #[test]
+    fn header_values_cannot_add_fields() {
+        let r = row(&[("s", "x\r\nBcc: evil@example.org")]);
+        assert!(header("Subject {{s}}", &r).is_err());
+        assert_eq!(header("plain", &r).unwrap(), "plain");
+    }
+
+    #[test]
+    fn display_names_are_quoted_or_encoded() {
+        assert_eq!(encode_addr("a@example.com"), "a@example.com");
+        assert_eq!(encode_addr("<a@example.com>"), "<a@example.com>");
+        assert_eq!(
+            encode_addr("Jane Doe <a@example.com>"),
+            "Jane Doe <a@example.com>"
+        );
+        assert_eq!(
+            encode_addr("\"Doe, Jane\" <a@example.com>"),
+            "\"Doe, Jane\" <a@example.com>"
+        );
+        assert_eq!(
+            encode_addr("Zoë <a@example.com>"),
+            "=?utf-8?B?Wm/Dqw==?= <a@example.com>"
+        );
+    }
+
+    #[test]
+    fn render_builds_a_single_recipient_notice() {
+        let tpl = template("");
+        let r = row(&[
+            ("name", "Doe, Jane"),
+            ("email", "Jane@Example.com"),
+            ("case", "A-1"),
+        ]);
+        let (eml, to) = render_row(&tpl, &r, Path::new(".")).unwrap();
+        assert_eq!(to, "jane@example.com");
+        let mail = mailparse::parse_mail(eml.as_bytes()).unwrap();
+        let get = |h: &str| mail.headers.get_first_value(h).unwrap();
+        assert_eq!(get("To"), "\"Doe, Jane\" <Jane@Example.com>");
+        assert_eq!(get("Subject"), "Case A-1");
+        assert!(get("Message-ID").ends_with(".run1.1@example.gov>"));
+        assert_eq!(mail.ctype.mimetype, "multipart/alternative");
+        assert_eq!(
+            mail.subparts[1].get_body().unwrap().trim(),
+            "<p>Doe, Jane</p>"
+        );
+    }
+
+    #[test]
+    fn recipient_lists_and_groups_are_refused() {
+        let tpl = template("");
+        for email in [
+            "a@example.com>, B <b@example.org",
+            "b@example.org, c@example.org",
+            "no-at-sign",
+        ] {
+            let r = row(&[("name", "A"), ("email", email), ("case", "1")]);
+            let err = render_row(&tpl, &r, Path::new(".")).unwrap_err();
+            assert!(format!("{err:#}").contains("to "), "{email}: {err:#}");
+        }
+        let group: Template = toml::from_str(
+            "from = \"agency@example.gov\"\nto = \"team: a@example.com, b@example.com;\"\nsubject = \"s\"",
+        )
+        .unwrap();
+        assert!(render_row(&group, &row(&[("name", "x")]), Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn message_ids_differ_between_runs() {
+        let tpl = template("");
+        let r = row(&[("name", "A"), ("email", "a@example.com"), ("case", "1")]);
+        let text = Some("t".to_string());
+        let id = |run_id: &str| {
+            let seed = RunSeed {
+                tpl_hash: vec![0; 32],
+                run_id: run_id.into(),
+            };
+            let (eml, _) = render(&tpl, &text, &None, Path::new("."), &r, &seed, 1).unwrap();
+            let (headers, _) = mailparse::parse_headers(&eml).unwrap();
+            headers.get_first_value("Message-ID").unwrap()
+        };
+        assert_eq!(id("r1"), id("r1"));
+        assert_ne!(id("r1"), id("r2"));
+    }
+
+    #[test]
+    fn dataset_attachment_paths_stay_under_the_template_dir() {
+        let base = std::env::temp_dir().join(format!("zkack-merge-{}", std::process::id()));
+        fs::create_dir_all(base.join("docs")).unwrap();
+        fs::write(base.join("docs/a-1.pdf"), b"%PDF-1.4\n").unwrap();
+        let tpl = template(
+            "[[attachments]]\npath = \"docs/{{file}}\"\ncontent_type = \"application/pdf\"",
+        );
+        let r = |file: &str| {
+            row(&[
+                ("name", "A"),
+                ("email", "a@example.com"),
+                ("case", "1"),
+                ("file", file),
+            ])
+        };
+        let (eml, _) = render_row(&tpl, &r("a-1.pdf"), &base).unwrap();
+        assert!(eml.contains("filename=\"a-1.pdf\""));
+        assert!(eml.contains("JVBERi0xLjQK"));
+        let tpl = template("[[attachments]]\npath = \"{{file}}\"\n");
+        for bad in ["../../etc/shadow", "/etc/shadow"] {
+            let err = render_row(&tpl, &r(bad), &base).unwrap_err();
+            assert!(format!("{err:#}").contains("template directory"), "{bad}");
+        }
+        // the template itself may point anywhere
+        assert!(check_attachment_path("../shared/logo.png", "../shared/logo.png").is_ok());
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Named signer profiles: `--profile <name>` fills in every option not given on the
+//! command line from `[profiles.<name>]` of the TOML file at `--config`.
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use zkack_signer::{dkim, smtp, DigestMode, ResignMode};
+use zkack_spec::FallbackStep;
+
+use crate::Args;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// One `[profiles.<name>]` table. Paths are relative to the working directory.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    /// Private key JSON
+    privkey: Option<String>,
+    kid: Option<String>,
+    /// Sender domains for this profile; must lie within the key file's allowlist
+    #[serde(default)]
+    domains: Vec<String>,
+    from: Option<String>,
+    ack_by_secs: Option<u64>,
+    digest: Option<String>,
+    /// Escalation path, as in a policy file
+    #[serde(default)]
+    fallbacks: Vec<FallbackStep>,
+    policy_file: Option<String>,
+    /// Portal URL for the visible verification footer
+    footer_url: Option<String>,
+    /// Verifier to register footer codes with
+    register_codes: Option<String>,
+    /// refuse, replace or countersign a message that already has X-ZK-DAT
+    resign: Option<String>,
+    /// Output mode: one copy per recipient instead of a single signed message
+    #[serde(default)]
+    per_recipient: bool,
+    out: Option<PathBuf>,
+    smtp_url: Option<String>,
+    mail_from: Option<String>,
+    smtp_log: Option<PathBuf>,
+    ledger: Option<PathBuf>,
+    dkim: Option<DkimProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DkimProfile {
+    key: String,
+    domain: String,
+    selector: String,
+    alg: Option<String>,
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+/// Merge the selected profile into `args` (command-line values win) and check it.
+/// Returns the profile's sender domains, which narrow the key's allowlist.
+pub fn apply(args: &mut Args) -> Result<Vec<String>> {
+    let Some(name) = args.profile.clone() else {
+        return Ok(Vec::new());
+    };
+    let p = load(&args.config, &name)?;
+    let ctx = || format!("profile {name} ({})", args.config.display());
+
+    if !p.fallbacks.is_empty() && p.policy_file.is_some() {
+        return Err(anyhow!("set fallbacks or policy_file, not both")).with_context(ctx);
+    }
+    fill(&mut args.privkey, p.privkey);
+    fill(&mut args.kid, p.kid);
+    fill(&mut args.from, p.from);
+    fill(&mut args.ack_by_secs, p.ack_by_secs);
+    fill(&mut args.footer_url, p.footer_url);
+    fill(&mut args.register_codes, p.register_codes);
+    if args.fallback.is_empty() && args.policy_file.is_none() {
+        args.fallback = p.fallbacks;
+        args.policy_file = p.policy_file;
+    }
+    if args.digest.is_none() {
+        args.digest = p
+            .digest
+            .map(|d| DigestMode::from_str(&d, true).map_err(|e| anyhow!("digest: {e}")))
+            .transpose()
+            .with_context(ctx)?;
+    }
+    if args.resign.is_none() {
+        args.resign = p
+            .resign
+            .map(|r| ResignMode::from_str(&r, true).map_err(|e| anyhow!("resign: {e}")))
+            .transpose()
+            .with_context(ctx)?;
+    }
+    // An explicit --to, --batch or --merge picks the mode over the profile's output mode
+    if p.per_recipient && args.to.is_none() && args.batch.is_none() && args.merge.is_none() {
+        args.per_recipient = true;
+    }
+    fill(&mut args.out, p.out);
+    fill(&mut args.mail_from, p.mail_from);
+    fill(&mut args.smtp_log, p.smtp_log);
+    fill(&mut args.ledger, p.ledger);
+    if let Some(url) = p.smtp_url {
+        if args.smtp_url.is_none() && args.batch.is_none() {
+            smtp::Submitter::from_url(&url).with_context(ctx)?;
+            args.smtp_url = Some(url);
+        }
+    }
+    if let Some(d) = p.dkim {
+        if args.dkim_key.is_none() {
+            args.dkim_key = Some(d.key);
+            args.dkim_domain = Some(d.domain);
+            args.dkim_selector = Some(d.selector);
+            if args.dkim_alg.is_none() {
+                args.dkim_alg = d
+                    .alg
+                    .map(|a| {
+                        dkim::DkimAlg::from_str(&a, true).map_err(|e| anyhow!("dkim alg: {e}"))
+                    })
+                    .transpose()
+                    .with_context(ctx)?;
+            }
+            if args.dkim_headers.is_empty() {
+                args.dkim_headers = d.headers;
+            }
+        }
+    }
+    if args.privkey.is_none() {
+        return Err(anyhow!("no privkey")).with_context(ctx);
+    }
+    Ok(p.domains)
+}
+
+fn load(path: &Path, name: &str) -> Result<Profile> {
+    let raw =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("config {}: {e}", path.display()))?;
+    let mut cfg: ConfigFile =
+        toml::from_str(&raw).map_err(|e| anyhow!("config {}: {e}", path.display()))?;
+    cfg.profiles.remove(name).ok_or_else(|| {
+        anyhow!(
+            "config {}: no profile {name} (have: {})",
+            path.display(),
+            cfg.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+fn fill<T>(slot: &mut Option<T>, value: Option<T>) {
+    if slot.is_none() {
+        *slot = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(name: &str, toml: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("zkack-profile-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        path
+    }
+
+    fn args(config: &Path, extra: &[&str]) -> Args {
+        let config = config.to_str().unwrap();
+        Args::parse_from(
+            ["zkack-signer", "--config", config]
+                .iter()
+                .chain(extra)
+                .chain(&["m.eml"]),
+        )
+    }
+
+    #[test]
+    fn sample_profiles_parse() {
+        let cfg: ConfigFile = toml::from_str(include_str!("../../../samples/signer.toml")).unwrap();
+        assert!(cfg.profiles.contains_key("dev"));
+        let a = &cfg.profiles["agency-a"];
+        assert_eq!(a.fallbacks.len(), 2);
+        assert!(a.per_recipient);
+        assert_eq!(a.dkim.as_ref().unwrap().selector, "zk1");
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let path = config(
+            "override",
+            r#"
+[profiles.p]
+privkey = "p.json"
+from = "a@example.gov"
+ack_by_secs = 60
+fallbacks = [{ channel = "sms", after_secs = 10 }]
+per_recipient = true
+"#,
+        );
+        let mut a = args(&path, &["--profile", "p", "--ack-by-secs", "120"]);
+        apply(&mut a).unwrap();
+        assert_eq!(a.privkey.as_deref(), Some("p.json"));
+        assert_eq!(a.from.as_deref(), Some("a@example.gov"));
+        assert_eq!(a.ack_by_secs, Some(120));
+        assert_eq!(a.fallback, vec!["sms:10".parse().unwrap()]);
+        assert!(a.per_recipient);
+
+        // an explicit --to picks single-recipient output over the profile's mode
+        let mut a = args(&path, &["--profile", "p", "--to", "b@example.com"]);
+        apply(&mut a).unwrap();
+        assert!(!a.per_recipient);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bad_profiles_are_refused() {
+        for (name, toml) in [
+            ("unknown-key", "[profiles.p]\nprivkey = \"p.json\"\nsender = \"x\"\n"),
+            (
+                "both-policies",
+                "[profiles.p]\nprivkey = \"p.json\"\npolicy_file = \"x.json\"\nfallbacks = [{ channel = \"sms\" }]\n",
+            ),
+            ("no-key", "[profiles.p]\nfrom = \"a@example.gov\"\n"),
+            ("bad-digest", "[profiles.p]\nprivkey = \"p.json\"\ndigest = \"md5\"\n"),
+        ] {
+            let path = config(name, toml);
+            let mut a = args(&path, &["--profile", "p"]);
+            assert!(apply(&mut a).is_err(), "{name} accepted");
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_profile_names_the_ones_available() {
+        let path = config("missing", "[profiles.a]\n[profiles.b]\n");
+        let err = load(&path, "c").unwrap_err().to_string();
+        assert!(err.ends_with("no profile c (have: a, b)"), "{err}");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+//! SMTP submission of signed messages to a relay.
+
+use anyhow::{anyhow, Result};
+use lettre::address::{Address, Envelope};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use mailparse::parse_headers;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use time::OffsetDateTime;
+
+use crate::{header_addrs, header_end, sign_message, SignOptions, Signed, SignerKey};
+
+/// Env var holding the relay password when the URL carries only a username.
+pub const SMTP_PASSWORD_ENV: &str = "ZKACK_SMTP_PASSWORD";
+
+/// Relay connection built from a URL:
+/// `smtp://host:25` (plain), `smtp://host:587?tls=required` (STARTTLS),
+/// `smtps://user@host:465` (implicit TLS); AUTH when the URL has a username.
+pub struct Submitter {
+    transport: SmtpTransport,
+    relay: String,
+}
+
+impl Submitter {
+    pub fn from_url(url: &str) -> Result<Self> {
+        let mut builder =
+            SmtpTransport::from_url(url).map_err(|e| anyhow!("smtp url {url}: {e}"))?;
+        let parsed = url::Url::parse(url)?;
+        if !parsed.username().is_empty() && parsed.password().is_none() {
+            let pass = std::env::var(SMTP_PASSWORD_ENV).map_err(|_| {
+                anyhow!("smtp url has a user but no password; set {SMTP_PASSWORD_ENV}")
+            })?;
+            builder = builder.credentials(Credentials::new(parsed.username().into(), pass));
+        }
+        let relay = format!(
+            "{}://{}:{}",
+            parsed.scheme(),
+            parsed.host_str().unwrap_or_default(),
+            parsed.port_or_known_default().unwrap_or(25)
+        );
+        Ok(Submitter {
+            transport: builder.build(),
+            relay,
+        })
+    }
+
+    /// Submit `message` unchanged; the relay's reply is returned on success.
+    pub fn send(&self, envelope: &MailEnvelope, message: &[u8]) -> Result<String> {
+        let from = envelope
+            .mail_from
+            .as_deref()
+            .map(str::parse::<Address>)
+            .transpose()?;
+        let to = envelope
+            .rcpt_to
+            .iter()
+            .map(|a| a.parse::<Address>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let env = Envelope::new(from, to).map_err(|e| anyhow!("smtp envelope: {e}"))?;
+        // lettre ends DATA with CRLF.CRLF; its CRLF ends our last line, so the relay
+        // receives exactly the bytes the DAT was issued over
+        let message = message
+            .strip_suffix(b"\r\n")
+            .or_else(|| message.strip_suffix(b"\n"))
+            .unwrap_or(message);
+        let resp = self
+            .transport
+            .send_raw(&env, message)
+            .map_err(|e| anyhow::Error::new(e).context(format!("smtp submit to {}", self.relay)))?;
+        Ok(format!(
+            "{} {}",
+            resp.code(),
+            resp.message().collect::<Vec<_>>().join(" ")
+        ))
+    }
+
+    pub fn relay(&self) -> &str {
+        &self.relay
+    }
+}
+
+/// True when a `send` error is a 5xx reply, i.e. retrying will not help.
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<lettre::transport::smtp::Error>()
+        .is_some_and(|e| e.is_permanent())
+}
+
+/// SMTP envelope (MAIL FROM / RCPT TO).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailEnvelope {
+    pub mail_from: Option<String>,
+    pub rcpt_to: Vec<String>,
+}
+
+/// Derive the envelope from headers: sender from Sender, else From;
+/// recipients from To, Cc and Bcc.
+pub fn envelope_from_headers(eml: &[u8]) -> Result<MailEnvelope> {
+    let (headers, _) = parse_headers(eml)?;
+    let mut sender = None;
+    let mut from = None;
+    let mut rcpt_to = Vec::new();
+    for h in &headers {
+        let key = h.get_key_ref().to_ascii_lowercase();
+        let slot = match key.as_str() {
+            "sender" => &mut sender,
+            "from" => &mut from,
+            "to" | "cc" | "bcc" => {
+                rcpt_to.extend(header_addrs(h)?);
+                continue;
+            }
+            _ => continue,
+        };
+        if slot.is_none() {
+            *slot = header_addrs(h)?.into_iter().next();
+        }
+    }
+    Ok(MailEnvelope {
+        mail_from: sender.or(from),
+        rcpt_to,
+    })
+}
+
+/// Remove every instance of a header field (with its continuation lines) from
+/// the header section; used to drop Bcc before signing a copy for submission.
+pub fn strip_header(eml: &[u8], name: &str) -> Vec<u8> {
+    let (hdr_end, _) = header_end(eml);
+    let mut out = Vec::with_capacity(eml.len());
+    let mut skipping = false;
+    for line in eml[..hdr_end].split_inclusive(|&b| b == b'\n') {
+        let folded = line.first().is_some_and(|&b| b == b' ' || b == b'\t');
+        if !folded {
+            skipping = line.len() > name.len()
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+                && line[name.len()..]
+                    .iter()
+                    .find(|&&b| b != b' ' && b != b'\t')
+                    == Some(&b':');
+        }
+        if !skipping {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(&eml[hdr_end..]);
+    out
+}
+
+/// One sign-and-submit outcome, appended as a JSON line to the submission log.
+#[derive(Debug, Serialize)]
+pub struct SubmissionRecord<'a> {
+    pub ts: String,
+    pub relay: &'a str,
+    pub kid: &'a str,
+    pub nonce_b64: &'a str,
+    pub envelope: &'a MailEnvelope,
+    pub ok: bool,
+    pub response: Option<String>,
+    pub error: Option<String>,
+}
+
+impl SubmissionRecord<'_> {
+    pub fn append_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(f, "{}", serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Sign and submit as one step: Bcc is dropped, the DAT is issued over the bytes
+/// actually sent, and the outcome (success or failure) is appended to `log`.
+/// `envelope` defaults to one derived from the headers.
+pub fn sign_and_submit(
+    key: &SignerKey,
+    eml: &[u8],
+    to: &str,
+    opts: &SignOptions,
+    submitter: &Submitter,
+    envelope: Option<MailEnvelope>,
+    log: &Path,
+) -> Result<(Signed, String)> {
+    let envelope = match envelope {
+        Some(e) => e,
+        None => envelope_from_headers(eml)?,
+    };
+    if envelope.rcpt_to.is_empty() {
+        anyhow::bail!("smtp: no envelope recipients (To/Cc/Bcc empty)");
+    }
+    let eml = strip_header(eml, "Bcc");
+    let signed = sign_message(key, &eml, to, opts)?;
+    let sent = submitter.send(&envelope, &signed.message);
+    let (response, error) = match &sent {
+        Ok(r) => (Some(r.clone()), None),
+        Err(e) => (None, Some(format!("{e:#}"))),
+    };
+    SubmissionRecord {
+        ts: now_rfc3339(),
+        relay: submitter.relay(),
+        kid: &key.kid,
+        nonce_b64: &signed.dat.nonce_b64,
+        envelope: &envelope,
+        ok: sent.is_ok(),
+        response,
+        error,
+    }
+    .append_to(log)?;
+    let response = sent?;
+    Ok((signed, response))
+}
+
+fn now_rfc3339() -> String {
+    OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DigestMode, ResignMode};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    const EML: &[u8] = b"From: Agency <agency@example.gov>\r\n\
+To: you@example.com\r\n\
+Bcc: audit@example.gov,\r\n hidden@example.gov\r\n\
+Subject: Notice\r\n\
+\r\n\
+Hello\r\n\
+.leading dot\r\n";
+
+    fn key() -> SignerKey {
+        SignerKey {
+            kid: "k1".into(),
+            sk: ed25519_dalek::SigningKey::from_bytes(&[5u8; 32]),
+            domains: vec!["example.gov".into()],
+            jku: None,
+        }
+    }
+
+    fn opts() -> SignOptions {
+        SignOptions {
+            digest: DigestMode::Blake3,
+            ack_by_secs: 900,
+            dkim: None,
+            from: None,
+            fallbacks: zkack_spec::default_fallbacks(),
+            footer_url: None,
+            resign: ResignMode::Refuse,
+        }
+    }
+
+    /// Commands and message a relay stand-in received.
+    type Session = (Vec<String>, Vec<u8>);
+
+    /// One-connection SMTP stand-in; returns its URL and, once the session ends,
+    /// the commands it saw and the (dot-unstuffed) message.
+    fn relay(rcpt_reply: &'static str) -> (String, std::thread::JoinHandle<Session>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut out = conn.try_clone().unwrap();
+            let mut r = BufReader::new(conn);
+            let (mut commands, mut data) = (Vec::new(), Vec::new());
+            out.write_all(b"220 stand-in ESMTP\r\n").unwrap();
+            let mut line = String::new();
+            while {
+                line.clear();
+                r.read_line(&mut line).unwrap() > 0
+            } {
+                let cmd = line.trim_end().to_string();
+                let verb = cmd.split([' ', ':']).next().unwrap().to_ascii_uppercase();
+                let reply = match verb.as_str() {
+                    "EHLO" => "250 stand-in",
+                    "RCPT" => rcpt_reply,
+                    "DATA" => {
+                        out.write_all(b"354 go ahead\r\n").unwrap();
+                        let mut l = Vec::new();
+                        loop {
+                            l.clear();
+                            r.read_until(b'\n', &mut l).unwrap();
+                            if l == b".\r\n" {
+                                break;
+                            }
+                            data.extend_from_slice(l.strip_prefix(b".").unwrap_or(&l));
+                        }
+                        "250 queued as 1"
+                    }
+                    "QUIT" => {
+                        out.write_all(b"221 bye\r\n").unwrap();
+                        commands.push(cmd);
+                        break;
+                    }
+                    _ => "250 ok",
+                };
+                commands.push(cmd);
+                out.write_all(format!("{reply}\r\n").as_bytes()).unwrap();
+            }
+            (commands, data)
+        });
+        (url, handle)
+    }
+
+    fn log_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("zkack-smtp-{}-{name}.jsonl", std::process::id()))
+    }
+
+    #[test]
+    fn envelope_comes_from_headers() {
+        let env = envelope_from_headers(EML).unwrap();
+        assert_eq!(env.mail_from.as_deref(), Some("agency@example.gov"));
+        assert_eq!(
+            env.rcpt_to,
+            ["you@example.com", "audit@example.gov", "hidden@example.gov"]
+        );
+        let with_sender = [&b"Sender: ops@example.gov\r\n"[..], EML].concat();
+        assert_eq!(
+            envelope_from_headers(&with_sender)
+                .unwrap()
+                .mail_from
+                .as_deref(),
+            Some("ops@example.gov")
+        );
+    }
+
+    #[test]
+    fn strip_header_drops_folded_field_only() {
+        let eml =
+            b"Bcc: a@x,\r\n b@x\r\nBcc-Note: keep\r\nbcc : c@x\r\nTo: t@x\r\n\r\nBcc: body\r\n";
+        assert_eq!(
+            strip_header(eml, "Bcc"),
+            b"Bcc-Note: keep\r\nTo: t@x\r\n\r\nBcc: body\r\n"
+        );
+    }
+
+    #[test]
+    fn signed_message_round_trips_through_relay() {
+        let (url, server) = relay("250 ok");
+        let submitter = Submitter::from_url(&url).unwrap();
+        let log = log_path("ok");
+        let (signed, response) = sign_and_submit(
+            &key(),
+            EML,
+            "you@example.com",
+            &opts(),
+            &submitter,
+            None,
+            &log,
+        )
+        .unwrap();
+        assert!(response.starts_with("250"), "{response}");
+        drop(submitter);
+        let (commands, data) = server.join().unwrap();
+        assert!(commands.contains(&"MAIL FROM:<agency@example.gov>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<hidden@example.gov>".to_string()));
+        // the relay got exactly the signed bytes, without Bcc
+        assert_eq!(data, signed.message);
+        assert!(!String::from_utf8_lossy(&data).contains("Bcc"));
+        assert!(data.ends_with(b".leading dot\r\n"));
+
+        let rec: serde_json::Value =
+            serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!(rec["ok"], true);
+        assert_eq!(rec["nonce_b64"], signed.dat.nonce_b64);
+        std::fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn permanent_refusal_is_logged_and_reported() {
+        let (url, _server) = relay("550 no such user");
+        let submitter = Submitter::from_url(&url).unwrap();
+        let log = log_path("refused");
+        let Err(err) = sign_and_submit(
+            &key(),
+            EML,
+            "you@example.com",
+            &opts(),
+            &submitter,
+            None,
+            &log,
+        ) else {
+            panic!("refused submission reported success");
+        };
+        assert!(is_permanent(&err), "{err:#}");
+        let rec: serde_json::Value =
+            serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!(rec["ok"], false);
+        assert!(rec["error"].as_str().unwrap().contains("no such user"));
+        std::fs::remove_file(log).unwrap();
+    }
+}
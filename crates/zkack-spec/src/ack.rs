@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+
+use crate::{b64d, b64e, blake3_b64, JwsHeader};
+
+/// What a receiving domain signs when it ACKs a DAT (recv_domain_sig).
+#[derive(Debug, Serialize)]
+pub struct AckStatement<'a> {
+    pub v: &'static str,
+    /// blake3 of the DAT JWS as posted
+    pub dat_digest_b64: String,
+    /// lowercase, without a trailing dot
+    pub recv_domain: String,
+    pub received_ts: &'a str,
+    pub msg_id: Option<&'a str>,
+    pub dkim_pass: bool,
+}
+
+/// Canonical statement bytes: the JSON of [`AckStatement`] in field order, no whitespace.
+pub fn ack_statement(
+    dat_jws: &str,
+    recv_domain: &str,
+    received_ts: &str,
+    msg_id: Option<&str>,
+    dkim_pass: bool,
+) -> String {
+    serde_json::to_string(&AckStatement {
+        v: "zkack-ack-v1",
+        dat_digest_b64: blake3_b64(dat_jws.as_bytes()),
+        recv_domain: recv_domain.trim_end_matches('.').to_ascii_lowercase(),
+        received_ts,
+        msg_id,
+        dkim_pass,
+    })
+    .unwrap()
+}
+
+/// Sign a statement as a JWS with detached payload (RFC 7515 appendix F): `header..sig`.
+pub fn ack_sign(statement: &str, kid: &str, sk: &SigningKey) -> String {
+    let header = JwsHeader {
+        alg: "EdDSA".into(),
+        kid: kid.into(),
+        jku: None,
+    };
+    let header_b64 = b64e(&serde_json::to_vec(&header).unwrap());
+    let signing_input = format!("{header_b64}.{}", b64e(statement.as_bytes()));
+    let sig: Signature = sk.sign(signing_input.as_bytes());
+    format!("{header_b64}..{}", b64e(&sig.to_bytes()))
+}
+
+/// Header of a recv_domain_sig, for picking the key; the signature is not checked.
+pub fn ack_sig_header(sig: &str) -> Result<JwsHeader> {
+    let (header_b64, _) = split_detached(sig)?;
+    let header: JwsHeader = serde_json::from_slice(&b64d(header_b64)?)?;
+    if header.alg != "EdDSA" {
+        return Err(anyhow!("unsupported alg"));
+    }
+    Ok(header)
+}
+
+/// Check a recv_domain_sig over the statement rebuilt from the ACK.
+pub fn ack_verify(sig: &str, statement: &str, vk: &VerifyingKey) -> Result<()> {
+    let (header_b64, sig_b64) = split_detached(sig)?;
+    let signing_input = format!("{header_b64}.{}", b64e(statement.as_bytes()));
+    let sig = Signature::from_slice(&b64d(sig_b64)?).map_err(|e| anyhow!("sig parse: {e}"))?;
+    vk.verify(signing_input.as_bytes(), &sig)
+        .map_err(|e| anyhow!("verify failed: {e}"))
+}
+
+fn split_detached(sig: &str) -> Result<(&str, &str)> {
+    match sig.split('.').collect::<Vec<_>>()[..] {
+        [h, "", s] => Ok((h, s)),
+        _ => Err(anyhow!(
+            "recv_domain_sig must be a detached JWS (header..signature)"
+        )),
+    }
+}
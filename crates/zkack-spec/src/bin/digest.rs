@@ -1,6 +1,6 @@
 use std::{env, fs};
 
-use zkack_spec::blake3_b64;
+use zkack_spec::{blake3_b64, manifest_digest_b64, part_manifest, DIGEST_ALG_MANIFEST};
 
 /// Extract DKIM body hash (bh=) from DKIM-Signature header (very lightweight parser).
 /// Returns base64url (no padding) to match other token fields.
@@ -16,7 +16,7 @@ fn find_dkim_bh(eml_str: &str) -> Option<String> {
     for line in hdrs.lines() {
         if line.to_ascii_lowercase().starts_with("dkim-signature:") {
             in_dkim = true;
-            val.push_str(line.splitn(2, ':').nth(1).unwrap_or("").trim());
+            val.push_str(line.split_once(':').map_or("", |x| x.1).trim());
             val.push(' ');
             continue;
         }
@@ -56,8 +56,22 @@ fn find_dkim_bh(eml_str: &str) -> Option<String> {
 }
 
 fn main() -> anyhow::Result<()> {
-    let path = env::args().nth(1).expect("usage: digest <path.eml>");
+    let mut args = env::args().skip(1);
+    let path = args.next().expect("usage: digest <path.eml> [--manifest]");
     let eml = fs::read(&path)?;
+
+    if args.next().as_deref() == Some("--manifest") {
+        let parts = part_manifest(&eml)?;
+        println!(
+            "{}",
+            serde_json::json!({
+                "digest_alg": DIGEST_ALG_MANIFEST,
+                "msg_digest_b64": manifest_digest_b64(&parts),
+                "parts": parts,
+            })
+        );
+        return Ok(());
+    }
     let eml_str = String::from_utf8_lossy(&eml);
 
     let (digest_alg, msg_digest_b64) = if let Some(bh) = find_dkim_bh(&eml_str) {
@@ -3,17 +3,36 @@ use serde_json::json;
 use std::fs;
 use zkack_spec::*;
 
+/// Usage: keygen [--jku=<https://host/.well-known/zkack-keys.json>] [domain ...] — the
+/// domains are the key's sender allowlist; --jku is where the issuer will publish
+/// ./keys/zkack-keys.json for verifiers to fetch.
 fn main() -> anyhow::Result<()> {
+    let (jku, domains): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|a| a.starts_with("--jku="));
+    let jku = jku.last().map(|a| a["--jku=".len()..].to_string());
+    if let Some(u) = &jku {
+        // verifiers refuse http:// unless allowed for local testing
+        if !(u.starts_with("https://") || u.starts_with("http://")) || !u.ends_with(JWKS_PATH) {
+            anyhow::bail!("--jku must be https://<host>{JWKS_PATH}");
+        }
+    }
+    if domains.is_empty() {
+        eprintln!("warning: no domains given; the signer refuses keys without a domain allowlist");
+    }
     let (sk, vk) = generate_keypair();
     let kid = format!("dev-{}", uuid::Uuid::new_v4());
     let priv_json = PrivKeyJson {
         kid: kid.clone(),
         sk_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sk.to_bytes()),
         vk_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(vk.to_bytes()),
+        domains: domains.clone(),
+        jku,
     };
     let pub_entry = PubKeyEntry {
         kid: kid.clone(),
         vk_b64: priv_json.vk_b64.clone(),
+        domains,
     };
     fs::create_dir_all("./keys")?;
     fs::write(
@@ -22,12 +41,30 @@ fn main() -> anyhow::Result<()> {
     )?;
     fs::write(
         "./keys/pubkeys.json",
-        serde_json::to_string_pretty(&vec![pub_entry])?,
+        serde_json::to_string_pretty(&vec![&pub_entry])?,
     )?;
+    fs::write(
+        "./keys/zkack-keys.json",
+        serde_json::to_string_pretty(&Jwks {
+            keys: vec![Jwk::from_entry(&pub_entry)],
+        })?,
+    )?;
+    // TXT records for verifiers that look keys up in DNS (dns_ack: as a receiving domain's ACK key)
+    let records = |name: fn(&str, &str) -> anyhow::Result<String>| {
+        pub_entry
+            .domains
+            .iter()
+            .map(|d| Ok(json!({ "name": name(&kid, d)?, "txt": dns_key_record(&vk) })))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    let (dns, dns_ack) = (records(dns_key_name)?, records(dns_ack_key_name)?);
     println!(
         "{}",
         serde_json::to_string_pretty(&json!({
-            "message": "wrote ./keys/dev-priv.json and ./keys/pubkeys.json",
+            "message": "wrote ./keys/dev-priv.json, ./keys/pubkeys.json and ./keys/zkack-keys.json (JWK Set to publish)",
+            "thumbprint": jwk_thumbprint(&vk),
+            "dns": dns,
+            "dns_ack": dns_ack,
             "kid": kid
         }))?
     );
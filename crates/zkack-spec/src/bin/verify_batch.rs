@@ -0,0 +1,41 @@
+use std::{env, fs};
+
+use zkack_spec::{jws_verify_batch, parse_pubkeys};
+
+/// Bulk audit: re-verify a file of DATs (one compact JWS per line) against pubkeys.json.
+/// Prints one JSON line per failure, then a summary.
+fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1);
+    let (Some(pubkeys), Some(path)) = (args.next(), args.next()) else {
+        anyhow::bail!("usage: verify_batch <pubkeys.json> <dats.txt>");
+    };
+    let keys = parse_pubkeys(&fs::read_to_string(&pubkeys)?)?;
+    let text = fs::read_to_string(&path)?;
+    let lines: Vec<(usize, &str)> = text
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty())
+        .collect();
+    let jwss: Vec<&str> = lines.iter().map(|(_, l)| *l).collect();
+
+    let results = jws_verify_batch(&jwss, &|kid| keys.get(kid).cloned());
+    let mut failed = 0usize;
+    for ((line, _), res) in lines.iter().zip(&results) {
+        if let Err(e) = res {
+            failed += 1;
+            println!(
+                "{}",
+                serde_json::json!({ "line": line, "ok": false, "error": e.to_string() })
+            );
+        }
+    }
+    println!(
+        "{}",
+        serde_json::json!({ "total": results.len(), "ok": results.len() - failed, "failed": failed })
+    );
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+
+/// DAT keys in DNS, DKIM-style: TXT `<kid>._zkack.<domain>` holding
+/// `v=ZKACK1; k=ed25519; p=<base64 public key>`. An empty `p=` revokes the key.
+pub const DNS_KEY_LABEL: &str = "_zkack";
+/// Receiving domains publish ACK keys (same record format) at `<kid>._zkack-ack.<domain>`.
+pub const DNS_ACK_KEY_LABEL: &str = "_zkack-ack";
+
+/// Owner name of a DAT key's TXT record; the kid must be a single DNS label.
+pub fn dns_key_name(kid: &str, domain: &str) -> Result<String> {
+    dns_name(kid, DNS_KEY_LABEL, domain)
+}
+
+/// Owner name of a receiving domain's ACK key TXT record.
+pub fn dns_ack_key_name(kid: &str, domain: &str) -> Result<String> {
+    dns_name(kid, DNS_ACK_KEY_LABEL, domain)
+}
+
+fn dns_name(kid: &str, label: &str, domain: &str) -> Result<String> {
+    let label_ok = !kid.is_empty()
+        && kid.len() <= 63
+        && kid
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !label_ok {
+        return Err(anyhow!("kid {kid:?} is not a DNS label"));
+    }
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let domain_ok = !domain.is_empty()
+        && domain.split('.').all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    if !domain_ok {
+        return Err(anyhow!("{domain:?} is not a domain name"));
+    }
+    Ok(format!("{kid}.{label}.{domain}"))
+}
+
+/// TXT record value publishing `vk`.
+pub fn dns_key_record(vk: &VerifyingKey) -> String {
+    let p = base64::engine::general_purpose::STANDARD.encode(vk.to_bytes());
+    format!("v=ZKACK1; k=ed25519; p={p}")
+}
+
+/// Parse a TXT record value; Ok(None) for a revoked key (empty `p=`). Unknown tags are ignored.
+pub fn parse_dns_key_record(txt: &str) -> Result<Option<VerifyingKey>> {
+    let mut tags = txt
+        .split(';')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| t.split_once('=').unwrap_or((t, "")))
+        .map(|(k, v)| (k.trim(), v.trim()));
+    match tags.next() {
+        Some(("v", "ZKACK1")) => {}
+        _ => return Err(anyhow!("not a v=ZKACK1 record")),
+    }
+    let (mut k, mut p) = ("ed25519", None);
+    for (tag, value) in tags {
+        match tag {
+            "k" => k = value,
+            "p" => p = Some(value),
+            _ => {}
+        }
+    }
+    if k != "ed25519" {
+        return Err(anyhow!("unsupported key type k={k}"));
+    }
+    let p: String = p
+        .ok_or_else(|| anyhow!("record has no p="))?
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if p.is_empty() {
+        return Ok(None);
+    }
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(&p)
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&p))
+        .map_err(|e| anyhow!("p=: {e}"))?;
+    let bytes: [u8; 32] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("p= must be a 32-byte Ed25519 key"))?;
+    Ok(Some(VerifyingKey::from_bytes(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vk() -> VerifyingKey {
+        ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]).verifying_key()
+    }
+
+    #[test]
+    fn record_round_trips() {
+        assert_eq!(
+            parse_dns_key_record(&dns_key_record(&vk())).unwrap(),
+            Some(vk())
+        );
+    }
+
+    #[test]
+    fn parser_tolerates_spacing_unknown_tags_and_split_strings() {
+        let p = base64::engine::general_purpose::STANDARD.encode(vk().to_bytes());
+        let (a, b) = p.split_at(20);
+        // TXT character-strings are joined; long values may be split by whitespace
+        let txt = format!(" v=ZKACK1 ;t=y; k = ed25519 ;p={a} {b}; ");
+        assert_eq!(parse_dns_key_record(&txt).unwrap(), Some(vk()));
+    }
+
+    #[test]
+    fn empty_p_is_a_revoked_key() {
+        assert_eq!(
+            parse_dns_key_record("v=ZKACK1; k=ed25519; p=").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn parser_rejects_bad_records() {
+        for bad in [
+            "v=DKIM1; k=ed25519; p=AAAA",
+            "k=ed25519; v=ZKACK1; p=AAAA",
+            "v=ZKACK1; k=rsa; p=AAAA",
+            "v=ZKACK1; k=ed25519",
+            "v=ZKACK1; p=AAAA",
+        ] {
+            assert!(parse_dns_key_record(bad).is_err(), "{bad} accepted");
+        }
+    }
+
+    #[test]
+    fn names_need_a_label_kid_and_a_domain() {
+        assert_eq!(
+            dns_key_name("k1", "Example.GOV.").unwrap(),
+            "k1._zkack.example.gov"
+        );
+        assert_eq!(
+            dns_ack_key_name("k1", "example.com").unwrap(),
+            "k1._zkack-ack.example.com"
+        );
+        assert!(dns_key_name("a.b", "example.gov").is_err());
+        assert!(dns_key_name(&"k".repeat(64), "example.gov").is_err());
+        assert!(dns_key_name("k1", "exa mple.gov").is_err());
+        assert!(dns_key_name("k1", "").is_err());
+    }
+}
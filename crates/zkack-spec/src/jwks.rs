@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::PubKeyEntry;
+
+/// Where an issuer publishes its DAT keys: https://<issuer host>/.well-known/zkack-keys.json
+pub const JWKS_PATH: &str = "/.well-known/zkack-keys.json";
+
+/// JWK Set (RFC 7517) of Ed25519 DAT keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// OKP/Ed25519 public key (RFC 8037) with the key's sender-domain allowlist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub kid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, rename = "use", skip_serializing_if = "Option::is_none")]
+    pub use_: Option<String>,
+    /// Sender domains this key may sign for (subdomains included).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zkack_domains: Vec<String>,
+}
+
+impl Jwk {
+    pub fn from_entry(e: &PubKeyEntry) -> Jwk {
+        Jwk {
+            kty: "OKP".into(),
+            crv: "Ed25519".into(),
+            x: e.vk_b64.clone(),
+            kid: e.kid.clone(),
+            alg: Some("EdDSA".into()),
+            use_: Some("sig".into()),
+            zkack_domains: e.domains.clone(),
+        }
+    }
+
+    /// The Ed25519 key; other key types, curves, algs and uses are refused.
+    pub fn verifying_key(&self) -> Result<VerifyingKey> {
+        if self.kty != "OKP" || self.crv != "Ed25519" {
+            return Err(anyhow!("jwk {}: not an OKP/Ed25519 key", self.kid));
+        }
+        if self.alg.as_deref().is_some_and(|a| a != "EdDSA")
+            || self.use_.as_deref().is_some_and(|u| u != "sig")
+        {
+            return Err(anyhow!("jwk {}: not an EdDSA signing key", self.kid));
+        }
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&self.x)?;
+        let bytes: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("jwk {}: x must be 32 bytes", self.kid))?;
+        Ok(VerifyingKey::from_bytes(&bytes)?)
+    }
+}
+
+/// RFC 7638 thumbprint (SHA-256, base64url) of an Ed25519 key; what pins name.
+pub fn jwk_thumbprint(vk: &VerifyingKey) -> String {
+    let x = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(vk.to_bytes());
+    let canonical = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+}
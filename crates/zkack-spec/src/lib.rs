@@ -3,8 +3,20 @@ use base64::Engine;
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use time::OffsetDateTime;
 
+mod manifest;
+pub use manifest::*;
+mod policy;
+pub use policy::*;
+mod jwks;
+pub use jwks::*;
+mod dnskey;
+pub use dnskey::*;
+mod ack;
+pub use ack::*;
+
 /// URL-safe base64 helpers
 fn b64e(input: &[u8]) -> String {
     base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(input)
@@ -13,35 +25,54 @@ fn b64d(input: &str) -> Result<Vec<u8>> {
     Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(input)?)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Policy {
-    pub ack_by_secs: u64,
-    pub fallbacks: Vec<String>,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatPayload {
     pub v: u8,
     pub salt_b64: String,       // 32B random salt (base64url)
     pub addr_hash_b64: String,  // H(salt || addr) -> base64url (placeholder hash)
     pub msg_digest_b64: String, // message digest -> base64url
-    pub digest_alg: String,     // "blake3" (skeleton; swap for DKIM bh later)
+    pub digest_alg: String,     // "blake3" | "dkim-bh" | "manifest" | "manifest-sd"
     pub exp: String,            // ISO8601 UTC
     pub nonce_b64: String,      // 16-32B
     pub policy: Policy,
+    /// Per-MIME-part digests; present when digest_alg == "manifest".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts: Option<Vec<PartDigest>>,
+    /// Sorted digests of salted part disclosures; present when digest_alg == "manifest-sd".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts_sd: Option<Vec<String>>,
+    /// Normalized From address the DAT was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Domain of `sender`; must align with the issuing key's allowed domains.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_domain: Option<String>,
+    /// Short code printed in the message's visible verification footer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_code: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JwsHeader {
     pub alg: String, // "EdDSA"
     pub kid: String, // key id
+    /// Issuer's JWK Set URL (https://<host>/.well-known/zkack-keys.json) for verifiers
+    /// that do not hold the key yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jku: Option<String>,
 }
 
 /// Compact JWS: base64url(header).base64url(payload).base64url(signature)
 pub fn jws_sign(payload_json: &str, kid: &str, sk: &SigningKey) -> String {
+    jws_sign_jku(payload_json, kid, None, sk)
+}
+
+/// `jws_sign` with a `jku` header pointing verifiers at the issuer's published keys.
+pub fn jws_sign_jku(payload_json: &str, kid: &str, jku: Option<&str>, sk: &SigningKey) -> String {
     let header = JwsHeader {
         alg: "EdDSA".into(),
         kid: kid.into(),
+        jku: jku.map(String::from),
     };
     let header_b64 = b64e(&serde_json::to_vec(&header).unwrap());
     let payload_b64 = b64e(payload_json.as_bytes());
@@ -51,10 +82,15 @@ pub fn jws_sign(payload_json: &str, kid: &str, sk: &SigningKey) -> String {
     format!("{}.{}", signing_input, sig_b64)
 }
 
-pub fn jws_verify(
-    jws: &str,
-    get_vk: &dyn Fn(&str) -> Option<VerifyingKey>,
-) -> Result<(JwsHeader, DatPayload)> {
+/// A compact JWS split into its decoded parts, not yet signature-checked.
+struct ParsedJws {
+    header: JwsHeader,
+    payload_json: String,
+    signing_input: String,
+    sig: Signature,
+}
+
+fn jws_parse(jws: &str) -> Result<ParsedJws> {
     let parts: Vec<&str> = jws.split('.').collect();
     if parts.len() != 3 {
         return Err(anyhow!("bad jws format"));
@@ -65,14 +101,113 @@ pub fn jws_verify(
         return Err(anyhow!("unsupported alg"));
     }
     let payload_json = String::from_utf8(b64d(parts[1])?)?;
-    let vk = get_vk(&header.kid).ok_or_else(|| anyhow!("unknown kid"))?;
     let signing_input = format!("{}.{}", parts[0], parts[1]);
     let sig_bytes = b64d(parts[2])?;
     let sig = Signature::from_slice(&sig_bytes).map_err(|e| anyhow!("sig parse: {}", e))?;
-    vk.verify(signing_input.as_bytes(), &sig)
+    Ok(ParsedJws {
+        header,
+        payload_json,
+        signing_input,
+        sig,
+    })
+}
+
+impl ParsedJws {
+    fn into_verified(self) -> Result<(JwsHeader, DatPayload)> {
+        let payload: DatPayload = serde_json::from_str(&self.payload_json)?;
+        Ok((self.header, payload))
+    }
+}
+
+pub fn jws_verify(
+    jws: &str,
+    get_vk: &dyn Fn(&str) -> Option<VerifyingKey>,
+) -> Result<(JwsHeader, DatPayload)> {
+    let parsed = jws_parse(jws)?;
+    let vk = get_vk(&parsed.header.kid).ok_or_else(|| anyhow!("unknown kid"))?;
+    vk.verify(parsed.signing_input.as_bytes(), &parsed.sig)
         .map_err(|e| anyhow!("verify failed: {}", e))?;
-    let payload: DatPayload = serde_json::from_str(&payload_json)?;
-    Ok((header, payload))
+    parsed.into_verified()
+}
+
+/// The kid a JWS claims, without checking the signature (display and routing only).
+pub fn jws_kid(jws: &str) -> Option<String> {
+    jws_header(jws).ok().map(|h| h.kid)
+}
+
+/// The JWS header, without checking the signature (key lookup only).
+pub fn jws_header(jws: &str) -> Result<JwsHeader> {
+    jws_parse(jws).map(|p| p.header)
+}
+
+/// Header and payload, without checking the signature (key lookup only).
+pub fn jws_peek(jws: &str) -> Result<(JwsHeader, DatPayload)> {
+    jws_parse(jws)?.into_verified()
+}
+
+/// Number of signatures checked per Ed25519 batch equation.
+pub const JWS_BATCH_CHUNK: usize = 256;
+
+/// Verify many compact JWS DATs at once (bulk audits).
+///
+/// Signatures are checked in chunks of `JWS_BATCH_CHUNK` with Ed25519 batch
+/// verification. A batch only says "something in here is bad", so when a chunk
+/// fails every item in it is re-checked individually to pinpoint the failures.
+/// Results are returned in input order, one per input.
+///
+/// The batch equation is cofactored while `jws_verify` is not, so a signature
+/// crafted with small-order components can pass here and fail `jws_verify`;
+/// honestly generated signatures get the same answer from both. Use this for
+/// audits and re-check with `jws_verify` wherever the result gates acceptance.
+pub fn jws_verify_batch<S: AsRef<str>>(
+    jwss: &[S],
+    get_vk: &dyn Fn(&str) -> Option<VerifyingKey>,
+) -> Vec<Result<(JwsHeader, DatPayload)>> {
+    let mut results: Vec<Option<Result<(JwsHeader, DatPayload)>>> =
+        jwss.iter().map(|_| None).collect();
+
+    // Parse + key lookup up front; anything failing here never enters a batch.
+    let mut pending: Vec<(usize, ParsedJws, VerifyingKey)> = Vec::with_capacity(jwss.len());
+    for (i, jws) in jwss.iter().enumerate() {
+        let parsed = match jws_parse(jws.as_ref()) {
+            Ok(p) => p,
+            Err(e) => {
+                results[i] = Some(Err(e));
+                continue;
+            }
+        };
+        match get_vk(&parsed.header.kid) {
+            Some(vk) => pending.push((i, parsed, vk)),
+            None => results[i] = Some(Err(anyhow!("unknown kid"))),
+        }
+    }
+
+    let mut pending = pending.into_iter().peekable();
+    while pending.peek().is_some() {
+        let chunk: Vec<_> = pending.by_ref().take(JWS_BATCH_CHUNK).collect();
+        let msgs: Vec<&[u8]> = chunk
+            .iter()
+            .map(|(_, p, _)| p.signing_input.as_bytes())
+            .collect();
+        let sigs: Vec<Signature> = chunk.iter().map(|(_, p, _)| p.sig).collect();
+        let vks: Vec<VerifyingKey> = chunk.iter().map(|(_, _, vk)| *vk).collect();
+        let batch_ok = ed25519_dalek::verify_batch(&msgs, &sigs, &vks).is_ok();
+
+        for (i, parsed, vk) in chunk {
+            let verified = if batch_ok {
+                Ok(())
+            } else {
+                vk.verify(parsed.signing_input.as_bytes(), &parsed.sig)
+                    .map_err(|e| anyhow!("verify failed: {}", e))
+            };
+            results[i] = Some(verified.and_then(|_| parsed.into_verified()));
+        }
+    }
+
+    results
+        .into_iter()
+        .map(|r| r.expect("every batch input gets a result"))
+        .collect()
 }
 
 /// Compute blake3 digest and return base64url
@@ -81,6 +216,50 @@ pub fn blake3_b64(data: &[u8]) -> String {
     b64e(hash.as_bytes())
 }
 
+/// Canonical recipient address for addr_hash: trimmed, angle brackets removed, lowercased.
+pub fn normalize_addr(addr: &str) -> String {
+    addr.trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+        .to_lowercase()
+}
+
+/// Every X-ZK-DAT on a message, in header order, folding whitespace removed.
+/// A message can carry several (countersigned, e.g. agency and delivery vendor).
+pub fn dat_headers(eml: &[u8]) -> Vec<String> {
+    let Ok((headers, _)) = mailparse::parse_headers(eml) else {
+        return Vec::new();
+    };
+    headers
+        .iter()
+        .filter(|h| h.get_key_ref().eq_ignore_ascii_case("X-ZK-DAT"))
+        .map(|h| h.get_value().split_whitespace().collect())
+        .collect()
+}
+
+/// Canonical verification code for lookup: Crockford base32 read leniently (case,
+/// dashes and spaces ignored; I/L read as 1, O as 0).
+pub fn normalize_verify_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| match c.to_ascii_uppercase() {
+            'I' | 'L' => '1',
+            'O' => '0',
+            c => c,
+        })
+        .collect()
+}
+
+/// Relaxed alignment: `domain` equals an allowed domain or is a subdomain of one.
+pub fn domain_aligned(domain: &str, allowed: &[String]) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    allowed.iter().any(|a| {
+        let a = a.trim_end_matches('.').to_ascii_lowercase();
+        domain == a || domain.ends_with(&format!(".{a}"))
+    })
+}
+
 /// Compute addr_hash = blake3(salt || addr)  (placeholder for Poseidon)
 pub fn addr_hash_b64(salt: &[u8], addr: &str) -> String {
     let mut ctx = blake3::Hasher::new();
@@ -111,9 +290,121 @@ pub struct PrivKeyJson {
     pub kid: String,
     pub sk_b64: String,
     pub vk_b64: String,
+    /// Sender domains this key may sign for (subdomains included).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+    /// Where the issuer publishes this key (JWK Set URL); sent as the DAT's jku header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jku: Option<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PubKeyEntry {
     pub kid: String,
     pub vk_b64: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+}
+
+/// Parse a pubkeys.json array (`[{kid, vk_b64}]`) into a kid -> key map.
+pub fn parse_pubkeys(json: &str) -> Result<HashMap<String, VerifyingKey>> {
+    let entries: Vec<PubKeyEntry> = serde_json::from_str(json)?;
+    let mut map = HashMap::new();
+    for e in entries {
+        let bytes = b64d(&e.vk_b64)?;
+        let vk_bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| anyhow!("vk len"))?;
+        let vk = VerifyingKey::from_bytes(&vk_bytes)?;
+        map.insert(e.kid, vk);
+    }
+    Ok(map)
+}
+
+/// Allowed sender domains per kid from pubkeys.json (keys without a list are omitted).
+pub fn parse_pubkey_domains(json: &str) -> Result<HashMap<String, Vec<String>>> {
+    let entries: Vec<PubKeyEntry> = serde_json::from_str(json)?;
+    Ok(entries
+        .into_iter()
+        .filter(|e| !e.domains.is_empty())
+        .map(|e| (e.kid, e.domains))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dat(n: usize) -> String {
+        let payload = DatPayload {
+            v: 1,
+            salt_b64: b64e(&[0; 32]),
+            addr_hash_b64: b64e(&[1; 32]),
+            msg_digest_b64: b64e(&[2; 32]),
+            digest_alg: "blake3".into(),
+            exp: "2099-01-01T00:00:00Z".into(),
+            nonce_b64: b64e(&(n as u64).to_be_bytes()),
+            policy: Policy::new(900, default_fallbacks()),
+            parts: None,
+            parts_sd: None,
+            sender: None,
+            sender_domain: None,
+            verify_code: None,
+        };
+        serde_json::to_string(&payload).unwrap()
+    }
+
+    /// Swap the payload for another one while keeping the original signature.
+    fn tamper(jws: &str, other: &str) -> String {
+        let p: Vec<&str> = jws.split('.').collect();
+        let q: Vec<&str> = other.split('.').collect();
+        format!("{}.{}.{}", p[0], q[1], p[2])
+    }
+
+    #[test]
+    fn batch_matches_single_verification() {
+        let sk = SigningKey::from_bytes(&[9; 32]);
+        let vk = sk.verifying_key();
+        let jwss: Vec<String> = (0..300).map(|n| jws_sign(&dat(n), "k1", &sk)).collect();
+        let results = jws_verify_batch(&jwss, &|kid| (kid == "k1").then_some(vk));
+        assert_eq!(results.len(), 300);
+        for (n, r) in results.iter().enumerate() {
+            let (header, payload) = r.as_ref().unwrap();
+            assert_eq!(header.kid, "k1");
+            assert_eq!(payload.nonce_b64, b64e(&(n as u64).to_be_bytes()));
+        }
+        assert!(jws_verify_batch::<&str>(&[], &|_| Some(vk)).is_empty());
+    }
+
+    #[test]
+    fn failed_chunk_pinpoints_the_bad_items() {
+        let sk = SigningKey::from_bytes(&[9; 32]);
+        let vk = sk.verifying_key();
+        let mut jwss: Vec<String> = (0..2 * JWS_BATCH_CHUNK)
+            .map(|n| jws_sign(&dat(n), "k1", &sk))
+            .collect();
+        jwss[100] = tamper(&jwss[100], &jwss[101]);
+        jwss[300] = "not.a.jws".into();
+        jwss.push(jws_sign(&dat(0), "k2", &sk));
+        let results = jws_verify_batch(&jwss, &|kid| (kid == "k1").then_some(vk));
+
+        let failed: Vec<usize> = (0..results.len())
+            .filter(|&i| results[i].is_err())
+            .collect();
+        assert_eq!(failed, vec![100, 300, 2 * JWS_BATCH_CHUNK]);
+        let err = |i: usize| results[i].as_ref().err().unwrap().to_string();
+        assert!(err(100).starts_with("verify failed"));
+        assert_eq!(err(2 * JWS_BATCH_CHUNK), "unknown kid");
+        // the rest of the failed chunk still verifies, and agrees with jws_verify
+        for i in [0, 99, 101, JWS_BATCH_CHUNK - 1] {
+            assert!(results[i].is_ok());
+            assert!(jws_verify(&jwss[i], &|_| Some(vk)).is_ok());
+        }
+        assert!(jws_verify(&jwss[100], &|_| Some(vk)).is_err());
+    }
+
+    #[test]
+    fn dat_headers_returns_every_dat_unfolded() {
+        let eml = b"X-ZK-DAT: a.b.c\r\nX-ZK-DAT-SD: sd\r\nSubject: s\r\n\
+x-zk-dat: d.e\r\n .f\r\n\r\nX-ZK-DAT: body.is.ignored\r\n";
+        assert_eq!(dat_headers(eml), ["a.b.c", "d.e.f"]);
+        assert!(dat_headers(b"Subject: s\r\n\r\n").is_empty());
+    }
 }
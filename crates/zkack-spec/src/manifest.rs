@@ -0,0 +1,323 @@
+use anyhow::Result;
+use mailparse::{parse_mail, ParsedMail};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{b64d, b64e, blake3_b64};
+
+/// digest_alg value for DATs that commit to a per-part manifest.
+pub const DIGEST_ALG_MANIFEST: &str = "manifest";
+
+/// One leaf MIME part of a message, hashed after transfer decoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartDigest {
+    pub part_path: String, // IMAP-style section number: "1", "2.1", ...
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub digest: String, // blake3(decoded part body) -> base64url
+}
+
+/// A part digest presented for verification, e.g. of an extracted attachment.
+/// Without `part_path` it is matched against any committed part.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartClaim {
+    pub part_path: Option<String>,
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartCheck {
+    pub part_path: Option<String>,
+    pub status: String, // "match" | "mismatch" | "unknown_part" | "no_match"
+}
+
+/// Build the manifest of leaf MIME parts of a raw message.
+pub fn part_manifest(eml: &[u8]) -> Result<Vec<PartDigest>> {
+    let parsed = parse_mail(eml)?;
+    let mut out = Vec::new();
+    walk(&parsed, String::new(), &mut out)?;
+    Ok(out)
+}
+
+fn walk(part: &ParsedMail, path: String, out: &mut Vec<PartDigest>) -> Result<()> {
+    if !part.subparts.is_empty() {
+        for (i, sub) in part.subparts.iter().enumerate() {
+            let child = if path.is_empty() {
+                format!("{}", i + 1)
+            } else {
+                format!("{}.{}", path, i + 1)
+            };
+            walk(sub, child, out)?;
+        }
+        return Ok(());
+    }
+    let filename = part
+        .get_content_disposition()
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned();
+    out.push(PartDigest {
+        part_path: if path.is_empty() { "1".into() } else { path },
+        content_type: part.ctype.mimetype.clone(),
+        filename,
+        digest: blake3_b64(&canonical_body(part)?),
+    });
+    Ok(())
+}
+
+/// Decoded part body; text/* is put in MIME canonical form (CRLF line breaks,
+/// RFC 2049) so LF/CRLF conversion in transit does not change its digest.
+fn canonical_body(part: &ParsedMail) -> Result<Vec<u8>> {
+    let body = part.get_body_raw()?;
+    if !part.ctype.mimetype.starts_with("text/") {
+        return Ok(body);
+    }
+    let mut out = Vec::with_capacity(body.len());
+    for (i, &b) in body.iter().enumerate() {
+        if b == b'\n' && (i == 0 || body[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    Ok(out)
+}
+
+/// Digest of the manifest itself; this is the DAT's msg_digest_b64 in manifest mode.
+pub fn manifest_digest_b64(parts: &[PartDigest]) -> String {
+    blake3_b64(&serde_json::to_vec(parts).expect("manifest serializes"))
+}
+
+/// Check presented part digests against the manifest committed in a DAT.
+pub fn check_parts(committed: &[PartDigest], claims: &[PartClaim]) -> Vec<PartCheck> {
+    claims
+        .iter()
+        .map(|c| match &c.part_path {
+            Some(p) => {
+                let status = match committed.iter().find(|d| &d.part_path == p) {
+                    Some(d) if d.digest == c.digest => "match",
+                    Some(_) => "mismatch",
+                    None => "unknown_part",
+                };
+                PartCheck {
+                    part_path: Some(p.clone()),
+                    status: status.into(),
+                }
+            }
+            None => match committed.iter().find(|d| d.digest == c.digest) {
+                Some(d) => PartCheck {
+                    part_path: Some(d.part_path.clone()),
+                    status: "match".into(),
+                },
+                None => PartCheck {
+                    part_path: None,
+                    status: "no_match".into(),
+                },
+            },
+        })
+        .collect()
+}
+
+/// digest_alg value for DATs that commit to salted per-part disclosures (SD-JWT style).
+pub const DIGEST_ALG_MANIFEST_SD: &str = "manifest-sd";
+
+/// Turn each part into a salted disclosure: base64url(JSON `[salt_b64, part]`).
+/// The holder keeps these; the DAT only carries their digests.
+pub fn make_disclosures(parts: &[PartDigest]) -> Vec<String> {
+    parts
+        .iter()
+        .map(|p| {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let arr = serde_json::json!([b64e(&salt), p]);
+            b64e(&serde_json::to_vec(&arr).expect("disclosure serializes"))
+        })
+        .collect()
+}
+
+/// Commitment to one disclosure: blake3 over its base64url text.
+pub fn disclosure_digest(disclosure: &str) -> String {
+    blake3_b64(disclosure.as_bytes())
+}
+
+/// Sorted disclosure digests (sorting hides part order); goes in `DatPayload.parts_sd`.
+pub fn sd_commitments(disclosures: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = disclosures.iter().map(|d| disclosure_digest(d)).collect();
+    out.sort();
+    out
+}
+
+/// Digest of the commitment list; this is the DAT's msg_digest_b64 in manifest-sd mode.
+pub fn sd_digest_b64(commitments: &[String]) -> String {
+    blake3_b64(&serde_json::to_vec(commitments).expect("commitments serialize"))
+}
+
+/// Decode a disclosure back into its part (does not check it is committed).
+pub fn open_disclosure(disclosure: &str) -> Result<PartDigest> {
+    let (_salt, part): (String, PartDigest) = serde_json::from_slice(&b64d(disclosure)?)?;
+    Ok(part)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisclosureCheck {
+    pub part: Option<PartDigest>,
+    pub status: String, // "disclosed" | "not_committed" | "malformed"
+}
+
+/// Check disclosures presented by a holder against the commitments in a DAT.
+pub fn check_disclosures(commitments: &[String], disclosures: &[String]) -> Vec<DisclosureCheck> {
+    disclosures
+        .iter()
+        .map(|d| match open_disclosure(d) {
+            Err(_) => DisclosureCheck {
+                part: None,
+                status: "malformed".into(),
+            },
+            Ok(part) => {
+                let status = if commitments.contains(&disclosure_digest(d)) {
+                    "disclosed"
+                } else {
+                    "not_committed"
+                };
+                DisclosureCheck {
+                    part: Some(part),
+                    status: status.into(),
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EML: &[u8] = b"From: a@example.gov\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"XX\"\r\n\
+\r\n\
+--XX\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Hello\r\n\
+--XX\r\n\
+Content-Type: application/pdf; name=\"notice.pdf\"\r\n\
+Content-Disposition: attachment; filename=\"notice.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0xLjQ=\r\n\
+--XX--\r\n";
+
+    #[test]
+    fn manifest_lists_leaf_parts() {
+        let parts = part_manifest(EML).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].part_path, "1");
+        assert_eq!(parts[0].content_type, "text/plain");
+        assert_eq!(parts[1].part_path, "2");
+        assert_eq!(parts[1].filename.as_deref(), Some("notice.pdf"));
+        // attachments are hashed after transfer decoding
+        assert_eq!(parts[1].digest, blake3_b64(b"%PDF-1.4"));
+    }
+
+    #[test]
+    fn manifest_survives_line_ending_conversion() {
+        let lf: Vec<u8> = String::from_utf8_lossy(EML)
+            .replace("\r\n", "\n")
+            .into_bytes();
+        let a = part_manifest(EML).unwrap();
+        let b = part_manifest(&lf).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(manifest_digest_b64(&a), manifest_digest_b64(&b));
+    }
+
+    #[test]
+    fn manifest_round_trips_through_json() {
+        let parts = part_manifest(EML).unwrap();
+        let back: Vec<PartDigest> =
+            serde_json::from_slice(&serde_json::to_vec(&parts).unwrap()).unwrap();
+        assert_eq!(manifest_digest_b64(&back), manifest_digest_b64(&parts));
+    }
+
+    #[test]
+    fn check_parts_reports_each_claim() {
+        let parts = part_manifest(EML).unwrap();
+        let claim = |path: Option<&str>, digest: &str| PartClaim {
+            part_path: path.map(str::to_string),
+            digest: digest.to_string(),
+        };
+        let checks = check_parts(
+            &parts,
+            &[
+                claim(Some("2"), &parts[1].digest),
+                claim(Some("1"), &parts[1].digest),
+                claim(Some("3"), &parts[1].digest),
+                claim(None, &parts[1].digest),
+                claim(None, "nope"),
+            ],
+        );
+        let got: Vec<(Option<&str>, &str)> = checks
+            .iter()
+            .map(|c| (c.part_path.as_deref(), c.status.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (Some("2"), "match"),
+                (Some("1"), "mismatch"),
+                (Some("3"), "unknown_part"),
+                (Some("2"), "match"),
+                (None, "no_match"),
+            ]
+        );
+    }
+
+    #[test]
+    fn disclosures_open_to_their_parts() {
+        let parts = part_manifest(EML).unwrap();
+        let disclosures = make_disclosures(&parts);
+        let opened: Vec<PartDigest> = disclosures
+            .iter()
+            .map(|d| open_disclosure(d).unwrap())
+            .collect();
+        assert_eq!(opened, parts);
+        // fresh salts: the same parts never give the same commitments twice
+        assert_ne!(
+            sd_commitments(&disclosures),
+            sd_commitments(&make_disclosures(&parts))
+        );
+    }
+
+    #[test]
+    fn commitments_hide_part_order() {
+        let parts = part_manifest(EML).unwrap();
+        let disclosures = make_disclosures(&parts);
+        let reversed: Vec<String> = disclosures.iter().rev().cloned().collect();
+        let commitments = sd_commitments(&disclosures);
+        assert_eq!(commitments, sd_commitments(&reversed));
+        assert_eq!(
+            sd_digest_b64(&commitments),
+            sd_digest_b64(&sd_commitments(&reversed))
+        );
+    }
+
+    #[test]
+    fn check_disclosures_reports_each_disclosure() {
+        let parts = part_manifest(EML).unwrap();
+        let disclosures = make_disclosures(&parts);
+        let commitments = sd_commitments(&disclosures[..1]);
+        let checks = check_disclosures(
+            &commitments,
+            &[
+                disclosures[0].clone(),
+                disclosures[1].clone(),
+                "not base64!".to_string(),
+            ],
+        );
+        let got: Vec<&str> = checks.iter().map(|c| c.status.as_str()).collect();
+        assert_eq!(got, ["disclosed", "not_committed", "malformed"]);
+        assert_eq!(checks[0].part.as_ref(), Some(&parts[0]));
+        assert!(checks[2].part.is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Fallback channels a DAT may name.
+pub const FALLBACK_CHANNELS: &[&str] = &["portal", "sms", "email", "phone", "postal"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Policy {
+    pub ack_by_secs: u64,
+    /// Fallback channels, in escalation order.
+    pub fallbacks: Vec<String>,
+    /// Delay and contact reference per fallback, same order as `fallbacks`
+    /// (absent in DATs issued before escalation steps existed).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub escalation: Vec<FallbackStep>,
+}
+
+/// One step of the escalation path after a missed ACK deadline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackStep {
+    pub channel: String,
+    /// Seconds after the ACK deadline before this channel is used.
+    #[serde(default)]
+    pub after_secs: u64,
+    /// Channel-specific contact reference: portal URL, email address, SMS/phone number or short code, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+}
+
+/// `channel[:after_secs][=contact]`, e.g. `portal=https://portal.example.gov/ack` or `sms:3600`.
+impl FromStr for FallbackStep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (head, contact) = match s.split_once('=') {
+            Some((h, c)) => (h, Some(c.to_string())),
+            None => (s, None),
+        };
+        let (channel, after_secs) = match head.split_once(':') {
+            Some((c, a)) => (
+                c,
+                a.parse()
+                    .map_err(|_| anyhow!("fallback {s}: bad delay {a:?}"))?,
+            ),
+            None => (head, 0),
+        };
+        Ok(FallbackStep {
+            channel: channel.trim().to_ascii_lowercase(),
+            after_secs,
+            contact,
+        })
+    }
+}
+
+/// The escalation path used when none is configured: portal, then SMS.
+pub fn default_fallbacks() -> Vec<FallbackStep> {
+    ["portal", "sms"]
+        .iter()
+        .map(|c| FallbackStep {
+            channel: c.to_string(),
+            after_secs: 0,
+            contact: None,
+        })
+        .collect()
+}
+
+impl Policy {
+    pub fn new(ack_by_secs: u64, escalation: Vec<FallbackStep>) -> Self {
+        Policy {
+            ack_by_secs,
+            fallbacks: escalation.iter().map(|s| s.channel.clone()).collect(),
+            escalation,
+        }
+    }
+
+    /// Check the policy against the schema: known channels, each at most once,
+    /// non-decreasing delays and well-formed contact references.
+    pub fn validate(&self) -> Result<()> {
+        if self.ack_by_secs == 0 {
+            anyhow::bail!("policy: ack_by_secs must be positive");
+        }
+        if self.fallbacks.is_empty() {
+            anyhow::bail!("policy: at least one fallback channel is required");
+        }
+        for (i, c) in self.fallbacks.iter().enumerate() {
+            if !FALLBACK_CHANNELS.contains(&c.as_str()) {
+                anyhow::bail!(
+                    "policy: unknown fallback channel {c:?} (expected one of {})",
+                    FALLBACK_CHANNELS.join(", ")
+                );
+            }
+            if self.fallbacks[..i].contains(c) {
+                anyhow::bail!("policy: fallback channel {c} listed twice");
+            }
+        }
+        if self.escalation.is_empty() {
+            return Ok(());
+        }
+        if !self
+            .escalation
+            .iter()
+            .map(|s| &s.channel)
+            .eq(&self.fallbacks)
+        {
+            anyhow::bail!("policy: escalation steps do not match fallbacks");
+        }
+        for w in self.escalation.windows(2) {
+            if w[1].after_secs < w[0].after_secs {
+                anyhow::bail!(
+                    "policy: {} (after {}s) escalates before {} (after {}s)",
+                    w[1].channel,
+                    w[1].after_secs,
+                    w[0].channel,
+                    w[0].after_secs
+                );
+            }
+        }
+        for s in &self.escalation {
+            if let Some(c) = &s.contact {
+                check_contact(&s.channel, c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_contact(channel: &str, contact: &str) -> Result<()> {
+    if contact.trim().is_empty() || contact.len() > 256 || contact.chars().any(char::is_control) {
+        anyhow::bail!("policy: {channel} contact must be 1-256 printable characters");
+    }
+    let ok = match channel {
+        "portal" => contact.starts_with("https://"),
+        "email" => contact
+            .split_once('@')
+            .is_some_and(|(l, d)| !l.is_empty() && d.contains('.')),
+        "sms" | "phone" => {
+            let digits = contact.strip_prefix('+').unwrap_or(contact);
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+        }
+        _ => true,
+    };
+    if !ok {
+        anyhow::bail!(
+            "policy: bad {channel} contact {contact:?} ({})",
+            match channel {
+                "portal" => "expected an https:// URL",
+                "email" => "expected an email address",
+                _ => "expected a phone number or short code",
+            }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(specs: &[&str]) -> Vec<FallbackStep> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn fallback_step_parses_delay_and_contact() {
+        let s: FallbackStep = "SMS:3600=+15550100".parse().unwrap();
+        assert_eq!(
+            s,
+            FallbackStep {
+                channel: "sms".into(),
+                after_secs: 3600,
+                contact: Some("+15550100".into()),
+            }
+        );
+        // the contact may itself contain ':' and '='
+        let s: FallbackStep = "portal=https://portal.example.gov/ack?a=b".parse().unwrap();
+        assert_eq!(s.after_secs, 0);
+        assert_eq!(
+            s.contact.as_deref(),
+            Some("https://portal.example.gov/ack?a=b")
+        );
+        assert!("sms:soon".parse::<FallbackStep>().is_err());
+    }
+
+    #[test]
+    fn validate_accepts_default_and_sample_policies() {
+        Policy::new(900, default_fallbacks()).validate().unwrap();
+        Policy::new(
+            86400,
+            steps(&[
+                "portal=https://portal.example.gov/ack",
+                "sms:3600=+15550100",
+                "postal:604800",
+            ]),
+        )
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_bad_policies() {
+        let bad = [
+            (0, vec!["portal"]),
+            (900, vec![]),
+            (900, vec!["fax"]),
+            (900, vec!["sms", "sms:60"]),
+            (900, vec!["sms:60", "portal:30"]),
+            (900, vec!["portal=http://portal.example.gov"]),
+            (900, vec!["email=nobody"]),
+            (900, vec!["sms=call me"]),
+        ];
+        for (ack_by_secs, specs) in bad {
+            let policy = Policy::new(ack_by_secs, steps(&specs));
+            assert!(policy.validate().is_err(), "{specs:?} accepted");
+        }
+    }
+
+    #[test]
+    fn policy_without_escalation_still_parses() {
+        // DATs issued before escalation steps existed
+        let p: Policy =
+            serde_json::from_str(r#"{"ack_by_secs":900,"fallbacks":["portal","sms"]}"#).unwrap();
+        assert!(p.escalation.is_empty());
+        p.validate().unwrap();
+        assert_eq!(
+            serde_json::to_string(&p).unwrap(),
+            r#"{"ack_by_secs":900,"fallbacks":["portal","sms"]}"#
+        );
+    }
+}
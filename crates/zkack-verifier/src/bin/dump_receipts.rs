@@ -1,7 +1,12 @@
 use anyhow::Result;
 
+/// Usage: dump_receipts [db_path] — defaults to $ZKACK_DB_PATH, else the verifier's default.
 fn main() -> Result<()> {
-    let db = sled::open("./data/receipts")?;
+    let path = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("ZKACK_DB_PATH").ok())
+        .unwrap_or_else(|| "./data/receipts/db".into());
+    let db = sled::open(path)?;
     let mut n = 0usize;
     for kv in db.iter() {
         let (_k, v) = kv?;
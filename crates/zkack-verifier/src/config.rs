@@ -0,0 +1,591 @@
+//! Verifier configuration. Each setting comes from the command line, else its
+//! ZKACK_* environment variable, else the TOML file at --config, else the default.
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use zkack_circuits::ProofSystemId;
+
+const DEFAULT_KEYS: &str = "./keys/pubkeys.json";
+const DEFAULT_DB_PATH: &str = "./data/receipts/db";
+const DEFAULT_PORT: u16 = 8787;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// TOML config file (relative paths in it are resolved against its directory)
+    #[arg(long, env = "ZKACK_CONFIG")]
+    config: Option<PathBuf>,
+    /// Public key set JSON; repeatable, the sets are merged [default: ./keys/pubkeys.json]
+    #[arg(long, env = "ZKACK_PUBKEYS", value_delimiter = ',')]
+    keys: Vec<PathBuf>,
+    /// Listen address, repeatable [default: 127.0.0.1:<port>]
+    #[arg(long, env = "ZKACK_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+    /// Port of the default 127.0.0.1 listener (unused when --listen is given)
+    #[arg(long, env = "ZKACK_PORT")]
+    port: Option<u16>,
+    /// Receipts database (sled directory) [default: ./data/receipts/db]
+    #[arg(long, env = "ZKACK_DB_PATH")]
+    db_path: Option<PathBuf>,
+    /// Older spelling: the database is <dir>/db
+    #[arg(long, env = "ZKACK_DB_DIR", hide = true)]
+    db_dir: Option<PathBuf>,
+    /// Seconds between checks of the key files for changes; 0 = only on SIGHUP [default: 5]
+    #[arg(long, env = "ZKACK_KEY_RELOAD_SECS")]
+    key_reload_secs: Option<u64>,
+    /// TLS certificate chain (PEM); serves HTTPS on every listener, needs --tls-key
+    #[arg(long, env = "ZKACK_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[arg(long, env = "ZKACK_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Largest accepted request body [default: 262144]
+    #[arg(long, env = "ZKACK_MAX_BODY_BYTES")]
+    max_body_bytes: Option<usize>,
+    /// Per-request time limit; slower requests get 408 [default: 30]
+    #[arg(long, env = "ZKACK_REQUEST_TIMEOUT_SECS")]
+    request_timeout_secs: Option<u64>,
+    /// Requests handled at once across all listeners; the rest wait [default: 256]
+    #[arg(long, env = "ZKACK_MAX_CONCURRENT_REQUESTS")]
+    max_concurrent_requests: Option<usize>,
+    /// Issuer whose JWK Set is searched for kids not in the local keys; repeatable.
+    /// An origin (https://example.gov) gets /.well-known/zkack-keys.json appended
+    #[arg(long, env = "ZKACK_JWKS_ISSUERS", value_delimiter = ',')]
+    jwks_issuer: Vec<String>,
+    /// Domain (subdomains included) whose hosts a DAT's jku may name; repeatable
+    #[arg(long, env = "ZKACK_JWKS_ALLOW", value_delimiter = ',')]
+    jwks_allow: Vec<String>,
+    /// HOST=THUMBPRINT: only keys with a pinned RFC 7638 thumbprint are used from HOST; repeatable
+    #[arg(long, env = "ZKACK_JWKS_PINS", value_delimiter = ',')]
+    jwks_pin: Vec<String>,
+    /// Least seconds between fetches of one JWK Set [default: 60]
+    #[arg(long, env = "ZKACK_JWKS_MIN_REFRESH_SECS")]
+    jwks_min_refresh_secs: Option<u64>,
+    /// Longest a fetched JWK Set is used, whatever its Cache-Control says [default: 86400]
+    #[arg(long, env = "ZKACK_JWKS_MAX_CACHE_SECS")]
+    jwks_max_cache_secs: Option<u64>,
+    /// JWK Set fetch time limit [default: 5]
+    #[arg(long, env = "ZKACK_JWKS_TIMEOUT_SECS")]
+    jwks_timeout_secs: Option<u64>,
+    /// Accept http:// JWK Set URLs (local testing only)
+    #[arg(long, env = "ZKACK_JWKS_ALLOW_HTTP")]
+    jwks_allow_http: bool,
+    /// Registered ACK keys of receiving domains (pubkeys.json format, "domains" = the
+    /// receiving domains each key signs for); repeatable, reloaded like --keys
+    #[arg(long, env = "ZKACK_RECEIVER_KEYS", value_delimiter = ',')]
+    receiver_keys: Vec<PathBuf>,
+    /// Refuse ACKs whose recv_domain_sig does not verify (default: store them unauthenticated)
+    #[arg(long, env = "ZKACK_REQUIRE_RECEIVER_AUTH")]
+    require_receiver_auth: bool,
+    /// Proof system ACK proofs are checked with [default: mock]
+    #[arg(long, env = "ZKACK_PROOF_SYSTEM")]
+    proof_system: Option<ProofSystemId>,
+    /// Look kids not found locally up as DNS TXT <kid>._zkack.<sender domain>
+    #[arg(long, env = "ZKACK_DNS_KEYS")]
+    dns_keys: bool,
+    /// Nameserver (ip:port) for key lookups instead of the system resolver
+    #[arg(long, env = "ZKACK_DNS_SERVER")]
+    dns_server: Option<String>,
+    /// Only these domains (subdomains included) may publish keys in DNS; repeatable [default: any]
+    #[arg(long, env = "ZKACK_DNS_ALLOW", value_delimiter = ',')]
+    dns_allow: Vec<String>,
+    /// Shortest time a DNS answer is cached, "no such key" included [default: 60]
+    #[arg(long, env = "ZKACK_DNS_MIN_TTL_SECS")]
+    dns_min_ttl_secs: Option<u64>,
+    /// Longest time a DNS answer is cached [default: 3600]
+    #[arg(long, env = "ZKACK_DNS_MAX_TTL_SECS")]
+    dns_max_ttl_secs: Option<u64>,
+    /// DNS query time limit [default: 5]
+    #[arg(long, env = "ZKACK_DNS_TIMEOUT_SECS")]
+    dns_timeout_secs: Option<u64>,
+    /// Validate and print the effective configuration, then exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    keys: Vec<PathBuf>,
+    #[serde(default)]
+    listen: Vec<String>,
+    key_reload_secs: Option<u64>,
+    db_path: Option<PathBuf>,
+    #[serde(default)]
+    receiver_keys: Vec<PathBuf>,
+    #[serde(default)]
+    require_receiver_auth: bool,
+    proof_system: Option<ProofSystemId>,
+    tls: Option<TlsFile>,
+    #[serde(default)]
+    limits: LimitsFile,
+    #[serde(default)]
+    jwks: JwksFile,
+    #[serde(default)]
+    dns: DnsFile,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsFile {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    max_body_bytes: Option<usize>,
+    request_timeout_secs: Option<u64>,
+    max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JwksFile {
+    #[serde(default)]
+    issuers: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    /// host -> thumbprints
+    #[serde(default)]
+    pins: BTreeMap<String, Vec<String>>,
+    min_refresh_secs: Option<u64>,
+    max_cache_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+    #[serde(default)]
+    allow_http: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DnsFile {
+    #[serde(default)]
+    enabled: bool,
+    server: Option<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    min_ttl_secs: Option<u64>,
+    max_ttl_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+}
+
+/// The effective configuration, printed at startup.
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub keys: Vec<PathBuf>,
+    pub key_reload_secs: u64,
+    pub listen: Vec<SocketAddr>,
+    pub db_path: PathBuf,
+    pub receiver_keys: Vec<PathBuf>,
+    pub require_receiver_auth: bool,
+    pub proof_system: ProofSystemId,
+    pub tls: Option<Tls>,
+    pub limits: Limits,
+    /// None unless issuers or an allowlist are configured
+    pub jwks: Option<JwksConfig>,
+    /// None unless DNS key lookup is enabled
+    pub dns: Option<DnsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsConfig {
+    /// None: the system resolver
+    pub server: Option<SocketAddr>,
+    pub allow: Vec<String>,
+    pub min_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JwksConfig {
+    /// JWK Set URLs
+    pub issuers: Vec<String>,
+    pub allow: Vec<String>,
+    pub pins: BTreeMap<String, Vec<String>>,
+    pub min_refresh_secs: u64,
+    pub max_cache_secs: u64,
+    pub timeout_secs: u64,
+    pub allow_http: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    pub request_timeout_secs: u64,
+    pub max_concurrent_requests: usize,
+}
+
+impl Config {
+    /// Merge command line, environment and config file, then validate.
+    pub fn load(args: Args) -> Result<Config> {
+        let file = match &args.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("config {}: {e}", path.display()))?;
+                let mut file: ConfigFile =
+                    toml::from_str(&raw).map_err(|e| anyhow!("config {}: {e}", path.display()))?;
+                file.resolve_paths(path.parent().unwrap_or(Path::new(".")));
+                file
+            }
+            None => ConfigFile::default(),
+        };
+
+        let keys = first_non_empty(args.keys, file.keys)
+            .unwrap_or_else(|| vec![PathBuf::from(DEFAULT_KEYS)]);
+        let listen = match first_non_empty(args.listen, file.listen) {
+            Some(addrs) => addrs
+                .iter()
+                .map(|a| {
+                    a.trim().parse().map_err(|_| {
+                        anyhow!("listen: bad address {a:?} (want ip:port, e.g. 0.0.0.0:8787)")
+                    })
+                })
+                .collect::<Result<Vec<SocketAddr>>>()?,
+            None => vec![([127, 0, 0, 1], args.port.unwrap_or(DEFAULT_PORT)).into()],
+        };
+        let db_path = args
+            .db_path
+            .or(args.db_dir.map(|d| d.join("db")))
+            .or(file.db_path)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH));
+        let tls = match (args.tls_cert, args.tls_key, file.tls) {
+            (Some(cert), Some(key), _) => Some(Tls { cert, key }),
+            (None, None, Some(t)) => Some(Tls {
+                cert: t.cert,
+                key: t.key,
+            }),
+            (None, None, None) => None,
+            _ => anyhow::bail!("tls: --tls-cert and --tls-key go together"),
+        };
+        let limits = Limits {
+            max_body_bytes: args
+                .max_body_bytes
+                .or(file.limits.max_body_bytes)
+                .unwrap_or(256 * 1024),
+            request_timeout_secs: args
+                .request_timeout_secs
+                .or(file.limits.request_timeout_secs)
+                .unwrap_or(30),
+            max_concurrent_requests: args
+                .max_concurrent_requests
+                .or(file.limits.max_concurrent_requests)
+                .unwrap_or(256),
+        };
+
+        let allow_http = args.jwks_allow_http || file.jwks.allow_http;
+        let issuers = first_non_empty(args.jwks_issuer, file.jwks.issuers)
+            .unwrap_or_default()
+            .iter()
+            .map(|i| crate::jwks::issuer_url(i.trim(), allow_http))
+            .collect::<Result<Vec<_>>>()?;
+        let allow: Vec<String> = first_non_empty(args.jwks_allow, file.jwks.allow)
+            .unwrap_or_default()
+            .iter()
+            .map(|d| d.trim().trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        let pins = if args.jwks_pin.is_empty() {
+            file.jwks.pins
+        } else {
+            let mut pins = BTreeMap::<String, Vec<String>>::new();
+            for p in &args.jwks_pin {
+                let (host, tp) = p
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("jwks pin {p:?}: want HOST=THUMBPRINT"))?;
+                pins.entry(host.trim().into())
+                    .or_default()
+                    .push(tp.trim().into());
+            }
+            pins
+        };
+        let pins = pins
+            .into_iter()
+            .map(|(h, tps)| (h.to_ascii_lowercase(), tps))
+            .collect();
+        let jwks = (!issuers.is_empty() || !allow.is_empty()).then(|| JwksConfig {
+            issuers,
+            allow,
+            pins,
+            min_refresh_secs: args
+                .jwks_min_refresh_secs
+                .or(file.jwks.min_refresh_secs)
+                .unwrap_or(60),
+            max_cache_secs: args
+                .jwks_max_cache_secs
+                .or(file.jwks.max_cache_secs)
+                .unwrap_or(86400),
+            timeout_secs: args
+                .jwks_timeout_secs
+                .or(file.jwks.timeout_secs)
+                .unwrap_or(5),
+            allow_http,
+        });
+
+        let dns = if args.dns_keys || file.dns.enabled {
+            let server = args
+                .dns_server
+                .or(file.dns.server)
+                .map(|s| {
+                    s.trim().parse().map_err(|_| {
+                        anyhow!("dns server: bad address {s:?} (want ip:port, e.g. 127.0.0.1:53)")
+                    })
+                })
+                .transpose()?;
+            Some(DnsConfig {
+                server,
+                allow: first_non_empty(args.dns_allow, file.dns.allow)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|d| d.trim().trim_end_matches('.').to_ascii_lowercase())
+                    .collect(),
+                min_ttl_secs: args
+                    .dns_min_ttl_secs
+                    .or(file.dns.min_ttl_secs)
+                    .unwrap_or(60),
+                max_ttl_secs: args
+                    .dns_max_ttl_secs
+                    .or(file.dns.max_ttl_secs)
+                    .unwrap_or(3600),
+                timeout_secs: args.dns_timeout_secs.or(file.dns.timeout_secs).unwrap_or(5),
+            })
+        } else {
+            None
+        };
+
+        let cfg = Config {
+            keys,
+            key_reload_secs: args.key_reload_secs.or(file.key_reload_secs).unwrap_or(5),
+            listen,
+            db_path,
+            receiver_keys: first_non_empty(args.receiver_keys, file.receiver_keys)
+                .unwrap_or_default(),
+            require_receiver_auth: args.require_receiver_auth || file.require_receiver_auth,
+            proof_system: args
+                .proof_system
+                .or(file.proof_system)
+                .unwrap_or(ProofSystemId::Mock),
+            tls,
+            limits,
+            jwks,
+            dns,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (i, a) in self.listen.iter().enumerate() {
+            if self.listen[..i].contains(a) {
+                anyhow::bail!("listen: {a} given twice");
+            }
+        }
+        for k in self.keys.iter().chain(&self.receiver_keys) {
+            if !k.is_file() {
+                anyhow::bail!("keys: {} is not a file", k.display());
+            }
+        }
+        if let Some(t) = &self.tls {
+            for (what, p) in [("cert", &t.cert), ("key", &t.key)] {
+                std::fs::metadata(p).with_context(|| format!("tls {what} {}", p.display()))?;
+            }
+        }
+        let l = &self.limits;
+        if l.max_body_bytes == 0 || l.request_timeout_secs == 0 || l.max_concurrent_requests == 0 {
+            anyhow::bail!("limits: every limit must be positive");
+        }
+        if let Some(j) = &self.jwks {
+            if j.min_refresh_secs == 0 || j.timeout_secs == 0 {
+                anyhow::bail!("jwks: min_refresh_secs and timeout_secs must be positive");
+            }
+            if j.max_cache_secs < j.min_refresh_secs {
+                anyhow::bail!("jwks: max_cache_secs is below min_refresh_secs");
+            }
+            if let Some((h, _)) = j.pins.iter().find(|(_, tps)| tps.is_empty()) {
+                anyhow::bail!("jwks: pins for {h} are empty");
+            }
+        }
+        if self.require_receiver_auth && self.receiver_keys.is_empty() && self.dns.is_none() {
+            anyhow::bail!("require_receiver_auth: no receiver keys and DNS lookup is off");
+        }
+        if let Some(d) = &self.dns {
+            if d.timeout_secs == 0 || d.max_ttl_secs < d.min_ttl_secs {
+                anyhow::bail!(
+                    "dns: timeout_secs must be positive and max_ttl_secs >= min_ttl_secs"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConfigFile {
+    fn resolve_paths(&mut self, dir: &Path) {
+        let paths = self
+            .keys
+            .iter_mut()
+            .chain(self.receiver_keys.iter_mut())
+            .chain(self.db_path.as_mut())
+            .chain(self.tls.iter_mut().flat_map(|t| [&mut t.cert, &mut t.key]));
+        for p in paths {
+            if p.is_relative() {
+                *p = dir.join(&*p);
+            }
+        }
+    }
+}
+
+fn first_non_empty<T>(a: Vec<T>, b: Vec<T>) -> Option<Vec<T>> {
+    [a, b].into_iter().find(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Args reads ZKACK_* from the process environment; tests touching it take turns.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zkack-config-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for f in ["a.json", "b.json", "c.json"] {
+            std::fs::write(dir.join(f), "[]").unwrap();
+        }
+        dir
+    }
+
+    fn load(flags: &[&str]) -> Result<Config> {
+        Config::load(Args::try_parse_from([&["zkack-verifier"], flags].concat())?)
+    }
+
+    #[test]
+    fn flags_override_env_override_file() {
+        let _env = ENV.lock().unwrap();
+        let dir = dir("precedence");
+        let toml = dir.join("verifier.toml");
+        std::fs::write(
+            &toml,
+            "keys = [\"a.json\"]\nkey_reload_secs = 9\nlisten = [\"127.0.0.1:1001\"]\n\
+             [limits]\nmax_body_bytes = 100\nrequest_timeout_secs = 7\n",
+        )
+        .unwrap();
+        let config = toml.to_str().unwrap();
+
+        let cfg = load(&["--config", config]).unwrap();
+        assert_eq!(cfg.keys, [dir.join("a.json")]);
+        assert_eq!(cfg.listen, ["127.0.0.1:1001".parse().unwrap()]);
+        assert_eq!(cfg.limits.max_body_bytes, 100);
+        assert_eq!(cfg.key_reload_secs, 9);
+        assert_eq!(cfg.limits.max_concurrent_requests, 256);
+
+        std::env::set_var("ZKACK_PUBKEYS", dir.join("b.json"));
+        std::env::set_var("ZKACK_MAX_BODY_BYTES", "200");
+        std::env::set_var("ZKACK_LISTEN", "127.0.0.1:1002,127.0.0.1:1003");
+        let env = load(&["--config", config]);
+        let c = dir.join("c.json");
+        let flags = load(&[
+            "--config",
+            config,
+            "--keys",
+            c.to_str().unwrap(),
+            "--max-body-bytes",
+            "300",
+        ]);
+        for var in ["ZKACK_PUBKEYS", "ZKACK_MAX_BODY_BYTES", "ZKACK_LISTEN"] {
+            std::env::remove_var(var);
+        }
+
+        let env = env.unwrap();
+        assert_eq!(env.keys, [dir.join("b.json")]);
+        assert_eq!(env.limits.max_body_bytes, 200);
+        assert_eq!(env.listen.len(), 2);
+        assert_eq!(env.limits.request_timeout_secs, 7);
+        let flags = flags.unwrap();
+        assert_eq!(flags.keys, [dir.join("c.json")]);
+        assert_eq!(flags.limits.max_body_bytes, 300);
+        assert_eq!(flags.listen.len(), 2);
+        assert_eq!(flags.limits.request_timeout_secs, 7);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn defaults_apply_without_a_file() {
+        let _env = ENV.lock().unwrap();
+        let dir = dir("defaults");
+        let keys = dir.join("a.json");
+        let cfg = load(&["--keys", keys.to_str().unwrap(), "--port", "9000"]).unwrap();
+        assert_eq!(cfg.listen, ["127.0.0.1:9000".parse().unwrap()]);
+        assert_eq!(cfg.db_path, PathBuf::from(DEFAULT_DB_PATH));
+        assert_eq!(cfg.key_reload_secs, 5);
+        assert_eq!(cfg.proof_system, ProofSystemId::Mock);
+        assert!(cfg.tls.is_none() && cfg.jwks.is_none() && cfg.dns.is_none());
+        let old = load(&["--keys", keys.to_str().unwrap(), "--db-dir", "/srv/zk"]).unwrap();
+        assert_eq!(old.db_path, PathBuf::from("/srv/zk/db"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_settings_are_reported() {
+        let _env = ENV.lock().unwrap();
+        let dir = dir("invalid");
+        let keys = dir.join("a.json");
+        let keys = keys.to_str().unwrap();
+        let missing = dir.join("missing.pem");
+        let missing = missing.to_str().unwrap();
+        let cases: &[(&[&str], &str)] = &[
+            (&["--listen", "localhost"], "listen: bad address"),
+            (&["--listen", "127.0.0.1:1,127.0.0.1:1"], "given twice"),
+            (&["--keys", "/nonexistent/keys.json"], "is not a file"),
+            (
+                &["--tls-cert", keys],
+                "--tls-cert and --tls-key go together",
+            ),
+            (&["--tls-cert", missing, "--tls-key", keys], "tls cert"),
+            (&["--max-body-bytes", "0"], "every limit must be positive"),
+            (
+                &["--jwks-allow", "example.gov", "--jwks-max-cache-secs", "10"],
+                "max_cache_secs",
+            ),
+            (
+                &["--jwks-pin", "host-without-thumbprint"],
+                "want HOST=THUMBPRINT",
+            ),
+            (&["--require-receiver-auth"], "no receiver keys"),
+            (
+                &["--dns-keys", "--dns-max-ttl-secs", "1"],
+                "max_ttl_secs >= min_ttl_secs",
+            ),
+            (
+                &["--dns-keys", "--dns-server", "resolver"],
+                "dns server: bad address",
+            ),
+        ];
+        for (flags, want) in cases {
+            let flags = [&["--keys", keys], *flags].concat();
+            let err = format!("{:#}", load(&flags).err().unwrap());
+            assert!(err.contains(want), "{flags:?}: {err}");
+        }
+
+        let toml = dir.join("bad.toml");
+        std::fs::write(&toml, "keys = [\"a.json\"]\nlisen = [\"127.0.0.1:1\"]\n").unwrap();
+        let err = format!(
+            "{:#}",
+            load(&["--config", toml.to_str().unwrap()]).err().unwrap()
+        );
+        assert!(err.contains("unknown field"), "{err}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+//! Issuer keys from DNS. A kid missing from the local key set is looked up as the TXT
+//! record <kid>._zkack.<sender_domain> of the DAT; such a key signs only for that
+//! domain and its subdomains. Answers, "no such key" included, are cached for their
+//! TTL clamped to [min_ttl_secs, max_ttl_secs].
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::VerifyingKey;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use zkack_spec::{dns_ack_key_name, dns_key_name, domain_aligned, parse_dns_key_record};
+
+use crate::config::DnsConfig;
+use crate::jwks::RemoteKey;
+
+/// Cached names; expired ones are dropped first, then the cache is cleared.
+const MAX_ENTRIES: usize = 4096;
+
+/// TXT answer: one string per record (character-strings joined), and how long it holds.
+pub struct TxtAnswer {
+    pub records: Vec<String>,
+    pub ttl: Duration,
+}
+
+/// Where TXT records come from; Ok(None) when the name has none.
+pub trait TxtResolver: Send + Sync {
+    fn txt<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<TxtAnswer>>> + Send + 'a>>;
+}
+
+/// The system resolver, or the nameserver at `server`.
+pub struct HickoryTxt(TokioAsyncResolver);
+
+impl HickoryTxt {
+    pub fn new(server: Option<std::net::SocketAddr>, timeout: Duration) -> Result<HickoryTxt> {
+        // DnsKeys caches (with the configured TTL bounds); the resolver must not
+        let mut opts = ResolverOpts::default();
+        opts.timeout = timeout;
+        opts.attempts = 1;
+        opts.cache_size = 0;
+        let resolver = match server {
+            Some(addr) => TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+                ),
+                opts,
+            ),
+            None => {
+                let (config, mut sys) = hickory_resolver::system_conf::read_system_conf()?;
+                sys.timeout = timeout;
+                sys.attempts = 1;
+                sys.cache_size = 0;
+                TokioAsyncResolver::tokio(config, sys)
+            }
+        };
+        Ok(HickoryTxt(resolver))
+    }
+}
+
+impl TxtResolver for HickoryTxt {
+    fn txt<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<TxtAnswer>>> + Send + 'a>> {
+        Box::pin(async move {
+            // fully qualified: no search domains
+            match self.0.txt_lookup(format!("{name}.")).await {
+                Ok(found) => Ok(Some(TxtAnswer {
+                    records: found
+                        .iter()
+                        .map(|txt| {
+                            txt.txt_data()
+                                .iter()
+                                .map(|s| String::from_utf8_lossy(s))
+                                .collect()
+                        })
+                        .collect(),
+                    ttl: found
+                        .as_lookup()
+                        .valid_until()
+                        .saturating_duration_since(Instant::now()),
+                })),
+                Err(e) => match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { .. } => Ok(None),
+                    _ => Err(anyhow!("{e}")),
+                },
+            }
+        })
+    }
+}
+
+pub struct DnsKeys {
+    cfg: DnsConfig,
+    resolver: Arc<dyn TxtResolver>,
+    cache: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    /// Err holds why there is no usable key (absent, revoked, malformed, lookup failed)
+    key: Result<VerifyingKey, String>,
+    until: Instant,
+}
+
+impl DnsKeys {
+    pub fn new(cfg: DnsConfig, resolver: Arc<dyn TxtResolver>) -> DnsKeys {
+        DnsKeys {
+            cfg,
+            resolver,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The DAT key published for `kid` at `domain`.
+    pub async fn resolve(&self, kid: &str, domain: &str) -> Result<RemoteKey> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if !self.cfg.allow.is_empty() && !domain_aligned(&domain, &self.cfg.allow) {
+            anyhow::bail!("dns: {domain} may not publish keys here");
+        }
+        let name = dns_key_name(kid, &domain)?;
+        self.resolve_name(name, domain).await
+    }
+
+    /// The ACK key a receiving domain published for `kid` (not subject to `allow`).
+    pub async fn resolve_ack(&self, kid: &str, domain: &str) -> Result<RemoteKey> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let name = dns_ack_key_name(kid, &domain)?;
+        self.resolve_name(name, domain).await
+    }
+
+    async fn resolve_name(&self, name: String, domain: String) -> Result<RemoteKey> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&name)
+            .filter(|e| Instant::now() < e.until)
+            .map(|e| e.key.clone());
+        let key = match cached {
+            Some(key) => key,
+            None => {
+                let (key, ttl) = self.lookup(&name).await;
+                let ttl = ttl.clamp(
+                    Duration::from_secs(self.cfg.min_ttl_secs),
+                    Duration::from_secs(self.cfg.max_ttl_secs),
+                );
+                if let Err(e) = &key {
+                    tracing::debug!(name, "dns key lookup: {e}");
+                }
+                let mut cache = self.cache.lock().unwrap();
+                if cache.len() >= MAX_ENTRIES {
+                    let now = Instant::now();
+                    cache.retain(|_, e| now < e.until);
+                    if cache.len() >= MAX_ENTRIES {
+                        cache.clear();
+                    }
+                }
+                cache.insert(
+                    name.clone(),
+                    Entry {
+                        key: key.clone(),
+                        until: Instant::now() + ttl,
+                    },
+                );
+                key
+            }
+        };
+        let vk = key.map_err(|e| anyhow!("dns {name}: {e}"))?;
+        Ok(RemoteKey {
+            vk,
+            domains: vec![domain],
+            url: format!("dns:{name}"),
+        })
+    }
+
+    async fn lookup(&self, name: &str) -> (Result<VerifyingKey, String>, Duration) {
+        let answer = match self.resolver.txt(name).await {
+            Ok(Some(a)) => a,
+            Ok(None) => return (Err("no such key".into()), Duration::ZERO),
+            Err(e) => return (Err(format!("lookup failed: {e:#}")), Duration::ZERO),
+        };
+        let mut keys = answer
+            .records
+            .iter()
+            .filter(|r| r.trim_start().starts_with("v=ZKACK1"))
+            .map(|r| parse_dns_key_record(r));
+        let key = match (keys.next(), keys.next()) {
+            (None, _) => Err("no v=ZKACK1 record".to_string()),
+            (Some(_), Some(_)) => Err("more than one v=ZKACK1 record".to_string()),
+            (Some(Ok(Some(vk))), None) => Ok(vk),
+            (Some(Ok(None)), None) => Err("key revoked".to_string()),
+            (Some(Err(e)), None) => Err(format!("{e:#}")),
+        };
+        (key, answer.ttl)
+    }
+
+    /// DNS part of /healthz.
+    pub fn status(&self) -> serde_json::Value {
+        let cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        serde_json::json!({
+            "server": self.cfg.server,
+            "cached": cache.values().filter(|e| now < e.until).count(),
+            "cached_keys": cache.values().filter(|e| now < e.until && e.key.is_ok()).count(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use zkack_spec::dns_key_record;
+
+    /// TXT records by name; a name mapped to None fails to resolve.
+    #[derive(Default)]
+    struct StubTxt {
+        zone: HashMap<String, Option<Vec<String>>>,
+        lookups: AtomicUsize,
+    }
+
+    impl StubTxt {
+        fn with(mut self, name: &str, records: &[&str]) -> Self {
+            self.zone.insert(
+                name.into(),
+                Some(records.iter().map(|r| r.to_string()).collect()),
+            );
+            self
+        }
+    }
+
+    impl TxtResolver for StubTxt {
+        fn txt<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<Option<TxtAnswer>>> + Send + 'a>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match self.zone.get(name) {
+                    None => Ok(None),
+                    Some(None) => Err(anyhow!("SERVFAIL")),
+                    Some(Some(records)) => Ok(Some(TxtAnswer {
+                        records: records.clone(),
+                        ttl: Duration::from_secs(5),
+                    })),
+                }
+            })
+        }
+    }
+
+    fn dns(stub: StubTxt, allow: &[&str]) -> (DnsKeys, Arc<StubTxt>) {
+        let stub = Arc::new(stub);
+        let cfg = DnsConfig {
+            server: None,
+            allow: allow.iter().map(|d| d.to_string()).collect(),
+            min_ttl_secs: 60,
+            max_ttl_secs: 3600,
+            timeout_secs: 5,
+        };
+        (DnsKeys::new(cfg, stub.clone()), stub)
+    }
+
+    fn vk(seed: u8) -> VerifyingKey {
+        ed25519_dalek::SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    #[tokio::test]
+    async fn key_signs_only_for_its_domain_and_is_cached() {
+        let record = dns_key_record(&vk(1));
+        let stub = StubTxt::default().with("k1._zkack.example.gov", &["v=spf1 -all", &record]);
+        let (dns, stub) = dns(stub, &[]);
+        let key = dns.resolve("k1", "Example.GOV.").await.unwrap();
+        assert_eq!(key.vk, vk(1));
+        assert_eq!(key.domains, ["example.gov"]);
+        assert_eq!(key.url, "dns:k1._zkack.example.gov");
+        dns.resolve("k1", "example.gov").await.unwrap();
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_key_is_cached_too() {
+        let (dns, stub) = dns(StubTxt::default(), &[]);
+        for _ in 0..2 {
+            let err = dns.resolve("k1", "example.gov").await.err().unwrap();
+            assert!(err.to_string().ends_with("no such key"), "{err}");
+        }
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unusable_records_are_refused() {
+        let a = dns_key_record(&vk(1));
+        let b = dns_key_record(&vk(2));
+        let stub = StubTxt::default()
+            .with("revoked._zkack.example.gov", &["v=ZKACK1; p="])
+            .with("twice._zkack.example.gov", &[&a, &b])
+            .with("junk._zkack.example.gov", &["v=ZKACK1; p=AAAA"])
+            .with("none._zkack.example.gov", &["hello"]);
+        let (dns, _) = dns(stub, &[]);
+        for (kid, why) in [
+            ("revoked", "key revoked"),
+            ("twice", "more than one v=ZKACK1 record"),
+            ("junk", "32-byte"),
+            ("none", "no v=ZKACK1 record"),
+        ] {
+            let err = dns.resolve(kid, "example.gov").await.err().unwrap();
+            assert!(err.to_string().contains(why), "{kid}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn allowlist_is_checked_before_lookup() {
+        let (dns, stub) = dns(StubTxt::default(), &["example.gov"]);
+        let err = dns.resolve("k1", "example.com").await.err().unwrap();
+        assert!(err.to_string().contains("may not publish keys"), "{err}");
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 0);
+        // ACK keys of receiving domains are not subject to it
+        assert!(dns.resolve_ack("k1", "example.com").await.is_err());
+        assert_eq!(stub.lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn lookup_failure_is_reported() {
+        let mut stub = StubTxt::default();
+        stub.zone.insert("k1._zkack.example.gov".into(), None);
+        let (dns, _) = dns(stub, &[]);
+        let err = dns.resolve("k1", "example.gov").await.err().unwrap();
+        assert!(err.to_string().contains("lookup failed: SERVFAIL"), "{err}");
+    }
+}
@@ -0,0 +1,417 @@
+//! Issuer keys fetched on demand. A kid missing from the local key set is looked up
+//! in the JWK Set the issuer publishes at https://<host>/.well-known/zkack-keys.json:
+//! the DAT's `jku` when its host is allowlisted, else each configured issuer.
+//! Sets are cached per URL for their Cache-Control max-age (clamped), refetched at
+//! most once per `min_refresh_secs`, and not used once expired (fail closed).
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::VerifyingKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use url::Url;
+
+use zkack_spec::{domain_aligned, jwk_thumbprint, Jwks, JwsHeader, JWKS_PATH};
+
+use crate::config::JwksConfig;
+
+/// Largest JWK Set accepted.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Cache lifetime when the issuer sends no max-age (before clamping).
+const DEFAULT_MAX_AGE: u64 = 300;
+/// Distinct JWK Set URLs tracked; DATs naming further ones are refused.
+const MAX_URLS: usize = 256;
+
+/// A key from an issuer's JWK Set and the sender domains it may sign for.
+pub struct RemoteKey {
+    pub vk: VerifyingKey,
+    pub domains: Vec<String>,
+    pub url: String,
+}
+
+pub struct JwksCache {
+    cfg: JwksConfig,
+    http: reqwest::Client,
+    /// One slot per JWK Set URL; the lock is held while that set is fetched.
+    slots: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Slot>>>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    keys: HashMap<String, (VerifyingKey, Vec<String>)>,
+    fetched_at: Option<String>,
+    fresh_until: Option<Instant>,
+    last_attempt: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl JwksCache {
+    pub fn new(cfg: JwksConfig) -> Result<JwksCache> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .https_only(!cfg.allow_http)
+            .user_agent(concat!("zkack-verifier/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(JwksCache {
+            cfg,
+            http,
+            slots: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Find the DAT's key: in its `jku` set if it names one, else in the configured issuers'.
+    pub async fn resolve(&self, hdr: &JwsHeader) -> Result<RemoteKey> {
+        let urls = match &hdr.jku {
+            Some(jku) => {
+                let url = check_url(jku, self.cfg.allow_http)?;
+                let host = url.host_str().unwrap_or_default();
+                if !self.cfg.issuers.contains(&url.to_string())
+                    && !domain_aligned(host, &self.cfg.allow)
+                {
+                    anyhow::bail!("jku host {host} is not allowed");
+                }
+                vec![url.to_string()]
+            }
+            None => self.cfg.issuers.clone(),
+        };
+        let mut errors = Vec::new();
+        for url in &urls {
+            match self.lookup(url, &hdr.kid).await {
+                Ok(key) => return Ok(key),
+                Err(e) => errors.push(format!("{e:#}")),
+            }
+        }
+        if errors.is_empty() {
+            anyhow::bail!("jwks: no issuers configured");
+        }
+        Err(anyhow!("{}", errors.join("; ")))
+    }
+
+    async fn lookup(&self, url: &str, kid: &str) -> Result<RemoteKey> {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            if !slots.contains_key(url) && slots.len() >= MAX_URLS {
+                anyhow::bail!("jwks {url}: too many key set URLs cached");
+            }
+            slots.entry(url.to_string()).or_default().clone()
+        };
+        let mut slot = slot.lock().await;
+        let now = Instant::now();
+        let fresh = slot.fresh_until.is_some_and(|t| now < t);
+        let min_refresh = Duration::from_secs(self.cfg.min_refresh_secs);
+        let may_fetch = slot
+            .last_attempt
+            .map_or(true, |t| now.duration_since(t) >= min_refresh);
+        // an unknown kid may be a key the issuer just rotated in
+        if (!fresh || !slot.keys.contains_key(kid)) && may_fetch {
+            slot.last_attempt = Some(now);
+            match self.fetch(url).await {
+                Ok((keys, ttl)) => {
+                    tracing::info!(
+                        url,
+                        kids = keys.len(),
+                        ttl_secs = ttl.as_secs(),
+                        "jwks fetched"
+                    );
+                    slot.keys = keys;
+                    slot.fetched_at = Some(OffsetDateTime::now_utc().format(&Rfc3339).unwrap());
+                    slot.fresh_until = Some(now + ttl);
+                    slot.last_error = None;
+                }
+                Err(e) => {
+                    tracing::warn!(url, "jwks fetch failed: {e:#}");
+                    slot.last_error = Some(format!("{e:#}"));
+                }
+            }
+        }
+        if slot.fresh_until.map_or(true, |t| Instant::now() >= t) {
+            let why = slot.last_error.as_deref().unwrap_or("key set expired");
+            anyhow::bail!("jwks {url}: {why}");
+        }
+        let (vk, domains) = slot
+            .keys
+            .get(kid)
+            .ok_or_else(|| anyhow!("jwks {url}: no kid {kid}"))?;
+        Ok(RemoteKey {
+            vk: *vk,
+            domains: domains.clone(),
+            url: url.to_string(),
+        })
+    }
+
+    async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<(HashMap<String, (VerifyingKey, Vec<String>)>, Duration)> {
+        let mut resp = self
+            .http
+            .get(url)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await?;
+        if resp.status() != reqwest::StatusCode::OK {
+            anyhow::bail!("HTTP {}", resp.status());
+        }
+        let ttl = self.ttl(
+            resp.headers()
+                .get(reqwest::header::CACHE_CONTROL)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if body.len() + chunk.len() > MAX_BODY_BYTES {
+                anyhow::bail!("key set larger than {MAX_BODY_BYTES} bytes");
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let set: Jwks = serde_json::from_slice(&body)?;
+
+        let host = Url::parse(url)?.host_str().unwrap_or_default().to_string();
+        let trusted = self.cfg.issuers.iter().any(|i| i == url);
+        let pins = self.cfg.pins.get(&host);
+        let mut keys = HashMap::new();
+        for jwk in set.keys {
+            let vk = match jwk.verifying_key() {
+                Ok(vk) => vk,
+                Err(e) => {
+                    tracing::warn!(url, "jwks: skipping key: {e:#}");
+                    continue;
+                }
+            };
+            if pins.is_some_and(|p| !p.contains(&jwk_thumbprint(&vk))) {
+                tracing::warn!(url, kid = %jwk.kid, "jwks: skipping unpinned key");
+                continue;
+            }
+            // a set the operator did not configure speaks only for its own host
+            let mut domains: Vec<String> = jwk
+                .zkack_domains
+                .into_iter()
+                .filter(|d| trusted || domain_aligned(d, std::slice::from_ref(&host)))
+                .collect();
+            if domains.is_empty() {
+                domains.push(host.clone());
+            }
+            if keys.insert(jwk.kid.clone(), (vk, domains)).is_some() {
+                anyhow::bail!("kid {} listed twice", jwk.kid);
+            }
+        }
+        if keys.is_empty() {
+            anyhow::bail!("no usable keys");
+        }
+        Ok((keys, ttl))
+    }
+
+    /// max-age, clamped to [min_refresh_secs, max_cache_secs]; no-store/no-cache keep it minimal.
+    fn ttl(&self, cache_control: Option<&str>) -> Duration {
+        let mut max_age = DEFAULT_MAX_AGE;
+        for directive in cache_control.unwrap_or_default().split(',') {
+            let d = directive.trim().to_ascii_lowercase();
+            if d == "no-store" || d == "no-cache" {
+                max_age = 0;
+                break;
+            }
+            if let Some(n) = d.strip_prefix("max-age=") {
+                max_age = n.trim_matches('"').parse().unwrap_or(0);
+            }
+        }
+        Duration::from_secs(max_age.clamp(self.cfg.min_refresh_secs, self.cfg.max_cache_secs))
+    }
+
+    /// JWKS part of /healthz.
+    pub async fn status(&self) -> serde_json::Value {
+        let slots: Vec<_> = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(u, s)| (u.clone(), s.clone()))
+            .collect();
+        let mut sets = Vec::new();
+        for (url, slot) in slots {
+            let s = slot.lock().await;
+            sets.push(serde_json::json!({
+                "url": url,
+                "kids": s.keys.len(),
+                "fetched_at": s.fetched_at,
+                "fresh_for_secs": s.fresh_until.map(|t| t.saturating_duration_since(Instant::now()).as_secs()),
+                "last_error": s.last_error,
+            }));
+        }
+        serde_json::json!({ "issuers": self.cfg.issuers, "sets": sets })
+    }
+}
+
+/// A JWK Set URL: https (http only when allowed), the well-known path, nothing else.
+pub fn check_url(raw: &str, allow_http: bool) -> Result<Url> {
+    let url = Url::parse(raw).map_err(|e| anyhow!("jwks url {raw}: {e}"))?;
+    let scheme_ok = url.scheme() == "https" || (allow_http && url.scheme() == "http");
+    if !scheme_ok
+        || url.host_str().is_none()
+        || url.path() != JWKS_PATH
+        || url.query().is_some()
+        || url.fragment().is_some()
+        || !url.username().is_empty()
+        || url.password().is_some()
+    {
+        anyhow::bail!("jwks url {raw}: want https://<host>{JWKS_PATH}");
+    }
+    Ok(url)
+}
+
+/// A configured issuer: its JWK Set URL, or a bare origin that gets the well-known path.
+pub fn issuer_url(raw: &str, allow_http: bool) -> Result<String> {
+    let mut url = Url::parse(raw).map_err(|e| anyhow!("jwks issuer {raw}: {e}"))?;
+    if url.path() == "/" || url.path().is_empty() {
+        url.set_path(JWKS_PATH);
+    }
+    Ok(check_url(url.as_str(), allow_http)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use ed25519_dalek::SigningKey;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn cfg() -> JwksConfig {
+        JwksConfig {
+            issuers: Vec::new(),
+            allow: vec!["127.0.0.1".into()],
+            pins: Default::default(),
+            min_refresh_secs: 60,
+            max_cache_secs: 3600,
+            timeout_secs: 5,
+            allow_http: true,
+        }
+    }
+
+    fn jwk(kid: &str, seed: u8, domains: &[&str]) -> (zkack_spec::Jwk, VerifyingKey) {
+        let vk = SigningKey::from_bytes(&[seed; 32]).verifying_key();
+        let entry = zkack_spec::PubKeyEntry {
+            kid: kid.into(),
+            vk_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(vk.as_bytes()),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        };
+        (zkack_spec::Jwk::from_entry(&entry), vk)
+    }
+
+    /// Serve `set` as the well-known JWK Set; returns its URL and a fetch counter.
+    async fn serve(set: Jwks, cache_control: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{JWKS_PATH}", listener.local_addr().unwrap());
+        let body = serde_json::to_string(&set).unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let count = fetches.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = conn.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: {cache_control}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                conn.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (url, fetches)
+    }
+
+    fn header(kid: &str, jku: Option<&str>) -> JwsHeader {
+        JwsHeader {
+            alg: "EdDSA".into(),
+            kid: kid.into(),
+            jku: jku.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn ttl_is_clamped_to_configured_bounds() {
+        let cache = JwksCache::new(cfg()).unwrap();
+        let secs = |cc: Option<&str>| cache.ttl(cc).as_secs();
+        assert_eq!(secs(None), DEFAULT_MAX_AGE);
+        assert_eq!(secs(Some("public, max-age=\"120\"")), 120);
+        assert_eq!(secs(Some("max-age=10")), 60);
+        assert_eq!(secs(Some("max-age=999999")), 3600);
+        assert_eq!(secs(Some("max-age=soon")), 60);
+        assert_eq!(secs(Some("no-store, max-age=900")), 60);
+    }
+
+    #[test]
+    fn urls_must_be_the_well_known_path() {
+        assert!(check_url("https://issuer.example/.well-known/zkack-keys.json", false).is_ok());
+        for bad in [
+            "http://issuer.example/.well-known/zkack-keys.json",
+            "https://issuer.example/keys.json",
+            "https://issuer.example/.well-known/zkack-keys.json?x=1",
+            "https://u:p@issuer.example/.well-known/zkack-keys.json",
+            "file:///.well-known/zkack-keys.json",
+        ] {
+            assert!(check_url(bad, false).is_err(), "{bad} accepted");
+        }
+        assert_eq!(
+            issuer_url("https://issuer.example", false).unwrap(),
+            "https://issuer.example/.well-known/zkack-keys.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn jku_host_must_be_allowlisted() {
+        let cache = JwksCache::new(cfg()).unwrap();
+        let err = cache
+            .resolve(&header(
+                "k1",
+                Some("http://keys.evil.example/.well-known/zkack-keys.json"),
+            ))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is not allowed"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn jku_set_is_cached_and_speaks_only_for_its_host() {
+        let (k1, vk1) = jwk("k1", 1, &["example.gov"]);
+        let (url, fetches) = serve(Jwks { keys: vec![k1] }, "max-age=600").await;
+        let cache = JwksCache::new(cfg()).unwrap();
+
+        let key = cache.resolve(&header("k1", Some(&url))).await.unwrap();
+        assert_eq!(key.vk, vk1);
+        // example.gov is not the set's host and the operator did not configure the set
+        assert_eq!(key.domains, ["127.0.0.1"]);
+        cache.resolve(&header("k1", Some(&url))).await.unwrap();
+        // an unknown kid may refetch, but not within min_refresh_secs
+        assert!(cache.resolve(&header("k2", Some(&url))).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn configured_issuer_keeps_domains_and_pins_filter_keys() {
+        let (k1, vk1) = jwk("k1", 1, &["example.gov"]);
+        let (k2, _) = jwk("k2", 2, &["example.gov"]);
+        let (url, _) = serve(Jwks { keys: vec![k1, k2] }, "max-age=600").await;
+        let mut cfg = cfg();
+        cfg.issuers = vec![url];
+        cfg.pins
+            .insert("127.0.0.1".into(), vec![jwk_thumbprint(&vk1)]);
+        let cache = JwksCache::new(cfg).unwrap();
+
+        let key = cache.resolve(&header("k1", None)).await.unwrap();
+        assert_eq!(key.domains, ["example.gov"]);
+        let err = cache.resolve(&header("k2", None)).await.err().unwrap();
+        assert!(err.to_string().contains("no kid k2"), "{err}");
+    }
+}
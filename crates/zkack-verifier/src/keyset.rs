@@ -0,0 +1,358 @@
+//! The public key set, swapped atomically on SIGHUP or when the key files change.
+//! A set that fails to load is rejected and the current one stays in service.
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::VerifyingKey;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use zkack_spec::{blake3_b64, parse_pubkey_domains, parse_pubkeys, PubKeyEntry};
+
+/// Public keys by kid, plus the sender-domain allowlists of keys that declare one.
+pub struct KeySet {
+    pub keys: HashMap<String, VerifyingKey>,
+    pub domains: HashMap<String, Vec<String>>,
+    /// Starts at 1, bumped on every swap
+    pub version: u64,
+    pub loaded_at: String,
+    /// blake3 over the key files' contents
+    pub digest: String,
+}
+
+pub struct KeyStore {
+    /// Which key set, for logs: "dat" or "receiver"
+    name: &'static str,
+    paths: Vec<PathBuf>,
+    current: RwLock<Arc<KeySet>>,
+    /// Serializes reloads; holds the last rejected set
+    last_error: Mutex<Option<Rejected>>,
+}
+
+#[derive(Clone)]
+struct Rejected {
+    at: String,
+    error: String,
+    /// None when the files could not be read at all
+    digest: Option<String>,
+}
+
+impl KeyStore {
+    pub fn open(name: &'static str, paths: Vec<PathBuf>) -> Result<KeyStore> {
+        let (digest, files) = read_files(&paths)?;
+        let set = parse_set(&files, 1, digest)?;
+        Ok(KeyStore {
+            name,
+            paths,
+            current: RwLock::new(Arc::new(set)),
+            last_error: Mutex::new(None),
+        })
+    }
+
+    /// The key set in service; hold it for the whole request.
+    pub fn current(&self) -> Arc<KeySet> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-read the key files and swap them in if they changed and load cleanly.
+    pub fn reload(&self, trigger: &str) -> Result<bool> {
+        let mut last_error = self.last_error.lock().unwrap();
+        let old = self.current();
+        let mut seen = None;
+        let mut repeat = false;
+        let loaded = read_files(&self.paths).and_then(|(digest, files)| {
+            if digest == old.digest {
+                return Ok(None);
+            }
+            if let Some(r) = last_error
+                .as_ref()
+                .filter(|r| r.digest.as_ref() == Some(&digest))
+            {
+                // same bytes as the set already rejected; nothing new to report
+                repeat = true;
+                return Err(anyhow!("{}", r.error));
+            }
+            seen = Some(digest.clone());
+            parse_set(&files, old.version + 1, digest).map(Some)
+        });
+        match loaded {
+            Ok(None) => {
+                *last_error = None;
+                Ok(false)
+            }
+            Ok(Some(set)) => {
+                let before: BTreeSet<_> = old.keys.keys().collect();
+                let after: BTreeSet<_> = set.keys.keys().collect();
+                tracing::info!(
+                    set = self.name,
+                    trigger,
+                    version = set.version,
+                    added = ?after.difference(&before).collect::<Vec<_>>(),
+                    removed = ?before.difference(&after).collect::<Vec<_>>(),
+                    "key set reloaded"
+                );
+                *self.current.write().unwrap() = Arc::new(set);
+                *last_error = None;
+                Ok(true)
+            }
+            Err(e) => {
+                let error = format!("{e:#}");
+                let unreadable_again = seen.is_none()
+                    && last_error
+                        .as_ref()
+                        .is_some_and(|r| r.digest.is_none() && r.error == error);
+                if repeat || unreadable_again {
+                    return Err(e);
+                }
+                tracing::warn!(
+                    set = self.name,
+                    trigger,
+                    version = old.version,
+                    "key set rejected, keeping current: {e:#}"
+                );
+                *last_error = Some(Rejected {
+                    at: now(),
+                    error,
+                    digest: seen,
+                });
+                Err(e)
+            }
+        }
+    }
+
+    /// Key-set part of /healthz.
+    pub fn status(&self) -> serde_json::Value {
+        let set = self.current();
+        let last_error = self.last_error.lock().unwrap().clone();
+        serde_json::json!({
+            "version": set.version,
+            "loaded_at": set.loaded_at,
+            "digest": set.digest,
+            "kids": set.keys.len(),
+            "last_reload_error": last_error.map(|r| serde_json::json!({ "at": r.at, "error": r.error })),
+        })
+    }
+
+    /// Reload on SIGHUP and, when `poll` is set, whenever the files' contents change.
+    pub fn watch(self: &Arc<Self>, poll: Option<Duration>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hup = signal(SignalKind::hangup())?;
+            let store = self.clone();
+            tokio::spawn(async move {
+                while hup.recv().await.is_some() {
+                    let store = store.clone();
+                    let _ = tokio::task::spawn_blocking(move || store.reload("SIGHUP")).await;
+                }
+            });
+        }
+        if let Some(every) = poll {
+            let store = self.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(every);
+                tick.tick().await;
+                loop {
+                    tick.tick().await;
+                    let store = store.clone();
+                    let _ = tokio::task::spawn_blocking(move || store.reload("file change")).await;
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn read_files(paths: &[PathBuf]) -> Result<(String, Vec<(PathBuf, String)>)> {
+    let mut all = Vec::new();
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let json =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("keys {}: {e}", path.display()))?;
+        all.extend_from_slice(&(json.len() as u64).to_be_bytes());
+        all.extend_from_slice(json.as_bytes());
+        files.push((path.clone(), json));
+    }
+    Ok((blake3_b64(&all), files))
+}
+
+/// Merge the key sets; each kid must be defined once across all files.
+fn parse_set(files: &[(PathBuf, String)], version: u64, digest: String) -> Result<KeySet> {
+    let mut keys = HashMap::new();
+    let mut domains = HashMap::new();
+    let mut defined_in: HashMap<String, &PathBuf> = HashMap::new();
+    for (path, json) in files {
+        let what = |e: anyhow::Error| anyhow!("keys {}: {e}", path.display());
+        let entries: Vec<PubKeyEntry> = serde_json::from_str(json).map_err(|e| what(e.into()))?;
+        for e in entries {
+            if let Some(prev) = defined_in.insert(e.kid.clone(), path) {
+                anyhow::bail!(
+                    "keys {}: kid {} is already defined in {}",
+                    path.display(),
+                    e.kid,
+                    prev.display()
+                );
+            }
+        }
+        keys.extend(parse_pubkeys(json).map_err(what)?);
+        domains.extend(parse_pubkey_domains(json).map_err(what)?);
+    }
+    if keys.is_empty() {
+        anyhow::bail!("keys: no public keys loaded");
+    }
+    Ok(KeySet {
+        keys,
+        domains,
+        version,
+        loaded_at: now(),
+        digest,
+    })
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc().format(&Rfc3339).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use std::path::Path;
+
+    fn entry(kid: &str, seed: u8, domains: &[&str]) -> PubKeyEntry {
+        let sk = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]);
+        PubKeyEntry {
+            kid: kid.into(),
+            vk_b64: base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .encode(sk.verifying_key().as_bytes()),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    fn write(path: &Path, entries: &[PubKeyEntry]) {
+        std::fs::write(path, serde_json::to_vec(entries).unwrap()).unwrap();
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zkack-keyset-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn kids(store: &KeyStore) -> BTreeSet<String> {
+        store.current().keys.keys().cloned().collect()
+    }
+
+    #[test]
+    fn reload_swaps_in_changed_files_only() {
+        let dir = dir("reload");
+        let path = dir.join("keys.json");
+        write(&path, &[entry("k1", 1, &["example.gov"])]);
+        let store = KeyStore::open("dat", vec![path.clone()]).unwrap();
+        assert_eq!(store.current().version, 1);
+        assert!(!store.reload("test").unwrap());
+
+        write(
+            &path,
+            &[entry("k1", 1, &[]), entry("k2", 2, &["example.org"])],
+        );
+        assert!(store.reload("test").unwrap());
+        let set = store.current();
+        assert_eq!(set.version, 2);
+        assert_eq!(kids(&store), BTreeSet::from(["k1".into(), "k2".into()]));
+        assert!(!set.domains.contains_key("k1"));
+        assert_eq!(set.domains["k2"], ["example.org"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_files_keep_the_current_set() {
+        let dir = dir("invalid");
+        let path = dir.join("keys.json");
+        write(&path, &[entry("k1", 1, &[])]);
+        let store = KeyStore::open("dat", vec![path.clone()]).unwrap();
+        let digest = store.current().digest.clone();
+
+        for bad in ["not json", "[]", r#"[{"kid":"k9","vk_b64":"AAAA"}]"#] {
+            std::fs::write(&path, bad).unwrap();
+            assert!(store.reload("test").is_err(), "{bad} accepted");
+            assert_eq!(store.current().digest, digest);
+            assert_eq!(kids(&store), BTreeSet::from(["k1".into()]));
+        }
+        assert!(store.status()["last_reload_error"]["error"].is_string());
+        std::fs::remove_file(&path).unwrap();
+        assert!(store.reload("test").is_err());
+        assert_eq!(store.current().version, 1);
+
+        // a good file clears the error
+        write(&path, &[entry("k2", 2, &[])]);
+        assert!(store.reload("test").unwrap());
+        assert!(store.status()["last_reload_error"].is_null());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_kids_are_rejected() {
+        let dir = dir("dupes");
+        let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+        write(&a, &[entry("k1", 1, &["example.gov"])]);
+        // same kid and key, different domains: neither file may silently win
+        write(&b, &[entry("k1", 1, &["evil.example"])]);
+        let err = KeyStore::open("dat", vec![a.clone(), b.clone()])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("kid k1 is already defined in"), "{err}");
+        write(&b, &[entry("k2", 2, &[]), entry("k2", 3, &[])]);
+        assert!(KeyStore::open("dat", vec![b.clone()]).is_err());
+
+        // on reload the current set stays
+        write(&b, &[entry("k2", 2, &[])]);
+        let store = KeyStore::open("dat", vec![a.clone(), b.clone()]).unwrap();
+        write(&b, &[entry("k1", 1, &["evil.example"])]);
+        assert!(store.reload("test").is_err());
+        assert_eq!(store.current().domains["k1"], ["example.gov"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn wait_for_version(store: &KeyStore, version: u64) {
+        for _ in 0..200 {
+            if store.current().version == version {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("key set never reached version {version}");
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_on_file_change_and_sighup() {
+        let dir = dir("watch");
+        let (polled, hup) = (dir.join("polled.json"), dir.join("hup.json"));
+        write(&polled, &[entry("k1", 1, &[])]);
+        write(&hup, &[entry("k1", 1, &[])]);
+        let by_poll = Arc::new(KeyStore::open("dat", vec![polled.clone()]).unwrap());
+        by_poll.watch(Some(Duration::from_millis(20))).unwrap();
+        let by_signal = Arc::new(KeyStore::open("dat", vec![hup.clone()]).unwrap());
+        by_signal.watch(None).unwrap();
+
+        write(&polled, &[entry("k2", 2, &[])]);
+        wait_for_version(&by_poll, 2).await;
+        assert_eq!(kids(&by_poll), BTreeSet::from(["k2".into()]));
+
+        write(&hup, &[entry("k3", 3, &[])]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(by_signal.current().version, 1);
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        wait_for_version(&by_signal, 2).await;
+        assert_eq!(kids(&by_signal), BTreeSet::from(["k3".into()]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
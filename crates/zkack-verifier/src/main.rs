@@ -1,25 +1,67 @@
 use axum::http::StatusCode;
 use axum::{
+    extract::{DefaultBodyLimit, Request},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use base64::Engine;
-use ed25519_dalek::VerifyingKey;
+use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, net::SocketAddr, path::Path, sync::Arc};
+use std::{sync::Arc, time::Duration};
 use time::OffsetDateTime;
+use tower::limit::ConcurrencyLimitLayer;
 use tracing_subscriber::{fmt::Subscriber, EnvFilter};
 use uuid::Uuid;
 
+use zkack_circuits::ProofSystemId;
 use zkack_spec::*; // jws_verify, DatPayload, JwsHeader, PubKeyEntry, etc.
 
+mod config;
+mod dnskeys;
+mod jwks;
+mod keyset;
+
 #[derive(Clone)]
 struct AppState {
-    keys: Arc<HashMap<String, VerifyingKey>>,
+    // hot-reloaded; take one snapshot per request with keys.current()
+    keys: Arc<keyset::KeyStore>,
+    // issuers' published keys, for kids not in `keys`
+    jwks: Option<Arc<jwks::JwksCache>>,
+    // keys published as DNS TXT records, for kids not in `keys` and receivers' ACK keys
+    dns: Option<Arc<dnskeys::DnsKeys>>,
+    // registered ACK keys of receiving domains
+    receiver_keys: Option<Arc<keyset::KeyStore>>,
+    require_receiver_auth: bool,
+    // checks the proof of every ACK
+    proof_system: ProofSystemId,
     db: sled::Db,
+    // normalized verify_code -> ack_id of the receipt that carried it
+    codes: sled::Tree,
+    // normalized verify_code -> kid \0 nonce_b64 of the DAT the issuer registered it with
+    issued_codes: sled::Tree,
+    // kid \0 nonce_b64 -> ack_id: one receipt per DAT
+    nonces: sled::Tree,
+    // Idempotency-Key -> {ack_id, fingerprint, at}
+    idempotency: sled::Tree,
 }
 
-#[derive(Debug, Deserialize)]
+/// Receipt fields a repeated ACK of the same DAT must match to count as a retry.
+const ACK_FIELDS: &[&str] = &[
+    "kid",
+    "dat",
+    "received_ts",
+    "recv_domain",
+    "recv_domain_sig",
+    "msg_id",
+    "dkim_pass",
+];
+
+/// How long an Idempotency-Key is remembered.
+const IDEMPOTENCY_TTL: time::Duration = time::Duration::hours(24);
+
+#[derive(Debug, Deserialize, Serialize)]
 struct AckReq {
     dat_jws: String,
     proof: String,
@@ -32,33 +74,373 @@ struct AckReq {
 
 #[derive(Debug, serde::Deserialize)]
 struct VerifyReq {
-    dat_jws: String,
+    // One DAT, or every X-ZK-DAT of a countersigned message (reported per DAT).
+    dat_jws: DatJws,
     // Optional: client-computed digest of the message; if provided, we compare to DAT payload.
     msg_digest_b64: Option<String>,
+    // Optional: digests of individual MIME parts (manifest DATs only), reported per part.
+    parts: Option<Vec<PartClaim>>,
+    // Optional: salted part disclosures (manifest-sd DATs) for selective disclosure.
+    disclosures: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DatJws {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterCodeReq {
+    dat_jws: String,
 }
 
 #[derive(Debug, Serialize)]
 struct AckResp {
     ack_id: Uuid,
     status: &'static str,
+    // set when the DAT was already acknowledged and this is its original receipt
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    duplicate: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct IdempotencyRecord {
+    // None while the first request with the key is still being processed
+    ack_id: Option<Uuid>,
+    fingerprint: String,
+    at: String,
+}
+
+impl IdempotencyRecord {
+    fn expired(&self, now: OffsetDateTime) -> bool {
+        OffsetDateTime::parse(&self.at, &time::format_description::well_known::Rfc3339)
+            .map_or(true, |at| now - at >= IDEMPOTENCY_TTL)
+    }
+}
+
+enum Claimed {
+    // the key now holds this pending record, owned by the caller
+    New(Vec<u8>),
+    // the key already completed with this receipt
+    Replay(Uuid),
+}
+
+fn rfc3339(t: OffsetDateTime) -> String {
+    t.format(&time::format_description::well_known::Rfc3339)
+        .unwrap()
+}
+
+/// Atomically take an Idempotency-Key for a new request, or find its earlier outcome.
+/// An expired record counts as absent.
+fn claim_idempotency(
+    tree: &sled::Tree,
+    key: &str,
+    fingerprint: &str,
+    now: OffsetDateTime,
+) -> Result<Claimed, (StatusCode, String)> {
+    let pending = serde_json::to_vec(&IdempotencyRecord {
+        ack_id: None,
+        fingerprint: fingerprint.to_string(),
+        at: rfc3339(now),
+    })
+    .unwrap();
+    loop {
+        let current = tree.get(key).map_err(db_error("read"))?;
+        if let Some(prev) = current
+            .as_ref()
+            .and_then(|v| serde_json::from_slice::<IdempotencyRecord>(v).ok())
+            .filter(|r| !r.expired(now))
+        {
+            if prev.fingerprint != fingerprint {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "422 Idempotency-Key already used for a different request".into(),
+                ));
+            }
+            return match prev.ack_id {
+                Some(ack_id) => Ok(Claimed::Replay(ack_id)),
+                None => Err((
+                    StatusCode::CONFLICT,
+                    "409 a request with this Idempotency-Key is in progress; retry later".into(),
+                )),
+            };
+        }
+        if tree
+            .compare_and_swap(key, current, Some(pending.as_slice()))
+            .map_err(db_error("insert"))?
+            .is_ok()
+        {
+            return Ok(Claimed::New(pending));
+        }
+    }
+}
+
+/// Drop expired Idempotency-Keys; at startup also drop claims left pending by a crash.
+fn sweep_idempotency(tree: &sled::Tree, now: OffsetDateTime, pending: bool) -> sled::Result<usize> {
+    let mut removed = 0;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        let stale = match serde_json::from_slice::<IdempotencyRecord>(&value) {
+            Ok(r) => r.expired(now) || (pending && r.ack_id.is_none()),
+            Err(_) => true,
+        };
+        if stale
+            && tree
+                .compare_and_swap(&key, Some(value), None::<&[u8]>)?
+                .is_ok()
+        {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// A receipt key (raw ack_id bytes) for logs.
+fn receipt_id(key: &[u8]) -> String {
+    Uuid::from_slice(key).map_or_else(|_| format!("{key:?}"), |id| id.to_string())
+}
+
+fn nonce_key(kid: &str, nonce_b64: &str) -> Vec<u8> {
+    [kid.as_bytes(), b"\0", nonce_b64.as_bytes()].concat()
+}
+
+fn db_error(what: &str) -> impl Fn(sled::Error) -> (StatusCode, String) + '_ {
+    move |e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("500 db {what}: {e}"),
+        )
+    }
+}
+
+/// A key with a domain allowlist only vouches for DATs whose sender_domain aligns with it.
+fn check_sender(allowed: Option<&[String]>, kid: &str, dat: &DatPayload) -> Result<(), String> {
+    let Some(allowed) = allowed else {
+        return Ok(());
+    };
+    match &dat.sender_domain {
+        Some(d) if domain_aligned(d, allowed) => Ok(()),
+        Some(d) => Err(format!("sender domain {d} not allowed for kid {kid}")),
+        None => Err(format!("DAT has no sender_domain; kid {kid} requires one")),
+    }
+}
+
+/// Check a DAT with its local key or, failing that, its issuer's published one.
+/// Returns where a remote key came from (JWK Set URL, or dns:<name>).
+async fn verify_jws(
+    state: &AppState,
+    jws: &str,
+) -> Result<(JwsHeader, DatPayload, Option<String>), String> {
+    let keys = state.keys.current();
+    let (hdr, unverified) = jws_peek(jws).map_err(|e| e.to_string())?;
+    let (vk, allowed, url) = match keys.keys.get(&hdr.kid) {
+        Some(vk) => (*vk, keys.domains.get(&hdr.kid).cloned(), None),
+        None => {
+            let k = remote_key(state, &hdr, &unverified).await?;
+            (k.vk, Some(k.domains), Some(k.url))
+        }
+    };
+    let (hdr, dat) = jws_verify(jws, &|_| Some(vk)).map_err(|e| e.to_string())?;
+    check_sender(allowed.as_deref(), &hdr.kid, &dat)?;
+    Ok((hdr, dat, url))
+}
+
+/// A DAT naming a jku is looked up there; otherwise in DNS, then at the configured issuers.
+async fn remote_key(
+    state: &AppState,
+    hdr: &JwsHeader,
+    dat: &DatPayload,
+) -> Result<jwks::RemoteKey, String> {
+    let mut errors = Vec::new();
+    if let (Some(dns), None) = (&state.dns, &hdr.jku) {
+        match &dat.sender_domain {
+            Some(d) => match dns.resolve(&hdr.kid, d).await {
+                Ok(k) => return Ok(k),
+                Err(e) => errors.push(format!("{e:#}")),
+            },
+            None => errors.push("dns: DAT has no sender_domain".into()),
+        }
+    }
+    if let Some(jwks) = &state.jwks {
+        match jwks.resolve(hdr).await {
+            Ok(k) => return Ok(k),
+            Err(e) => errors.push(format!("{e:#}")),
+        }
+    }
+    if errors.is_empty() {
+        return Err("unknown kid".into());
+    }
+    Err(format!("unknown kid: {}", errors.join("; ")))
+}
+
+/// The kid of the key recv_domain signed this ACK with, or why it is not authenticated.
+/// Registered receiver keys are tried first, then <kid>._zkack-ack.<recv_domain> in DNS.
+async fn authenticate_receiver(state: &AppState, req: &AckReq) -> Result<String, String> {
+    let sig = req.recv_domain_sig.as_deref().ok_or("no recv_domain_sig")?;
+    let hdr = ack_sig_header(sig).map_err(|e| format!("recv_domain_sig: {e}"))?;
+    let domain = req.recv_domain.trim_end_matches('.').to_ascii_lowercase();
+    let registered = state.receiver_keys.as_ref().map(|k| k.current());
+    let vk = match registered.as_ref().and_then(|k| k.keys.get(&hdr.kid)) {
+        Some(vk) => {
+            let allowed = registered
+                .as_ref()
+                .and_then(|k| k.domains.get(&hdr.kid))
+                .is_some_and(|d| domain_aligned(&domain, d));
+            if !allowed {
+                return Err(format!(
+                    "receiver kid {} is not registered for {domain}",
+                    hdr.kid
+                ));
+            }
+            *vk
+        }
+        None => match &state.dns {
+            Some(dns) => {
+                dns.resolve_ack(&hdr.kid, &domain)
+                    .await
+                    .map_err(|e| format!("{e:#}"))?
+                    .vk
+            }
+            None => return Err(format!("unknown receiver kid {}", hdr.kid)),
+        },
+    };
+    let statement = ack_statement(
+        &req.dat_jws,
+        &domain,
+        &req.received_ts,
+        req.msg_id.as_deref(),
+        req.dkim_pass.unwrap_or(true),
+    );
+    ack_verify(sig, &statement, &vk).map_err(|e| format!("recv_domain_sig: {e}"))?;
+    Ok(hdr.kid)
 }
 
 fn unprocessable<T: std::fmt::Display>(e: T) -> (StatusCode, String) {
     (StatusCode::UNPROCESSABLE_ENTITY, format!("{e}"))
 }
 
+/// An ACK rejection. `code`, sent as X-ZK-Error, names the failed check where the
+/// status alone is ambiguous (several checks answer 422).
+#[derive(Debug)]
+struct AckError {
+    status: StatusCode,
+    code: Option<&'static str>,
+    message: String,
+}
+
+/// X-ZK-Error value for a proof that does not verify.
+const INVALID_PROOF: &str = "invalid_proof";
+
+impl From<(StatusCode, String)> for AckError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        AckError {
+            status,
+            code: None,
+            message,
+        }
+    }
+}
+
+impl axum::response::IntoResponse for AckError {
+    fn into_response(self) -> axum::response::Response {
+        let mut resp = (self.status, self.message).into_response();
+        if let Some(code) = self.code {
+            resp.headers_mut().insert(
+                axum::http::HeaderName::from_static("x-zk-error"),
+                axum::http::HeaderValue::from_static(code),
+            );
+        }
+        resp
+    }
+}
+
+/// A retry carrying the same Idempotency-Key gets the original response; the key may
+/// not be reused for a different request.
 async fn handle_ack(
     axum::extract::State(state): axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
     Json(req): Json<AckReq>,
-) -> Result<Json<AckResp>, (StatusCode, String)> {
-    // v0: proof is mocked; require non-empty so the field is used (and callers can't omit it).
+) -> Result<Json<AckResp>, AckError> {
+    let idempotency_key = match headers.get("idempotency-key") {
+        Some(v) => {
+            let key = v
+                .to_str()
+                .ok()
+                .map(|k| k.trim().trim_matches('"'))
+                .filter(|k| !k.is_empty() && k.len() <= 255)
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        "Idempotency-Key: 1 to 255 visible ASCII characters".to_string(),
+                    )
+                })?;
+            Some(key.to_string())
+        }
+        None => None,
+    };
+    let fingerprint = blake3_b64(&serde_json::to_vec(&req).unwrap());
+    let now = OffsetDateTime::now_utc();
+    let claim = match &idempotency_key {
+        Some(key) => match claim_idempotency(&state.idempotency, key, &fingerprint, now)? {
+            Claimed::Replay(ack_id) => {
+                return Ok(Json(AckResp {
+                    ack_id,
+                    status: "DELIVERED",
+                    duplicate: true,
+                }))
+            }
+            Claimed::New(pending) => Some((key, pending)),
+        },
+        None => None,
+    };
+
+    let resp = match ingest_ack(&state, req).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            // free the key so the client can retry with it
+            if let Some((key, pending)) = &claim {
+                state
+                    .idempotency
+                    .compare_and_swap(key, Some(pending.as_slice()), None::<&[u8]>)
+                    .map_err(db_error("remove"))?
+                    .ok();
+            }
+            return Err(e);
+        }
+    };
+
+    if let Some((key, _)) = &claim {
+        let rec = IdempotencyRecord {
+            ack_id: Some(resp.ack_id),
+            fingerprint,
+            at: rfc3339(now),
+        };
+        state
+            .idempotency
+            .insert(key, serde_json::to_vec(&rec).unwrap())
+            .map_err(db_error("insert"))?;
+    }
+    state.db.flush().map_err(db_error("flush"))?;
+    Ok(Json(resp))
+}
+
+/// Check and store one ACK. A DAT already acknowledged returns its original receipt
+/// if the ACK repeats it, else 409.
+async fn ingest_ack(state: &AppState, req: AckReq) -> Result<AckResp, AckError> {
+    // base64url proof bytes
     if req.proof.trim().is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "empty proof".to_string()));
+        return Err((StatusCode::BAD_REQUEST, "empty proof".to_string()).into());
     }
+    let proof = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(req.proof.trim().trim_end_matches('='))
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("bad proof encoding: {e}")))?;
 
     // Verify JWS and parse DAT
-    let (hdr, dat) =
-        jws_verify(&req.dat_jws, &|kid| state.keys.get(kid).cloned()).map_err(unprocessable)?;
+    let (hdr, dat, jwks_url) = verify_jws(state, &req.dat_jws)
+        .await
+        .map_err(unprocessable)?;
 
     // Check expiration
     let exp = OffsetDateTime::parse(&dat.exp, &time::format_description::well_known::Rfc3339)
@@ -67,7 +449,35 @@ async fn handle_ack(
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
             "422 invalid: DAT expired".into(),
-        ));
+        )
+            .into());
+    }
+
+    // The proof is over the DAT's public inputs
+    let system = state.proof_system;
+    let invalid_proof = |why: String| AckError {
+        status: StatusCode::UNPROCESSABLE_ENTITY,
+        code: Some(INVALID_PROOF),
+        message: format!("422 invalid proof ({system}): {why}"),
+    };
+    match system.verify(
+        &dat.addr_hash_b64,
+        &dat.msg_digest_b64,
+        &dat.nonce_b64,
+        &proof,
+    ) {
+        Ok(true) => {}
+        Ok(false) => return Err(invalid_proof("does not verify".into())),
+        Err(e) => return Err(invalid_proof(format!("{e:#}"))),
+    }
+
+    let receiver = authenticate_receiver(state, &req).await;
+    if let (true, Err(e)) = (state.require_receiver_auth, &receiver) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("403 receiver not authenticated: {e}"),
+        )
+            .into());
     }
 
     // Create record
@@ -79,42 +489,212 @@ async fn handle_ack(
     let record = serde_json::json!({
         "ack_id": ack_id,
         "kid": hdr.kid,                   // <— store kid so ?kid= works
+        "jwks_url": jwks_url,
+        "proof_system": system,
         "dat": dat,
         "received_ts": req.received_ts,
         "recv_domain": req.recv_domain,
         "recv_domain_sig": req.recv_domain_sig,
         "msg_id": req.msg_id,
         "dkim_pass": req.dkim_pass.unwrap_or(true),
+        "receiver_authenticated": receiver.is_ok(),
+        "receiver_kid": receiver.as_ref().ok(),
+        "receiver_auth_error": receiver.as_ref().err(),
         "stored_at": now_iso,
     });
 
-    state
+    let nonce = nonce_key(&hdr.kid, &dat.nonce_b64);
+    let mut record = record;
+    let mut superseded: Option<sled::IVec> = None;
+    if let Some(prev) = state.nonces.get(&nonce).map_err(db_error("read"))? {
+        if receiver.is_err() || !can_supersede(state, &prev, &req.recv_domain)? {
+            return Ok(duplicate_ack(state, &prev, &record)?);
+        }
+        superseded = Some(prev);
+    }
+    loop {
+        if let Some(prev) = &superseded {
+            record["supersedes"] = serde_json::json!(receipt_id(prev));
+        }
+        state
+            .db
+            .insert(ack_id.as_bytes(), serde_json::to_vec(&record).unwrap())
+            .map_err(db_error("insert"))?;
+        // a concurrent ACK of the same DAT may have won
+        match state
+            .nonces
+            .compare_and_swap(&nonce, superseded.clone(), Some(ack_id.as_bytes()))
+            .map_err(db_error("insert"))?
+        {
+            Ok(()) => break,
+            Err(cas) => {
+                let current = cas.current.unwrap_or_default();
+                if receiver.is_ok() && can_supersede(state, &current, &req.recv_domain)? {
+                    superseded = Some(current);
+                    continue;
+                }
+                state
+                    .db
+                    .remove(ack_id.as_bytes())
+                    .map_err(db_error("remove"))?;
+                return Ok(duplicate_ack(state, &current, &record)?);
+            }
+        }
+    }
+    if let Some(prev) = &superseded {
+        // the unauthenticated receipt gives way to one whose receiver signature verifies;
+        // it stays in the store for the audit trail and for Idempotency-Key replays
+        state
+            .db
+            .fetch_and_update(prev, |v| {
+                let mut rec: serde_json::Value = serde_json::from_slice(v?).ok()?;
+                rec["superseded_by"] = serde_json::json!(ack_id);
+                serde_json::to_vec(&rec).ok()
+            })
+            .map_err(db_error("update"))?;
+        tracing::info!(
+            %ack_id,
+            superseded = %receipt_id(prev),
+            "authenticated ACK replaced an unauthenticated receipt"
+        );
+    }
+    if let Some(code) = &dat.verify_code {
+        // codes are random; a clash is not overwritten, the first receipt keeps the code
+        let key = normalize_verify_code(code);
+        let mut cas = state
+            .codes
+            .compare_and_swap(&key, None::<&[u8]>, Some(ack_id.as_bytes()))
+            .map_err(db_error("insert"))?;
+        if let (Err(c), Some(prev)) = (&cas, &superseded) {
+            if c.current.as_ref() == Some(prev) {
+                cas = state
+                    .codes
+                    .compare_and_swap(&key, Some(prev), Some(ack_id.as_bytes()))
+                    .map_err(db_error("insert"))?;
+            }
+        }
+        if let Err(cas) = cas {
+            tracing::warn!(
+                code = %key,
+                %ack_id,
+                kept = %receipt_id(&cas.current.unwrap_or_default()),
+                "verify code already in use; not indexed for this receipt"
+            );
+        }
+    }
+
+    Ok(AckResp {
+        ack_id,
+        status: "DELIVERED",
+        duplicate: false,
+    })
+}
+
+/// Whether an authenticated ACK from recv_domain may replace the receipt stored under
+/// ack_id: only one that did not authenticate its receiver, for the same or an aligned
+/// receiving domain.
+fn can_supersede(
+    state: &AppState,
+    ack_id: &[u8],
+    recv_domain: &str,
+) -> Result<bool, (StatusCode, String)> {
+    let Some(rec) = state
         .db
-        .insert(ack_id.as_bytes(), serde_json::to_vec(&record).unwrap())
-        .map_err(|e| {
+        .get(ack_id)
+        .map_err(db_error("read"))?
+        .and_then(|v| serde_json::from_slice::<serde_json::Value>(&v).ok())
+    else {
+        return Ok(false);
+    };
+    let prev_domain = rec["recv_domain"].as_str().unwrap_or_default();
+    Ok(rec["receiver_authenticated"] != true
+        && (domain_aligned(recv_domain, &[prev_domain.to_string()])
+            || domain_aligned(prev_domain, &[recv_domain.to_string()])))
+}
+
+fn duplicate_ack(
+    state: &AppState,
+    prev_id: &[u8],
+    record: &serde_json::Value,
+) -> Result<AckResp, (StatusCode, String)> {
+    let prev: serde_json::Value = state
+        .db
+        .get(prev_id)
+        .map_err(db_error("read"))?
+        .and_then(|v| serde_json::from_slice(&v).ok())
+        .ok_or_else(|| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("500 db insert: {e}"),
+                "500 db: receipt for DAT nonce missing".to_string(),
             )
         })?;
-    state.db.flush().map_err(|e| {
+    let ack_id: Uuid = serde_json::from_value(prev["ack_id"].clone()).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("500 db flush: {e}"),
+            format!("500 db: bad receipt: {e}"),
         )
     })?;
-
-    Ok(Json(AckResp {
+    if ACK_FIELDS.iter().any(|f| prev[f] != record[f]) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("409 conflict: DAT already acknowledged as {ack_id} with different details"),
+        ));
+    }
+    Ok(AckResp {
         ack_id,
         status: "DELIVERED",
-    }))
+        duplicate: true,
+    })
+}
+
+/// Index receipts stored before the nonce index existed; the first receipt of a DAT wins.
+fn backfill_nonces(db: &sled::Db, nonces: &sled::Tree) -> anyhow::Result<()> {
+    if nonces.len() >= db.len() {
+        return Ok(());
+    }
+    for (id, v) in db.iter().flatten() {
+        let Ok(rec) = serde_json::from_slice::<serde_json::Value>(&v) else {
+            continue;
+        };
+        if !rec["superseded_by"].is_null() {
+            continue;
+        }
+        if let (Some(kid), Some(nonce)) = (rec["kid"].as_str(), rec["dat"]["nonce_b64"].as_str()) {
+            let _ = nonces.compare_and_swap(nonce_key(kid, nonce), None::<&[u8]>, Some(id))?;
+        }
+    }
+    Ok(())
 }
 
 async fn handle_verify(
     axum::extract::State(state): axum::extract::State<AppState>,
     axum::Json(req): axum::Json<VerifyReq>,
 ) -> Result<axum::Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let (hdr, dat) = jws_verify(&req.dat_jws, &|kid| state.keys.get(kid).cloned())
+    match &req.dat_jws {
+        DatJws::One(jws) => verify_dat(&state, &req, jws).await.map(axum::Json),
+        DatJws::Many(list) => {
+            if list.is_empty() {
+                return Err((StatusCode::BAD_REQUEST, "dat_jws: empty list".into()));
+            }
+            let mut dats = Vec::with_capacity(list.len());
+            for jws in list {
+                dats.push(verify_dat(&state, &req, jws).await.unwrap_or_else(
+                    |(_, e)| serde_json::json!({ "ok": false, "dat_jws": jws, "error": e }),
+                ));
+            }
+            let ok = dats.iter().all(|d| d["ok"] == true);
+            Ok(axum::Json(serde_json::json!({ "ok": ok, "dats": dats })))
+        }
+    }
+}
+
+async fn verify_dat(
+    state: &AppState,
+    req: &VerifyReq,
+    dat_jws: &str,
+) -> Result<serde_json::Value, (axum::http::StatusCode, String)> {
+    let (hdr, dat, jwks_url) = verify_jws(state, dat_jws)
+        .await
         .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, format!("bad DAT: {e}")))?;
 
     let digest_match = req
@@ -122,24 +702,70 @@ async fn handle_verify(
         .as_ref()
         .map(|d| d == &dat.msg_digest_b64);
 
-    Ok(axum::Json(serde_json::json!({
+    // Manifest DATs: the signed part list must hash to the committed digest.
+    if dat.digest_alg == DIGEST_ALG_MANIFEST {
+        let consistent = dat
+            .parts
+            .as_deref()
+            .is_some_and(|p| manifest_digest_b64(p) == dat.msg_digest_b64);
+        if !consistent {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "bad DAT: manifest does not match msg_digest_b64".into(),
+            ));
+        }
+    }
+    // Manifest-sd DATs: disclosed parts stand in for the (undisclosed) full manifest.
+    let mut disclosure_results = None;
+    let committed = if dat.digest_alg == DIGEST_ALG_MANIFEST_SD {
+        let commitments = dat.parts_sd.as_deref().unwrap_or_default();
+        if sd_digest_b64(commitments) != dat.msg_digest_b64 {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "bad DAT: parts_sd does not match msg_digest_b64".into(),
+            ));
+        }
+        let checks = check_disclosures(commitments, req.disclosures.as_deref().unwrap_or_default());
+        let disclosed: Vec<PartDigest> = checks
+            .iter()
+            .filter(|c| c.status == "disclosed")
+            .filter_map(|c| c.part.clone())
+            .collect();
+        disclosure_results = Some(checks);
+        Some(disclosed)
+    } else {
+        dat.parts.clone()
+    };
+    let part_results = match (&req.parts, &committed) {
+        (Some(claims), Some(committed)) => Some(check_parts(committed, claims)),
+        (Some(_), None) => {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "DAT has no part manifest".into(),
+            ))
+        }
+        (None, _) => None,
+    };
+
+    Ok(serde_json::json!({
         "ok": true,
         "kid": hdr.kid,
+        "jwks_url": jwks_url,
         "dat": dat,
-        "digest_match": digest_match
-    })))
+        "digest_match": digest_match,
+        "part_results": part_results,
+        "disclosure_results": disclosure_results
+    }))
 }
 
 async fn list_receipts(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<serde_json::Value> {
     let mut items = Vec::new();
-    for kv in state.db.iter() {
-        if let Ok((_k, v)) = kv {
-            if let Ok(s) = String::from_utf8(v.to_vec()) {
-                if let Ok(j) = serde_json::from_str::<serde_json::Value>(&s) {
-                    items.push(j);
-                }
+    for (_k, v) in state.db.iter().flatten() {
+        if let Ok(s) = String::from_utf8(v.to_vec()) {
+            if let Ok(j) = serde_json::from_str::<serde_json::Value>(&s) {
+                items.push(j);
             }
         }
     }
@@ -164,33 +790,31 @@ async fn search_receipts(
         time::OffsetDateTime::parse(&s, &time::format_description::well_known::Rfc3339).ok()
     });
 
-    for kv in state.db.iter() {
-        if let Ok((_k, v)) = kv {
-            if let Ok(s) = String::from_utf8(v.to_vec()) {
-                if let Ok(j) = serde_json::from_str::<serde_json::Value>(&s) {
-                    // since filter
-                    if let (Some(stxt), Some(sts)) =
-                        (j.get("stored_at").and_then(|x| x.as_str()), since_ts)
-                    {
-                        if let Ok(st) = time::OffsetDateTime::parse(
-                            stxt,
-                            &time::format_description::well_known::Rfc3339,
-                        ) {
-                            if st < sts {
-                                continue;
-                            }
+    for (_k, v) in state.db.iter().flatten() {
+        if let Ok(s) = String::from_utf8(v.to_vec()) {
+            if let Ok(j) = serde_json::from_str::<serde_json::Value>(&s) {
+                // since filter
+                if let (Some(stxt), Some(sts)) =
+                    (j.get("stored_at").and_then(|x| x.as_str()), since_ts)
+                {
+                    if let Ok(st) = time::OffsetDateTime::parse(
+                        stxt,
+                        &time::format_description::well_known::Rfc3339,
+                    ) {
+                        if st < sts {
+                            continue;
                         }
                     }
-                    // kid filter
-                    if let Some(ref want) = q.kid {
-                        if let Some(k) = j.get("kid").and_then(|x| x.as_str()) {
-                            if k != want {
-                                continue;
-                            }
+                }
+                // kid filter
+                if let Some(ref want) = q.kid {
+                    if let Some(k) = j.get("kid").and_then(|x| x.as_str()) {
+                        if k != want {
+                            continue;
                         }
                     }
-                    items.push(j);
                 }
+                items.push(j);
             }
         }
     }
@@ -215,13 +839,77 @@ async fn search_receipts(
     Json(serde_json::json!({ "receipts": items }))
 }
 
+/// Register the verify code of a DAT at issuance, so it resolves before any ACK. The
+/// DAT's signature is the authorization; its exp (the ACK deadline) does not apply.
+async fn register_code(
+    State(state): State<AppState>,
+    Json(req): Json<RegisterCodeReq>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let (hdr, dat, _) = verify_jws(&state, &req.dat_jws)
+        .await
+        .map_err(unprocessable)?;
+    let code = dat
+        .verify_code
+        .as_deref()
+        .map(normalize_verify_code)
+        .ok_or_else(|| unprocessable("422 invalid: DAT has no verify_code"))?;
+    let nonce = nonce_key(&hdr.kid, &dat.nonce_b64);
+    // codes are random; a clash is not overwritten, the first DAT keeps the code
+    let registered = match state
+        .issued_codes
+        .compare_and_swap(&code, None::<&[u8]>, Some(nonce.as_slice()))
+        .map_err(db_error("insert"))?
+    {
+        Ok(()) => true,
+        Err(cas) => cas.current.as_deref() == Some(nonce.as_slice()),
+    };
+    if !registered {
+        tracing::warn!(code = %code, kid = %hdr.kid, "verify code already registered for another DAT");
+    }
+    state.issued_codes.flush().map_err(db_error("flush"))?;
+    Ok(Json(
+        serde_json::json!({ "code": code, "registered": registered }),
+    ))
+}
+
+/// Whether the code from a notice's visible footer belongs to a genuine notice (its
+/// issuer registered it, or an ACK carried it) and whether that notice was acknowledged.
+/// Unauthenticated, so nothing about the receipt is returned.
+async fn lookup_code(
+    State(state): State<AppState>,
+    axum::extract::Path(code): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let code = normalize_verify_code(&code);
+    let acknowledged = match state.codes.get(&code).map_err(db_error("read"))? {
+        Some(id) => state.db.contains_key(&id).map_err(db_error("read"))?,
+        None => false,
+    };
+    let issued = state
+        .issued_codes
+        .contains_key(&code)
+        .map_err(db_error("read"))?;
+    Ok(Json(serde_json::json!({
+        "code": code,
+        "verified": issued || acknowledged,
+        "acknowledged": acknowledged,
+    })))
+}
+
 async fn healthz(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<serde_json::Value> {
     let count = state.db.iter().count();
+    let jwks = match &state.jwks {
+        Some(j) => Some(j.status().await),
+        None => None,
+    };
     Json(serde_json::json!({
         "status": "ok",
         "receipts": count,
+        "key_set": state.keys.status(),
+        "receiver_key_set": state.receiver_keys.as_ref().map(|k| k.status()),
+        "jwks": jwks,
+        "dns": state.dns.as_ref().map(|d| d.status()),
         "time": time::OffsetDateTime::now_utc().format(&time::format_description::well_known::Rfc3339).unwrap(),
     }))
 }
@@ -231,37 +919,95 @@ async fn main() -> anyhow::Result<()> {
     let _ = Subscriber::builder()
         .with_env_filter(EnvFilter::from_default_env())
         .try_init();
+    let args = config::Args::parse();
+    let check_only = args.check_config;
+    let cfg = config::Config::load(args)?;
 
     // Load public keys
-    let keys_json = fs::read_to_string("./keys/pubkeys.json")?;
-    let entries: Vec<PubKeyEntry> = serde_json::from_str(&keys_json)?;
-    let mut map = HashMap::new();
-    for e in entries {
-        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(e.vk_b64)?;
-        let vk_bytes: [u8; 32] = bytes
-            .as_slice()
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("vk len"))?;
-        let vk = VerifyingKey::from_bytes(&vk_bytes)?;
-        map.insert(e.kid, vk);
+    let keys = Arc::new(keyset::KeyStore::open("dat", cfg.keys.clone())?);
+    let receiver_keys = if cfg.receiver_keys.is_empty() {
+        None
+    } else {
+        Some(Arc::new(keyset::KeyStore::open(
+            "receiver",
+            cfg.receiver_keys.clone(),
+        )?))
+    };
+    let printed = serde_json::to_string_pretty(&serde_json::json!({
+        "config": cfg,
+        "kids": keys.current().keys.keys().collect::<std::collections::BTreeSet<_>>(),
+        "receiver_kids": receiver_keys.as_ref().map(|k| k.current().keys.keys().cloned().collect::<std::collections::BTreeSet<_>>()),
+    }))?;
+    if check_only {
+        println!("{printed}");
+        return Ok(());
     }
+    eprintln!("zkack-verifier effective configuration:\n{printed}");
 
     // DB
-    let db_dir = std::env::var("ZKACK_DB_DIR").unwrap_or_else(|_| "./data/receipts".into());
-    fs::create_dir_all(&db_dir).ok();
-    let db_path =
-        std::env::var("ZKACK_DB_PATH").unwrap_or_else(|_| "./data/receipts/db".to_string());
-    if let Some(parent) = Path::new(&db_path).parent() {
+    let db_path = &cfg.db_path;
+    if let Some(parent) = db_path.parent() {
         std::fs::create_dir_all(parent)
             .map_err(|e| anyhow::anyhow!("create db dir failed: {}", e))?;
     }
-    let db = sled::open(&db_path)
-        .map_err(|e| anyhow::anyhow!("open db failed (path={}): {}", db_path, e))?;
-    tracing::info!(db_path=%db_path, "opened receipts db");
+    let db = sled::open(db_path)
+        .map_err(|e| anyhow::anyhow!("open db failed (path={}): {}", db_path.display(), e))?;
+    tracing::info!(db_path=%db_path.display(), "opened receipts db");
 
+    let codes = db.open_tree("verify_codes")?;
+    let issued_codes = db.open_tree("issued_codes")?;
+    let nonces = db.open_tree("dat_nonces")?;
+    backfill_nonces(&db, &nonces)?;
+    let idempotency = db.open_tree("idempotency_keys")?;
+    let swept = sweep_idempotency(&idempotency, OffsetDateTime::now_utc(), true)?;
+    tracing::info!(swept, "expired idempotency keys removed");
+    {
+        let idempotency = idempotency.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(3600));
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                match sweep_idempotency(&idempotency, OffsetDateTime::now_utc(), false) {
+                    Ok(swept) => tracing::debug!(swept, "expired idempotency keys removed"),
+                    Err(e) => tracing::warn!(error = %e, "idempotency sweep failed"),
+                }
+            }
+        });
+    }
+    let poll = (cfg.key_reload_secs > 0).then(|| Duration::from_secs(cfg.key_reload_secs));
+    keys.watch(poll)?;
+    if let Some(r) = &receiver_keys {
+        r.watch(poll)?;
+    }
+    let jwks = cfg
+        .jwks
+        .clone()
+        .map(jwks::JwksCache::new)
+        .transpose()?
+        .map(Arc::new);
+    let dns = match &cfg.dns {
+        Some(d) => {
+            let resolver = dnskeys::HickoryTxt::new(d.server, Duration::from_secs(d.timeout_secs))?;
+            Some(Arc::new(dnskeys::DnsKeys::new(
+                d.clone(),
+                Arc::new(resolver),
+            )))
+        }
+        None => None,
+    };
     let state = AppState {
-        keys: Arc::new(map),
+        keys: keys.clone(),
+        jwks,
+        dns,
+        receiver_keys,
+        require_receiver_auth: cfg.require_receiver_auth,
+        proof_system: cfg.proof_system,
         db,
+        codes,
+        issued_codes,
+        nonces,
+        idempotency,
     };
 
     // Routes
@@ -270,17 +1016,477 @@ async fn main() -> anyhow::Result<()> {
         .route("/zk-ack/v1/verify", post(handle_verify))
         .route("/zk-ack/v1/receipts", get(list_receipts))
         .route("/zk-ack/v1/receipts/search", get(search_receipts))
+        .route("/zk-ack/v1/codes", post(register_code))
+        .route("/zk-ack/v1/codes/:code", get(lookup_code))
         .route("/healthz", get(healthz))
         .with_state(state);
+    let timeout = Duration::from_secs(cfg.limits.request_timeout_secs);
+    let app = app
+        .layer(middleware::from_fn(move |req: Request, next: Next| {
+            request_timeout(timeout, req, next)
+        }))
+        .layer(DefaultBodyLimit::max(cfg.limits.max_body_bytes))
+        .layer(ConcurrencyLimitLayer::new(
+            cfg.limits.max_concurrent_requests,
+        ));
 
     // Listen
-    let port: u16 = std::env::var("ZKACK_PORT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(8787);
-    let addr: SocketAddr = ([127, 0, 0, 1], port).into();
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    tracing::info!("zkack-verifier listening on http://{}", addr);
-    axum::serve(listener, app).await?;
+    let tls = match &cfg.tls {
+        Some(t) => {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            Some(
+                axum_server::tls_rustls::RustlsConfig::from_pem_file(&t.cert, &t.key)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("tls: {e}"))?,
+            )
+        }
+        None => None,
+    };
+    let mut servers = tokio::task::JoinSet::new();
+    for &addr in &cfg.listen {
+        let app = app.clone();
+        match &tls {
+            Some(tls) => {
+                let server = axum_server::bind_rustls(addr, tls.clone());
+                tracing::info!("zkack-verifier listening on https://{}", addr);
+                servers.spawn(async move { server.serve(app.into_make_service()).await });
+            }
+            None => {
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("listen {addr}: {e}"))?;
+                tracing::info!("zkack-verifier listening on http://{}", addr);
+                servers.spawn(async move { axum::serve(listener, app).await });
+            }
+        }
+    }
+    // Any listener stopping is fatal
+    if let Some(res) = servers.join_next().await {
+        res??;
+    }
     Ok(())
 }
+
+async fn request_timeout(limit: Duration, req: Request, next: Next) -> Response {
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(resp) => resp,
+        Err(_) => (StatusCode::REQUEST_TIMEOUT, "request timed out").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use ed25519_dalek::SigningKey;
+
+    const DAT_KID: &str = "dat-key";
+    const RECV_KID: &str = "recv-key";
+
+    fn b64e(bytes: &[u8]) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn dat_key() -> SigningKey {
+        SigningKey::from_bytes(&[1u8; 32])
+    }
+
+    fn recv_key() -> SigningKey {
+        SigningKey::from_bytes(&[2u8; 32])
+    }
+
+    fn write_keys(dir: &std::path::Path, file: &str, kid: &str, sk: &SigningKey, domain: &str) {
+        let entry = PubKeyEntry {
+            kid: kid.into(),
+            vk_b64: b64e(sk.verifying_key().as_bytes()),
+            domains: vec![domain.into()],
+        };
+        std::fs::write(dir.join(file), serde_json::to_vec(&[entry]).unwrap()).unwrap();
+    }
+
+    fn test_state(name: &str) -> AppState {
+        let dir =
+            std::env::temp_dir().join(format!("zkack-verifier-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_keys(&dir, "pubkeys.json", DAT_KID, &dat_key(), "example.gov");
+        write_keys(&dir, "receivers.json", RECV_KID, &recv_key(), "example.com");
+        let keys = keyset::KeyStore::open("keys", vec![dir.join("pubkeys.json")]).unwrap();
+        let receivers =
+            keyset::KeyStore::open("receiver keys", vec![dir.join("receivers.json")]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        AppState {
+            keys: Arc::new(keys),
+            jwks: None,
+            dns: None,
+            receiver_keys: Some(Arc::new(receivers)),
+            require_receiver_auth: false,
+            proof_system: ProofSystemId::Mock,
+            codes: db.open_tree("verify_codes").unwrap(),
+            issued_codes: db.open_tree("issued_codes").unwrap(),
+            nonces: db.open_tree("dat_nonces").unwrap(),
+            idempotency: db.open_tree("idempotency_keys").unwrap(),
+            db,
+        }
+    }
+
+    fn dat_jws(nonce: u8) -> (String, DatPayload) {
+        let dat = DatPayload {
+            v: 1,
+            salt_b64: b64e(&[0u8; 32]),
+            addr_hash_b64: addr_hash_b64(&[0u8; 32], "you@example.com"),
+            msg_digest_b64: blake3_b64(b"message"),
+            digest_alg: "blake3".into(),
+            exp: "2099-01-01T00:00:00Z".into(),
+            nonce_b64: b64e(&[nonce; 16]),
+            policy: Policy::new(900, default_fallbacks()),
+            parts: None,
+            parts_sd: None,
+            sender: Some("agency@example.gov".into()),
+            sender_domain: Some("example.gov".into()),
+            verify_code: Some("ABCDE12345".into()),
+        };
+        let jws = jws_sign(&serde_json::to_string(&dat).unwrap(), DAT_KID, &dat_key());
+        (jws, dat)
+    }
+
+    fn ack(nonce: u8, received_ts: &str) -> AckReq {
+        let (dat_jws, dat) = dat_jws(nonce);
+        let proof = ProofSystemId::Mock
+            .prove(&dat.addr_hash_b64, &dat.msg_digest_b64, &dat.nonce_b64)
+            .unwrap();
+        AckReq {
+            dat_jws,
+            proof: b64e(&proof),
+            received_ts: received_ts.into(),
+            recv_domain: "example.com".into(),
+            recv_domain_sig: None,
+            msg_id: None,
+            dkim_pass: Some(true),
+        }
+    }
+
+    async fn post(state: &AppState, key: Option<&str>, req: AckReq) -> Result<AckResp, StatusCode> {
+        let mut headers = axum::http::HeaderMap::new();
+        if let Some(k) = key {
+            headers.insert("idempotency-key", k.parse().unwrap());
+        }
+        handle_ack(State(state.clone()), headers, Json(req))
+            .await
+            .map(|Json(r)| r)
+            .map_err(|e| e.status)
+    }
+
+    #[tokio::test]
+    async fn verify_reports_every_dat() {
+        let state = test_state("verify-many");
+        let (first, _) = dat_jws(8);
+        let (second, _) = dat_jws(9);
+        let forged = format!("{}x", &second[..second.len() - 1]);
+        let req = |dats: serde_json::Value| {
+            serde_json::from_value::<VerifyReq>(serde_json::json!({ "dat_jws": dats })).unwrap()
+        };
+
+        let Json(one) = handle_verify(State(state.clone()), Json(req(first.clone().into())))
+            .await
+            .unwrap();
+        assert_eq!(one["ok"], true);
+        assert!(one.get("dats").is_none());
+
+        let list = serde_json::json!([first, second, forged]);
+        let Json(all) = handle_verify(State(state.clone()), Json(req(list)))
+            .await
+            .unwrap();
+        assert_eq!(all["ok"], false);
+        let dats = all["dats"].as_array().unwrap();
+        assert_eq!(dats.len(), 3);
+        assert_eq!(dats[0]["dat"]["nonce_b64"], b64e(&[8; 16]));
+        assert_eq!(dats[1]["dat"]["nonce_b64"], b64e(&[9; 16]));
+        assert_eq!(dats[2]["ok"], false);
+        assert_eq!(dats[2]["dat_jws"], forged.as_str());
+        assert!(dats[2]["error"].as_str().unwrap().starts_with("bad DAT"));
+
+        let empty = handle_verify(State(state.clone()), Json(req(serde_json::json!([])))).await;
+        assert_eq!(empty.err().unwrap().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_proof_has_its_own_error_code() {
+        use axum::response::IntoResponse;
+        let state = test_state("proof");
+        let mut bad = ack(6, "2026-01-01T00:00:00Z");
+        bad.proof = "AAAA".into();
+        let err = handle_ack(State(state.clone()), Default::default(), Json(bad))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.code, Some(INVALID_PROOF));
+        assert!(
+            err.message.contains("invalid proof (mock)"),
+            "{}",
+            err.message
+        );
+        let resp = err.into_response();
+        assert_eq!(resp.headers()["x-zk-error"], "invalid_proof");
+        assert!(state.db.is_empty());
+
+        // other 422s carry no code
+        let mut forged = ack(7, "2026-01-01T00:00:00Z");
+        forged.dat_jws = "x.y.z".into();
+        let err = handle_ack(State(state.clone()), Default::default(), Json(forged))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.code, None);
+        assert!(err.into_response().headers().get("x-zk-error").is_none());
+    }
+
+    #[tokio::test]
+    async fn repeated_ack_returns_the_original_receipt() {
+        let state = test_state("dedupe");
+        let first = post(&state, None, ack(1, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        assert!(!first.duplicate);
+        let again = post(&state, None, ack(1, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(again.ack_id, first.ack_id);
+        assert!(again.duplicate);
+        let other = post(&state, None, ack(1, "2026-01-01T00:05:00Z")).await;
+        assert_eq!(other.err(), Some(StatusCode::CONFLICT));
+        assert_eq!(state.db.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_acks_of_one_dat_store_one_receipt() {
+        let state = test_state("race");
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(
+                    async move { post(&state, None, ack(2, "2026-01-01T00:00:00Z")).await },
+                )
+            })
+            .collect();
+        let mut ids = std::collections::HashSet::new();
+        for t in tasks {
+            ids.insert(t.await.unwrap().unwrap().ack_id);
+        }
+        assert_eq!(ids.len(), 1);
+        assert_eq!(state.db.len(), 1);
+        assert_eq!(state.nonces.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_replays_and_refuses_other_bodies() {
+        let state = test_state("idempotency");
+        let first = post(&state, Some("k1"), ack(3, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        let again = post(&state, Some("k1"), ack(3, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(again.ack_id, first.ack_id);
+        assert!(again.duplicate);
+        let other = post(&state, Some("k1"), ack(4, "2026-01-01T00:00:00Z")).await;
+        assert_eq!(other.err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+
+        // a failed request frees its key
+        let mut bad = ack(5, "2026-01-01T00:00:00Z");
+        bad.proof = "AAAA".into();
+        let failed = post(&state, Some("k2"), bad).await;
+        assert_eq!(failed.err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+        assert!(!state.idempotency.contains_key("k2").unwrap());
+    }
+
+    #[test]
+    fn idempotency_claims_are_exclusive_and_expire() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("idempotency_keys").unwrap();
+        let now = OffsetDateTime::now_utc();
+        assert!(matches!(
+            claim_idempotency(&tree, "k", "fp", now),
+            Ok(Claimed::New(_))
+        ));
+        // still pending
+        let busy = claim_idempotency(&tree, "k", "fp", now).err().unwrap();
+        assert_eq!(busy.0, StatusCode::CONFLICT);
+
+        let ack_id = Uuid::new_v4();
+        let done = |at: OffsetDateTime| {
+            serde_json::to_vec(&IdempotencyRecord {
+                ack_id: Some(ack_id),
+                fingerprint: "fp".into(),
+                at: rfc3339(at),
+            })
+            .unwrap()
+        };
+        tree.insert("k", done(now)).unwrap();
+        assert!(matches!(
+            claim_idempotency(&tree, "k", "fp", now),
+            Ok(Claimed::Replay(id)) if id == ack_id
+        ));
+        let later = now + IDEMPOTENCY_TTL;
+        assert!(matches!(
+            claim_idempotency(&tree, "k", "other", later),
+            Ok(Claimed::New(_))
+        ));
+    }
+
+    #[test]
+    fn sweep_drops_expired_keys_and_abandoned_claims() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("idempotency_keys").unwrap();
+        let now = OffsetDateTime::now_utc();
+        let record = |ack_id: Option<Uuid>, at: OffsetDateTime| {
+            serde_json::to_vec(&IdempotencyRecord {
+                ack_id,
+                fingerprint: "fp".into(),
+                at: rfc3339(at),
+            })
+            .unwrap()
+        };
+        tree.insert("fresh", record(Some(Uuid::new_v4()), now))
+            .unwrap();
+        tree.insert("old", record(Some(Uuid::new_v4()), now - IDEMPOTENCY_TTL))
+            .unwrap();
+        tree.insert("pending", record(None, now)).unwrap();
+        assert_eq!(sweep_idempotency(&tree, now, false).unwrap(), 1);
+        assert!(tree.contains_key("pending").unwrap());
+        assert_eq!(sweep_idempotency(&tree, now, true).unwrap(), 1);
+        assert_eq!(
+            tree.iter().keys().collect::<Result<Vec<_>, _>>().unwrap(),
+            [sled::IVec::from("fresh")]
+        );
+    }
+
+    fn signed(mut req: AckReq) -> AckReq {
+        let statement = ack_statement(
+            &req.dat_jws,
+            &req.recv_domain,
+            &req.received_ts,
+            req.msg_id.as_deref(),
+            req.dkim_pass.unwrap_or(true),
+        );
+        req.recv_domain_sig = Some(ack_sign(&statement, RECV_KID, &recv_key()));
+        req
+    }
+
+    #[tokio::test]
+    async fn signed_ack_replaces_unauthenticated_receipt() {
+        let state = test_state("upgrade");
+        let unsigned = post(&state, None, ack(6, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        let upgraded = post(&state, None, signed(ack(6, "2026-01-01T00:01:00Z")))
+            .await
+            .unwrap();
+        assert_ne!(upgraded.ack_id, unsigned.ack_id);
+        assert!(!upgraded.duplicate);
+
+        let receipt: serde_json::Value =
+            serde_json::from_slice(&state.db.get(upgraded.ack_id.as_bytes()).unwrap().unwrap())
+                .unwrap();
+        assert_eq!(receipt["receiver_authenticated"], true);
+        assert_eq!(receipt["supersedes"], unsigned.ack_id.to_string());
+        // the old receipt stays, pointing at its successor
+        assert_eq!(state.db.len(), 2);
+        let old: serde_json::Value =
+            serde_json::from_slice(&state.db.get(unsigned.ack_id.as_bytes()).unwrap().unwrap())
+                .unwrap();
+        assert_eq!(old["superseded_by"], upgraded.ack_id.to_string());
+        assert_eq!(
+            state.codes.get("ABCDE12345").unwrap().unwrap(),
+            upgraded.ack_id.as_bytes()
+        );
+
+        // the signed receipt holds the DAT from now on
+        let again = post(&state, None, ack(6, "2026-01-01T00:00:00Z")).await;
+        assert_eq!(again.err(), Some(StatusCode::CONFLICT));
+        let retry = post(&state, None, signed(ack(6, "2026-01-01T00:01:00Z")))
+            .await
+            .unwrap();
+        assert_eq!(retry.ack_id, upgraded.ack_id);
+        assert!(retry.duplicate);
+    }
+
+    #[tokio::test]
+    async fn signed_ack_from_another_domain_cannot_supersede() {
+        let state = test_state("hijack");
+        let mut unsigned = ack(8, "2026-01-01T00:00:00Z");
+        unsigned.recv_domain = "example.org".into();
+        let unsigned = post(&state, None, unsigned).await.unwrap();
+        // example.com's key verifies, but the receipt belongs to example.org
+        let other = post(&state, None, signed(ack(8, "2026-01-01T00:00:00Z"))).await;
+        assert_eq!(other.err(), Some(StatusCode::CONFLICT));
+        assert_eq!(state.db.len(), 1);
+        assert_eq!(
+            state.codes.get("ABCDE12345").unwrap().unwrap(),
+            unsigned.ack_id.as_bytes()
+        );
+    }
+
+    async fn code_status(state: &AppState, code: &str) -> serde_json::Value {
+        let Json(v) = lookup_code(State(state.clone()), axum::extract::Path(code.into()))
+            .await
+            .unwrap();
+        v
+    }
+
+    #[tokio::test]
+    async fn registered_codes_resolve_before_any_ack() {
+        let state = test_state("codes");
+        let unknown = code_status(&state, "abcde-12345").await;
+        assert_eq!(unknown["verified"], false);
+
+        let (jws, _) = dat_jws(9);
+        let register = |jws: String| {
+            let state = state.clone();
+            async move {
+                register_code(State(state), Json(RegisterCodeReq { dat_jws: jws }))
+                    .await
+                    .map(|Json(v)| v)
+                    .map_err(|(code, _)| code)
+            }
+        };
+        let resp = register(jws.clone()).await.unwrap();
+        assert_eq!(resp["registered"], true);
+        // idempotent for the same DAT, refused for another one with the same code
+        assert_eq!(register(jws).await.unwrap()["registered"], true);
+        assert_eq!(register(dat_jws(10).0).await.unwrap()["registered"], false);
+
+        let status = code_status(&state, "abcde-12345").await;
+        assert_eq!(status["code"], "ABCDE12345");
+        assert_eq!(status["verified"], true);
+        assert_eq!(status["acknowledged"], false);
+
+        post(&state, None, ack(9, "2026-01-01T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(
+            code_status(&state, "ABCDE12345").await["acknowledged"],
+            true
+        );
+
+        // only a DAT the verifier trusts registers anything
+        let forged = jws_sign("{}", DAT_KID, &recv_key());
+        assert_eq!(
+            register(forged).await.err(),
+            Some(StatusCode::UNPROCESSABLE_ENTITY)
+        );
+    }
+
+    #[tokio::test]
+    async fn required_receiver_auth_refuses_unsigned_acks() {
+        let mut state = test_state("require");
+        state.require_receiver_auth = true;
+        let refused = post(&state, None, ack(7, "2026-01-01T00:00:00Z")).await;
+        assert_eq!(refused.err(), Some(StatusCode::FORBIDDEN));
+        post(&state, None, signed(ack(7, "2026-01-01T00:00:00Z")))
+            .await
+            .unwrap();
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::{anyhow, Result};
+use base64::Engine;
 use clap::Parser;
-use mailparse::parse_mail;
 use std::fs;
 use time::OffsetDateTime;
+use zkack_circuits::ProofSystemId;
 use zkack_spec::*;
 
 #[derive(Parser, Debug)]
@@ -13,43 +14,160 @@ struct Args {
     /// Path to a single .eml file to ACK (shortcut for pilots)
     #[arg(long)]
     eml: String,
+    /// Proof system to prove with; must match the verifier's
+    #[arg(long, default_value = "mock")]
+    proof_system: ProofSystemId,
+    /// Receiving domain the ACKs are made for
+    #[arg(long, default_value = "local.test")]
+    recv_domain: String,
+    /// ACK key of the receiving domain (keygen private key JSON); signs recv_domain_sig
+    #[arg(long)]
+    recv_key: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
     let eml = fs::read(&args.eml)?;
-    let parsed = parse_mail(&eml)?;
-
-    // Extract X-ZK-DAT header
-    let mut dat_jws = None;
-    for h in parsed.get_headers() {
-        if h.get_key_ref().eq_ignore_ascii_case("X-ZK-DAT") {
-            dat_jws = Some(h.get_value());
-            break;
-        }
-    }
-    let dat_jws = dat_jws.ok_or_else(|| anyhow!("X-ZK-DAT not found"))?;
+    let recv_key = args.recv_key.as_deref().map(load_recv_key).transpose()?;
 
     // Recompute digest the same way the signer did (skeleton: whole .eml blake3)
     let _digest = blake3_b64(&eml);
     let now = OffsetDateTime::now_utc()
         .format(&time::format_description::well_known::Rfc3339)
         .unwrap();
+    let bodies = ack_bodies(&args, &eml, recv_key.as_ref(), &now)?;
 
-    // Post ACK (proof is mocked as "mock-proof-ok")
-    let body = serde_json::json!({
-        "dat_jws": dat_jws,
-        "proof": "mock-proof-ok",
-        "received_ts": now,
-        "recv_domain": "local.test",
-        "dkim_pass": true,
-    });
-
+    // Post one ACK per DAT, proving over its public inputs
     let url = format!("{}/zk-ack/v1/ack", args.verifier.trim_end_matches('/'));
-    let resp = reqwest::Client::new().post(&url).json(&body).send().await?;
-    let status = resp.status();
-    let text = resp.text().await?;
-    println!("Verifier {} -> {}", status, text);
+    let client = reqwest::Client::new();
+    let mut failed = 0;
+    for (i, (dat_jws, body)) in bodies.iter().enumerate() {
+        let resp = client.post(&url).json(&body).send().await?;
+        let status = resp.status();
+        let text = resp.text().await?;
+        if !status.is_success() {
+            failed += 1;
+        }
+        println!(
+            "DAT {}/{} (kid {}): Verifier {} -> {}",
+            i + 1,
+            bodies.len(),
+            jws_kid(dat_jws).as_deref().unwrap_or("?"),
+            status,
+            text
+        );
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{failed} of {} DAT(s) not acknowledged",
+            bodies.len()
+        ));
+    }
     Ok(())
 }
+
+/// One ACK body per X-ZK-DAT: a countersigned message carries one per issuer.
+fn ack_bodies(
+    args: &Args,
+    eml: &[u8],
+    recv_key: Option<&(String, ed25519_dalek::SigningKey)>,
+    now: &str,
+) -> Result<Vec<(String, serde_json::Value)>> {
+    let dats = dat_headers(eml);
+    if dats.is_empty() {
+        return Err(anyhow!("X-ZK-DAT not found"));
+    }
+    dats.into_iter()
+        .map(|dat_jws| {
+            let (_, dat) = jws_peek(&dat_jws)?;
+            let proof =
+                args.proof_system
+                    .prove(&dat.addr_hash_b64, &dat.msg_digest_b64, &dat.nonce_b64)?;
+            let recv_domain_sig = recv_key.map(|(kid, sk)| {
+                let statement = ack_statement(&dat_jws, &args.recv_domain, now, None, true);
+                ack_sign(&statement, kid, sk)
+            });
+            let body = serde_json::json!({
+                "dat_jws": dat_jws,
+                "proof": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(proof),
+                "received_ts": now,
+                "recv_domain": args.recv_domain,
+                "recv_domain_sig": recv_domain_sig,
+                "dkim_pass": true,
+            });
+            Ok((dat_jws, body))
+        })
+        .collect()
+}
+
+fn load_recv_key(path: &str) -> Result<(String, ed25519_dalek::SigningKey)> {
+    let raw = fs::read_to_string(path).map_err(|e| anyhow!("recv key {path}: {e}"))?;
+    let pkj: PrivKeyJson = serde_json::from_str(&raw)?;
+    let sk = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&pkj.sk_b64)?;
+    let sk: [u8; 32] = sk
+        .try_into()
+        .map_err(|_| anyhow!("recv key {path}: bad sk length"))?;
+    Ok((pkj.kid, ed25519_dalek::SigningKey::from_bytes(&sk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    fn dat_jws(kid: &str, nonce: &str, sk: &SigningKey) -> String {
+        let dat = serde_json::json!({
+            "v": 1, "salt_b64": "c2FsdA", "addr_hash_b64": "aGFzaA", "msg_digest_b64": "ZGlnZXN0",
+            "digest_alg": "blake3", "exp": "2099-01-01T00:00:00Z", "nonce_b64": nonce,
+            "policy": {"ack_by_secs": 900, "fallbacks": ["portal"]}
+        });
+        jws_sign(&dat.to_string(), kid, sk)
+    }
+
+    #[test]
+    fn every_dat_gets_its_own_ack() {
+        let sk = SigningKey::from_bytes(&[1; 32]);
+        let (agency, vendor) = (
+            dat_jws("agency", "bm9uY2Ux", &sk),
+            dat_jws("vendor", "bm9uY2Uy", &sk),
+        );
+        // the second DAT is folded, as long header values are on the wire
+        let (head, tail) = vendor.split_at(40);
+        let eml = format!(
+            "From: a@example.gov\r\nX-ZK-DAT: {agency}\r\nSubject: s\r\nx-zk-dat: {head}\r\n {tail}\r\n\r\nbody\r\n"
+        );
+        let args = Args::try_parse_from([
+            "zkack-watcher",
+            "--eml",
+            "m.eml",
+            "--recv-domain",
+            "example.com",
+        ])
+        .unwrap();
+        let recv = ("recv".to_string(), SigningKey::from_bytes(&[2; 32]));
+        let now = "2026-01-01T00:00:00Z";
+        let bodies = ack_bodies(&args, eml.as_bytes(), Some(&recv), now).unwrap();
+
+        assert_eq!(bodies.len(), 2);
+        for ((jws, body), (want, nonce)) in bodies
+            .iter()
+            .zip([(&agency, "bm9uY2Ux"), (&vendor, "bm9uY2Uy")])
+        {
+            assert_eq!(jws, want);
+            assert_eq!(body["dat_jws"], **want);
+            let proof = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(body["proof"].as_str().unwrap())
+                .unwrap();
+            assert!(ProofSystemId::Mock
+                .verify("aGFzaA", "ZGlnZXN0", nonce, &proof)
+                .unwrap());
+            let statement = ack_statement(jws, "example.com", now, None, true);
+            let sig = body["recv_domain_sig"].as_str().unwrap();
+            ack_verify(sig, &statement, &recv.1.verifying_key()).unwrap();
+        }
+
+        let none = ack_bodies(&args, b"Subject: s\r\n\r\nX-ZK-DAT: a.b.c\r\n", None, now);
+        assert_eq!(none.err().unwrap().to_string(), "X-ZK-DAT not found");
+    }
+}
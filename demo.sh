@@ -18,13 +18,7 @@ mkdir -p "$OUTDIR" "$OUTDIR/keys"
 KEY_PRIV="$OUTDIR/keys/dev-priv.json"
 KEY_PUB="$OUTDIR/keys/pubkeys.json"
 
-# These env vars are harmless if unused; helpful if verifier supports them.
 export RUST_LOG="${RUST_LOG:-info}"
-export ZKACK_PORT="$PORT"
-export ZKACK_DB_PATH="$DB_PATH"
-export ZKACK_PUBKEYS_PATH="$OUTDIR/keys/pubkeys.json"
-export ZKACK_PUBKEYS="$OUTDIR/keys/pubkeys.json"
-export PUBKEYS_PATH="$OUTDIR/keys/pubkeys.json"
 
 echo "OUTDIR=$OUTDIR"
 echo "PORT=$PORT"
@@ -51,7 +45,7 @@ keygen_maybe() {
 
   echo "== keygen (into OUTDIR) =="
   # Phase0 keygen writes ./keys/* relative to CWD, so run it inside OUTDIR
-  ( cd "$OUTDIR" && "$keygen" )
+  ( cd "$OUTDIR" && "$keygen" example.gov )
   [[ -f "$OUTDIR/keys/dev-priv.json" && -f "$OUTDIR/keys/pubkeys.json" ]] || {
     echo "keygen did not produce OUTDIR keys" >&2
     ls -la "$OUTDIR/keys" || true
@@ -67,23 +61,25 @@ if ss -ltn "sport = :$PORT" 2>/dev/null | grep -q LISTEN; then
   ALT_PORT="${ZKACK_ALT_PORT:-18787}"
   echo "PORT $PORT already in use; switching to $ALT_PORT"
   PORT="$ALT_PORT"
-  export ZKACK_PORT="$PORT"
 fi
 
 # keygen writes to ./keys by default; mirror into OUTDIR for this run.
 mkdir -p "$OUTDIR/keys"
+# Keys made before sender allowlists have no "domains" and cannot sign; keep OUTDIR's then.
 if [[ -f "$ROOT/keys/dev-priv.json" && -f "$ROOT/keys/pubkeys.json" ]]; then
-  cp -f "$ROOT/keys/dev-priv.json" "$KEY_PRIV"
-  cp -f "$ROOT/keys/pubkeys.json" "$KEY_PUB"
+  if grep -q '"domains"' "$ROOT/keys/dev-priv.json"; then
+    cp -f "$ROOT/keys/dev-priv.json" "$KEY_PRIV"
+    cp -f "$ROOT/keys/pubkeys.json" "$KEY_PUB"
+  else
+    echo "note: $ROOT/keys/dev-priv.json has no \"domains\"; using the OUTDIR key instead"
+  fi
 fi
 
-# Force verifier pubkeys path (covers multiple env var names)
-export ZKACK_PUBKEYS_PATH="$KEY_PUB"
-export ZKACK_PUBKEYS="$KEY_PUB"
-export PUBKEYS_PATH="$KEY_PUB"
-
-echo "== start verifier (CWD=OUTDIR so it loads OUTDIR/keys/pubkeys.json) =="
-( cd "$OUTDIR" && "$ROOT/target/debug/zkack-verifier" ) >"$VERIFIER_LOG" 2>&1 &
+echo "== start verifier =="
+"$ROOT/target/debug/zkack-verifier" \
+  --keys "$KEY_PUB" \
+  --listen "127.0.0.1:$PORT" \
+  --db-path "$DB_PATH" >"$VERIFIER_LOG" 2>&1 &
 VPID=$!
 trap 'kill "$VPID" 2>/dev/null || true; wait "$VPID" 2>/dev/null || true' EXIT
 